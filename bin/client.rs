@@ -17,10 +17,7 @@ fn main() -> Result<(), TupleError> {
 
     let x1 = Field::Type(Type::String);
     let x2 = Field::Value(Value::Integer(1));
-    let find = tuple!(
-        x1,
-        x2
-    );
+    let find = tuple!(x1, x2);
 
     let res = tuple_space.rd_bl(find)?;
 
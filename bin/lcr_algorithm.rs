@@ -17,25 +17,22 @@ struct Node {
 impl Node {
     fn new(id: i32, right_neighbor: i32, ip_addr: &str) -> Self {
         Node {
-            id: id,
-            right_neighbor: right_neighbor,
+            id,
+            right_neighbor,
             current_leader: 0,
             tuple_space: TupleSpace::new(ip_addr),
         }
     }
 
-    /// Message is composed by this tuple (id_receiver, id)
+    /// Message is composed by this tuple (id_receiver, id), every id reaches a node at most once so the proposals are
+    /// never duplicated
     fn send_leader_proposal(&mut self, prop_id: i32) -> Result<(), TupleError> {
         let tuple = tuple!(
             Field::Value(Value::Integer(self.right_neighbor)),
             Field::Value(Value::Integer(prop_id))
         );
 
-        match self.tuple_space.out(tuple) {
-            Ok(_) => Ok(()),
-            Err(TupleError::TupleAlreadyPresentError) => Ok(()),
-            Err(err) => Err(err),
-        }
+        self.tuple_space.out(tuple)
     }
 
     fn receive_leader_proposal(&mut self) -> Result<Vec<Tuple>, TupleError> {
@@ -64,24 +61,34 @@ impl Node {
         );
 
         match self.tuple_space.rd_non_bl(tuple) {
-            Ok(val) => {
-                match val[0].iter().last().unwrap() {
-                    Field::Value(Value::Integer(id)) => {
-                        return Ok(id.clone());
-                    }
-                    _ => panic!("Not possible"),
-                };
-            }
+            Ok(val) => match val[0].iter().last().unwrap() {
+                Field::Value(Value::Integer(id)) => Ok(*id),
+                _ => panic!("Not possible"),
+            },
             Err(_) => Err(TupleError::Error),
         }
     }
 
-    fn received_max(vector: &Vec<Tuple>) -> Vec<Tuple> {
+    /// Block until there is a proposal for this node or a HALT message
+    fn wait_message(&mut self) -> Result<(), TupleError> {
+        let proposal = tuple!(
+            Field::Value(Value::Integer(self.id)),
+            Field::Type(Type::Integer)
+        );
+        let halt = tuple!(
+            Field::Value(Value::String("HALT".to_string())),
+            Field::Type(Type::Integer)
+        );
+
+        self.tuple_space.rd_any(vec![halt, proposal]).map(|_| ())
+    }
+
+    fn received_max(vector: &[Tuple]) -> Vec<Tuple> {
         let a = vector
             .iter()
             .enumerate()
             .map(|(idx, a)| match a.iter().last().unwrap() {
-                Field::Value(Value::Integer(val)) => (idx, val.clone()),
+                Field::Value(Value::Integer(val)) => (idx, *val),
                 _ => (idx, 0),
             })
             .max_by_key(|x| x.1)
@@ -96,18 +103,17 @@ impl Node {
         self.send_leader_proposal(self.id)?;
 
         loop {
+            self.wait_message()?;
+
             // control HALT message
             let halt = self.control_halt_message();
-            let _ = match halt {
-                Ok(val) => {
-                    println!(
-                        "Id {}: Received HALT message from {}, it is the new Leader!",
-                        self.id, val
-                    );
-                    self.current_leader = val;
-                    break;
-                }
-                Err(_) => (),
+            if let Ok(val) = halt {
+                println!(
+                    "Id {}: Received HALT message from {}, it is the new Leader!",
+                    self.id, val
+                );
+                self.current_leader = val;
+                break;
             };
 
             let mut received_proposal: Vec<Tuple>;
@@ -136,7 +142,6 @@ impl Node {
                             "Id {}: Received proposal from {}. I'm going to DISCARD it!",
                             self.id, val
                         );
-                        self.send_leader_proposal(self.id)?;
                     } else {
                         println!("Id {}: Received proposal with my Id. I'm going to HALT message to everyone to declare myself as the new leader!", self.id);
                         self.current_leader = self.id;
@@ -171,9 +176,8 @@ fn main() -> Result<(), TupleError> {
 
             let mut node = Node::new(i, right, "ws://localhost:9001/socket");
             let err = node.run();
-            match err {
-                Err(err) => println!("Node with Id: {} return an Error {:?}", i, err),
-                Ok(_) => (),
+            if let Err(err) = err {
+                println!("Node with Id: {} return an Error {:?}", i, err)
             }
             node.close();
         }));
@@ -182,9 +186,8 @@ fn main() -> Result<(), TupleError> {
     for (idx, handle) in handles.into_iter().enumerate() {
         let err = handle.join();
         println!("Closed thread {}", idx);
-        match err {
-            Err(err) => println!("Error {:?}", err),
-            Ok(_) => (),
+        if let Err(err) = err {
+            println!("Error {:?}", err)
         }
     }
 
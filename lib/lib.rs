@@ -1,6 +1,7 @@
 /// Modules that contain Data structures used by the library
 pub mod data {
     use std::fmt::Display;
+    use std::time::Duration;
 
     use serde::{Deserialize, Serialize};
 
@@ -11,12 +12,20 @@ pub mod data {
         TupleNotOnlyDataError,
         TupleOnlyDataError,
         NoMatchingTupleError,
+        LeaseNotFoundError,
+        SubscriptionNotFoundError,
+        CancelledError,
+        SpaceAlreadyPresentError,
+        SpaceNotFoundError,
+        UnauthorizedError,
+        TimeoutError,
+        NotPrimaryError,
         Error,
         NoError,
     }
 
     /// Type allowed in the Field type to do pattern matching
-    #[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+    #[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
     pub enum Type {
         Integer,
         String,
@@ -32,7 +41,7 @@ pub mod data {
     }
 
     /// Values allowed in the Field type
-    #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+    #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
     pub enum Value {
         Integer(i32),
         String(String),
@@ -48,7 +57,7 @@ pub mod data {
     }
 
     /// Type that represent a single field in Tuple
-    #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+    #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Debug)]
     pub enum Field {
         /// Concrete value
         Value(Value),
@@ -72,6 +81,12 @@ pub mod data {
         tuples: Vec<Field>,
     }
 
+    impl Default for Tuple {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
     impl Tuple {
         pub fn new() -> Self {
             Tuple { tuples: vec![] }
@@ -110,6 +125,10 @@ pub mod data {
             self.tuples.len()
         }
 
+        pub fn is_empty(&self) -> bool {
+            self.tuples.is_empty()
+        }
+
         /// Iterate over elements of the Tuple
         pub fn iter(&self) -> std::slice::Iter<'_, Field> {
             self.tuples.iter()
@@ -117,6 +136,11 @@ pub mod data {
 
         /// Return true if two tuples match, otherwise false
         pub fn matching_tuples(&self, other: Tuple) -> bool {
+            self.matches(&other)
+        }
+
+        /// Same as matching_tuples, borrowing the pattern
+        pub fn matches(&self, other: &Tuple) -> bool {
             for (i, j) in self.tuples.iter().zip(other.tuples.iter()) {
                 match j {
                     Field::Value(_) => {
@@ -154,15 +178,9 @@ pub mod data {
 
             for (idx, i) in self.tuples.iter().enumerate() {
                 if idx != self.tuples.len() - 1 {
-                    match write!(f, "{}, ", i) {
-                        Ok(_) => (),
-                        Err(_) => (),
-                    }
+                    let _ = write!(f, "{}, ", i);
                 } else {
-                    match write!(f, "{})", i) {
-                        Ok(_) => (),
-                        Err(_) => (),
-                    }
+                    let _ = write!(f, "{})", i);
                 }
             }
 
@@ -170,6 +188,148 @@ pub mod data {
         }
     }
 
+    /// Options that can be attached to a tuple put in the Tuple Space with the OutWith operation
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+    pub struct OutOptions {
+        /// Time to live of the tuple in milliseconds, after that the server removes it (None means forever)
+        #[serde(default)]
+        pub ttl: Option<u64>,
+
+        /// Priority of the tuple: the matching tuples with higher priority are taken (and read) first, the ones with
+        /// the same priority in insertion order
+        #[serde(default)]
+        pub priority: i32,
+    }
+
+    impl OutOptions {
+        pub fn new() -> Self {
+            OutOptions {
+                ttl: None,
+                priority: 0,
+            }
+        }
+
+        /// Set the priority of the tuple (0 by default)
+        pub fn priority(mut self, priority: i32) -> Self {
+            self.priority = priority;
+            self
+        }
+
+        /// Set the time to live of the tuple, the tuple is leased and the lease can be renewed
+        pub fn ttl(mut self, ttl: Duration) -> Self {
+            self.ttl = Some(ttl.as_millis() as u64);
+            self
+        }
+    }
+
+    /// A single non-blocking step of a Transaction
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub enum TransactionStep {
+        /// Takes out the tuples that match a given pattern, failing with NoMatchingTupleError if there are none
+        In(Tuple),
+
+        /// Copies the tuples that match a given pattern, failing with NoMatchingTupleError if there are none
+        Rd(Tuple),
+
+        /// Puts a tuple
+        Out(Tuple),
+
+        /// Puts a tuple with the given priority
+        OutWithPriority(Tuple, i32),
+    }
+
+    /// Result of a step of a Transaction: the tuples taken or read (empty for Out), or the error of the step
+    pub type StepResult = Result<Vec<Tuple>, TupleError>;
+
+    /// Message pushed by the server to a subscribed client for every tuple put that matches the subscription pattern
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct Notification {
+        /// Id of the subscription, as returned by the Subscribe operation
+        pub subscription: u64,
+
+        /// The tuple put in the Tuple Space
+        pub tuple: Tuple,
+    }
+
+    /// Options of a named Tuple Space created with the CreateSpace operation
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+    pub struct SpaceOptions {
+        /// If true the space stores duplicate tuples, otherwise an Out of a tuple already present fails
+        #[serde(default)]
+        pub multiset: bool,
+    }
+
+    impl SpaceOptions {
+        pub fn new() -> Self {
+            SpaceOptions { multiset: false }
+        }
+
+        /// Set the multiset semantics of the space
+        pub fn multiset(mut self, multiset: bool) -> Self {
+            self.multiset = multiset;
+            self
+        }
+    }
+
+    /// Opaque position of a Scan in the Tuple Space, returned with a page to request the following one
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ScanCursor {
+        position: u64,
+    }
+
+    impl ScanCursor {
+        /// Cursor positioned after the tuple with the given sequence number (used by the server)
+        pub fn after(position: u64) -> Self {
+            ScanCursor { position }
+        }
+
+        pub fn position(&self) -> u64 {
+            self.position
+        }
+    }
+
+    /// A page of tuples returned by the Scan operation
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct ScanPage {
+        pub tuples: Vec<Tuple>,
+
+        /// Cursor to request the next page, None if the scan is finished
+        pub cursor: Option<ScanCursor>,
+    }
+
+    /// Description of a Tuple Space of the server, returned by the ListSpaces admin operation
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct SpaceInfo {
+        pub name: String,
+
+        /// Number of tuples in the space
+        pub size: usize,
+
+        pub multiset: bool,
+    }
+
+    /// Operations to administer the Tuple Spaces of the server, permitted only with the admin credential
+    #[derive(Serialize, Deserialize, Debug)]
+    pub enum AdminOperation {
+        /// Creates a new empty Tuple Space with the given name, selected by the clients connecting to /spaces/<name>
+        CreateSpace(String, SpaceOptions),
+
+        /// Lists all the Tuple Spaces with their size
+        ListSpaces,
+
+        /// Removes all the tuples from a Tuple Space
+        ClearSpace(String),
+
+        /// Removes a Tuple Space from the server
+        DropSpace(String),
+
+        /// Opens a replication connection, on which a primary server streams its changes to a backup server
+        Replicate,
+
+        /// Promotes a backup server to primary: it stops following its primary and serves the clients
+        Promote,
+    }
+
     /// An Enumeration to represent all Operation permitted on the Tuple Space
     #[derive(Serialize, Deserialize, Debug)]
     pub enum Operation {
@@ -182,11 +342,65 @@ pub mod data {
         /// Copies a tuple that matches a given pattern from the Tuple Space (Blocking)
         RdBl(Tuple),
 
+        /// Takes out only the matching tuple with highest priority, the oldest among the same priority (Blocking)
+        InOneBl(Tuple),
+
+        /// Takes out the tuples that match the first of the given patterns that has any, returning the index of the
+        /// pattern (Blocking)
+        InAny(Vec<Tuple>),
+
+        /// Copies the tuples that match the first of the given patterns that has any, returning the index of the
+        /// pattern (Blocking)
+        RdAny(Vec<Tuple>),
+
         /// Takes out a tuple that matches a given pattern from the Tuple Space (Non Blocking)
         InNonBl(Tuple),
 
         /// Copies a tuple that matches a given pattern from the Tuple Space (Non Blocking)
         RdNonBl(Tuple),
+
+        /// Takes out only the matching tuple with highest priority, the oldest among the same priority (Non Blocking)
+        InOneNonBl(Tuple),
+
+        /// Counts the tuples that match a given pattern, without transferring them
+        Count(Tuple),
+
+        /// Reads a page of the tuples that match an optional pattern: the page size and the cursor returned with the
+        /// previous page (None for the first page)
+        Scan(Option<Tuple>, usize, Option<ScanCursor>),
+
+        /// Puts a tuple in the Tuple Space with some options, the server returns the lease id if a TTL is set
+        OutWith(Tuple, OutOptions),
+
+        /// Renews the lease with the given id, setting its time to live to the given milliseconds
+        RenewLease(u64, u64),
+
+        /// Atomically replaces the first tuple that matches a given pattern with a new tuple, returning the replaced one.
+        /// The new tuple has no lease, even if the replaced one had
+        Replace(Tuple, Tuple),
+
+        /// Executes a list of steps atomically: all of them or, if one fails, none of them
+        Transaction(Vec<TransactionStep>),
+
+        /// Subscribes to the tuples that match a given pattern, the server pushes a Notification for every one put from now on
+        Subscribe(Tuple),
+
+        /// Cancels the subscription with the given id
+        Unsubscribe(u64),
+
+        /// Waits until no tuple matches a given pattern, failing with TimeoutError after the given milliseconds
+        /// (None means forever) (Blocking)
+        WaitEmpty(Tuple, Option<u64>),
+
+        /// Waits until at least the given number of tuples match a given pattern, then copies all of them or, if the
+        /// flag is true, takes all of them out atomically (Blocking)
+        WaitCount(Tuple, usize, bool),
+
+        /// Cancels the blocking operation pending on the connection, which returns CancelledError
+        Cancel,
+
+        /// Executes an admin operation, authenticated by the admin credential of the server
+        Admin(String, AdminOperation),
     }
 }
 
@@ -195,7 +409,7 @@ pub mod data {
 macro_rules! tuple {
     ($($x:expr),*) => {
         {
-            use rustuple::data::Tuple;
+            use $crate::data::Tuple;
             let mut temp_tuple = Tuple::new();
             $(
                 temp_tuple.add($x);
@@ -207,34 +421,205 @@ macro_rules! tuple {
 
 /// Module that contain the implementation of the Tuple Space operations using the data structures of the "data" module
 pub mod tuple_space {
+    use std::collections::VecDeque;
+    use std::io::ErrorKind;
     use std::net::TcpStream;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
 
+    use serde::de::DeserializeOwned;
     use tungstenite::{connect, stream::MaybeTlsStream, Message, WebSocket};
     use url::Url;
 
-    use crate::data::{Operation, Tuple, TupleError};
+    use crate::data::{
+        AdminOperation, Notification, Operation, OutOptions, ScanCursor, ScanPage, SpaceInfo,
+        SpaceOptions, StepResult, TransactionStep, Tuple, TupleError,
+    };
 
     /// Struct to handle the connection and the operation between the client and the server
     pub struct TupleSpace {
         socket: WebSocket<MaybeTlsStream<TcpStream>>,
+
+        /// Urls of the servers of the cluster, a single one if the server is not in a cluster
+        urls: Vec<String>,
+
+        /// Position in the urls of the server of the connection
+        server: usize,
+
+        /// False once the connection is lost, the next request looks for another server of the cluster
+        connected: bool,
+
+        /// Notifications received while waiting for the response of a request
+        notifications: VecDeque<Notification>,
+
+        /// Set by a CancelHandle to cancel the pending blocking operation
+        cancel: Arc<AtomicBool>,
+
+        /// True while a blocking operation is waiting for its response
+        blocking: bool,
+    }
+
+    /// How long the client waits on the socket before checking if the pending blocking operation has to be cancelled
+    const CANCEL_INTERVAL: Duration = Duration::from_millis(50);
+
+    /// How long a request looks for the leader of the cluster, before failing
+    const FOLLOW_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// How long the client waits after trying all the servers of the cluster, before trying them again
+    const FOLLOW_INTERVAL: Duration = Duration::from_millis(100);
+
+    /// Handle to cancel from another thread the blocking operation pending on a TupleSpace, returned by TupleSpace::cancel_handle
+    #[derive(Clone)]
+    pub struct CancelHandle {
+        cancel: Arc<AtomicBool>,
+    }
+
+    impl CancelHandle {
+        /// Cancel the blocking operation (in_bl or rd_bl) pending on the Tuple Space, that returns CancelledError.
+        /// The operation notices the cancel and sends it to the server within CANCEL_INTERVAL (50 ms).
+        /// If the server already found the matching tuples the operation returns them as usual.
+        /// Has no effect if no blocking operation is pending
+        pub fn cancel(&self) {
+            self.cancel.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Blocking iterator over the notifications of the subscriptions, returned by TupleSpace::notifications
+    pub struct Notifications<'a> {
+        space: &'a mut TupleSpace,
+    }
+
+    impl Iterator for Notifications<'_> {
+        type Item = Notification;
+
+        /// Wait for the next notification, return None if the connection is closed
+        fn next(&mut self) -> Option<Notification> {
+            if let Some(notification) = self.space.notifications.pop_front() {
+                return Some(notification);
+            }
+
+            loop {
+                let msg = self.space.read_message()?;
+
+                if let Some(notification) = TupleSpace::deserialize_notification(&msg) {
+                    return Some(notification);
+                }
+            }
+        }
     }
 
     impl TupleSpace {
-        /// Construct a new Tuple Space and open the connection on the ip address and port passed in
+        /// Construct a new Tuple Space and open the connection on the ip address and port passed in.
+        /// The path of the address selects the Tuple Space of the server, e.g. ws://localhost:9001/spaces/election
+        /// (any path not under /spaces/ selects the default space)
         pub fn new(ip_addr: &str) -> Self {
-            let (socket, response) = connect(Url::parse(ip_addr).unwrap()).expect("Can't connect");
+            TupleSpace::cluster(&[ip_addr])
+        }
+
+        /// Construct a new Tuple Space on a cluster of servers, given the address of every server with the same path.
+        /// The requests are sent to the leader of the cluster: when a server is not the leader (anymore) the request
+        /// is sent again to the next one, until the leader is found. The subscriptions are lost when the client
+        /// switches to another server
+        pub fn cluster(urls: &[&str]) -> Self {
+            let (server, socket) = urls
+                .iter()
+                .enumerate()
+                .find_map(|(idx, url)| Some((idx, TupleSpace::open(url)?)))
+                .expect("Can't connect");
+
+            TupleSpace {
+                socket,
+                urls: urls.iter().map(|url| url.to_string()).collect(),
+                server,
+                connected: true,
+                notifications: VecDeque::new(),
+                cancel: Arc::new(AtomicBool::new(false)),
+                blocking: false,
+            }
+        }
 
-            println!("Connected to the server");
-            println!("Response HTTP code: {}", response.status());
+        /// Open the connection to a server, return None if it cannot be reached
+        fn open(url: &str) -> Option<WebSocket<MaybeTlsStream<TcpStream>>> {
+            let (socket, _) = connect(Url::parse(url).ok()?).ok()?;
+            Some(socket)
+        }
+
+        /// Send a request and return the first message of its response. In a cluster the request is sent again to
+        /// the next server while the server refuses it with NotPrimaryError or cannot be reached. If the connection
+        /// is lost after the request is sent the request is not sent again, since it may have been executed
+        fn send(&mut self, request: String) -> Result<Message, TupleError> {
+            let deadline = Instant::now() + FOLLOW_TIMEOUT;
+            let mut error = TupleError::Error;
+            let mut tried = 0;
+
+            loop {
+                if self.connected && self.is_closed() {
+                    self.connected = false;
+                }
+
+                if self.connected && self.socket.send(Message::Text(request.clone())).is_ok() {
+                    let msg = self.read_response()?;
 
-            TupleSpace { socket }
+                    if self.urls.len() == 1 || !TupleSpace::is_not_primary(&msg) {
+                        return Ok(msg);
+                    }
+                    error = TupleError::NotPrimaryError;
+                } else if self.urls.len() == 1 {
+                    return Err(TupleError::Error);
+                }
+
+                if Instant::now() >= deadline {
+                    return Err(error);
+                }
+
+                tried += 1;
+                if tried % self.urls.len() == 0 {
+                    sleep(FOLLOW_INTERVAL);
+                }
+
+                self.server = (self.server + 1) % self.urls.len();
+                match TupleSpace::open(&self.urls[self.server]) {
+                    Some(socket) => {
+                        self.socket = socket;
+                        self.connected = true;
+                        self.set_read_timeout();
+                    }
+                    None => self.connected = false,
+                }
+            }
+        }
+
+        /// True if the server closed the connection, checked before sending a request so that in a cluster it is
+        /// sent to another server instead
+        fn is_closed(&self) -> bool {
+            match self.socket.get_ref() {
+                MaybeTlsStream::Plain(stream) => {
+                    let _ = stream.set_nonblocking(true);
+                    let closed = matches!(stream.peek(&mut [0]), Ok(0));
+                    let _ = stream.set_nonblocking(false);
+
+                    closed
+                }
+                _ => false,
+            }
+        }
+
+        fn is_not_primary(msg: &Message) -> bool {
+            match msg {
+                Message::Text(val) => {
+                    matches!(serde_json::from_str(val), Ok(TupleError::NotPrimaryError))
+                }
+                _ => false,
+            }
         }
 
         fn serialize(operation: Operation) -> Result<String, TupleError> {
             match serde_json::to_string(&operation) {
                 Ok(res) => Ok(res),
                 Err(e) => {
-                    println!("Error serializing! Error: {}", e);
+                    eprintln!("Error serializing! Error: {}", e);
                     Err(TupleError::Error)
                 }
             }
@@ -254,6 +639,89 @@ pub mod tuple_space {
             }
         }
 
+        fn deserialize_notification(msg: &Message) -> Option<Notification> {
+            match msg {
+                Message::Text(val) => serde_json::from_str(val).ok(),
+                _ => None,
+            }
+        }
+
+        /// Read the next message from the server, sending the cancel of the pending blocking operation if requested.
+        /// Return None if the connection is closed
+        fn read_message(&mut self) -> Option<Message> {
+            loop {
+                match self.socket.read() {
+                    Err(tungstenite::Error::Io(e))
+                        if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut =>
+                    {
+                        if self.blocking && self.cancel.swap(false, Ordering::SeqCst) {
+                            let serialized = TupleSpace::serialize(Operation::Cancel).unwrap();
+                            self.socket.send(Message::Text(serialized)).ok()?;
+                        }
+                    }
+                    res => return res.ok(),
+                }
+            }
+        }
+
+        /// Read the response of a request, keeping aside the notifications pushed by the server in the meantime.
+        /// Return Error if the connection is lost
+        fn read_response(&mut self) -> Result<Message, TupleError> {
+            loop {
+                let msg = match self.read_message() {
+                    Some(msg) => msg,
+                    None => {
+                        self.connected = false;
+                        return Err(TupleError::Error);
+                    }
+                };
+
+                match TupleSpace::deserialize_notification(&msg) {
+                    Some(notification) => self.notifications.push_back(notification),
+                    None => return Ok(msg),
+                }
+            }
+        }
+
+        fn deserialize_value<T: DeserializeOwned>(msg: Message) -> Result<T, serde_json::Error> {
+            match msg {
+                Message::Text(val) => serde_json::from_str(&val),
+                _ => panic!("Errore: Messaggio ricevuto non e' in forma testuale!"),
+            }
+        }
+
+        /// Send an operation that returns a value followed by the error code, which is returned instead if something went wrong
+        fn request<T: DeserializeOwned>(&mut self, operation: Operation) -> Result<T, TupleError> {
+            let serialized = TupleSpace::serialize(operation)?;
+
+            let res = self.send(serialized)?;
+            let value = match TupleSpace::deserialize_value(res.clone()) {
+                Ok(value) => value,
+                Err(_) => {
+                    return Err(TupleSpace::deserialize_error(res));
+                }
+            };
+
+            let no_error = self.read_response()?;
+            let no = TupleSpace::deserialize_error(no_error);
+
+            match no {
+                TupleError::NoError => Ok(value),
+                _ => Err(no),
+            }
+        }
+
+        /// Send an operation that returns only the error code
+        fn request_no_value(&mut self, operation: Operation) -> Result<(), TupleError> {
+            let serialized = TupleSpace::serialize(operation)?;
+
+            let res = self.send(serialized)?;
+            match TupleSpace::deserialize_error(res) {
+                TupleError::NoError => Ok(()),
+                err => Err(err),
+            }
+        }
+
         /// Close the connection
         pub fn close(&mut self) {
             self.socket.close(Option::None).unwrap();
@@ -264,33 +732,20 @@ pub mod tuple_space {
         pub fn out(&mut self, tuple: Tuple) -> Result<(), TupleError> {
             let serialized = TupleSpace::serialize(Operation::Out(tuple))?;
 
-            let send = self.socket.send(Message::Text(serialized));
-
-            match send {
-                Ok(_) => (),
-                Err(_) => return Err(TupleError::Error),
-            }
-
-            let res = self.socket.read().unwrap();
+            let res = self.send(serialized)?;
             let res_deser = TupleSpace::deserialize_error(res);
             match res_deser {
                 TupleError::NoError => Ok(()),
                 TupleError::TupleNotOnlyDataError => Err(TupleError::TupleNotOnlyDataError),
                 TupleError::TupleAlreadyPresentError => Err(TupleError::TupleAlreadyPresentError),
+                TupleError::NotPrimaryError => Err(TupleError::NotPrimaryError),
                 _ => Err(TupleError::Error),
             }
         }
 
         /// Implementation of the operations which are the same for the in and rd operation (blocking and non-blocking)
         fn in_rd(&mut self, operation: String) -> Result<Vec<Tuple>, TupleError> {
-            let res = self.socket.send(Message::Text(operation));
-
-            match res {
-                Ok(_) => (),
-                Err(_) => return Err(TupleError::Error),
-            }
-
-            let res = self.socket.read().unwrap();
+            let res = self.send(operation)?;
             let vector = match TupleSpace::deserialize_vector(res.clone()) {
                 Ok(vec) => vec,
                 Err(_) => {
@@ -298,7 +753,7 @@ pub mod tuple_space {
                 }
             };
 
-            let no_error = self.socket.read().unwrap();
+            let no_error = self.read_response()?;
             let no = TupleSpace::deserialize_error(no_error);
 
             match no {
@@ -307,18 +762,90 @@ pub mod tuple_space {
             }
         }
 
+        /// Execute a blocking request, which can be cancelled with a CancelHandle
+        fn blocking<T>(
+            &mut self,
+            request: impl FnOnce(&mut Self) -> Result<T, TupleError>,
+        ) -> Result<T, TupleError> {
+            self.cancel.store(false, Ordering::SeqCst);
+            self.blocking = true;
+            self.set_read_timeout();
+
+            let res = request(self);
+
+            self.blocking = false;
+            self.set_read_timeout();
+            res
+        }
+
+        /// While a blocking request is pending wake up periodically waiting its response, to send the cancel
+        fn set_read_timeout(&self) {
+            if let MaybeTlsStream::Plain(stream) = self.socket.get_ref() {
+                let _ = stream.set_read_timeout(self.blocking.then_some(CANCEL_INTERVAL));
+            }
+        }
+
+        /// Implementation of the blocking operations, which can be cancelled with a CancelHandle
+        fn in_rd_bl(&mut self, operation: String) -> Result<Vec<Tuple>, TupleError> {
+            self.blocking(|space| space.in_rd(operation))
+        }
+
         /// In blocking operation, return when the requested matching tuples are finded and erased in the server
         pub fn in_bl(&mut self, tuple: Tuple) -> Result<Vec<Tuple>, TupleError> {
             let serialized = TupleSpace::serialize(Operation::InBl(tuple))?;
 
-            self.in_rd(serialized)
+            self.in_rd_bl(serialized)
         }
 
         /// Rd blocking operation, return when the requested matching tuples are finded in the server
         pub fn rd_bl(&mut self, tuple: Tuple) -> Result<Vec<Tuple>, TupleError> {
             let serialized = TupleSpace::serialize(Operation::RdBl(tuple))?;
 
-            self.in_rd(serialized)
+            self.in_rd_bl(serialized)
+        }
+
+        /// Blocking In of a single tuple, return when a matching tuple is found and erased in the server: the one with
+        /// highest priority (the oldest among the same priority)
+        pub fn in_one_bl(&mut self, tuple: Tuple) -> Result<Tuple, TupleError> {
+            self.blocking(|space| space.request(Operation::InOneBl(tuple)))
+        }
+
+        /// Blocking In on many patterns, return when one of them matches: the index of the pattern and the erased tuples
+        pub fn in_any(&mut self, patterns: Vec<Tuple>) -> Result<(usize, Vec<Tuple>), TupleError> {
+            self.blocking(|space| space.request(Operation::InAny(patterns)))
+        }
+
+        /// Blocking Rd on many patterns, return when one of them matches: the index of the pattern and the tuples
+        pub fn rd_any(&mut self, patterns: Vec<Tuple>) -> Result<(usize, Vec<Tuple>), TupleError> {
+            self.blocking(|space| space.request(Operation::RdAny(patterns)))
+        }
+
+        /// Block until no tuple matches the pattern, return TimeoutError if the timeout (if any) expires first
+        pub fn wait_empty(
+            &mut self,
+            pattern: Tuple,
+            timeout: Option<Duration>,
+        ) -> Result<(), TupleError> {
+            let timeout = timeout.map(|timeout| timeout.as_millis() as u64);
+
+            self.blocking(|space| space.request_no_value(Operation::WaitEmpty(pattern, timeout)))
+        }
+
+        /// Block until at least count tuples match the pattern, return all of them (erased in the server if take is true)
+        pub fn wait_count(
+            &mut self,
+            pattern: Tuple,
+            count: usize,
+            take: bool,
+        ) -> Result<Vec<Tuple>, TupleError> {
+            self.blocking(|space| space.request(Operation::WaitCount(pattern, count, take)))
+        }
+
+        /// Return a handle to cancel the blocking operations of this Tuple Space from another thread
+        pub fn cancel_handle(&self) -> CancelHandle {
+            CancelHandle {
+                cancel: Arc::clone(&self.cancel),
+            }
         }
 
         /// Rd non-blocking operation, return NoMatchingTupleError in case of no matching tuples
@@ -334,5 +861,146 @@ pub mod tuple_space {
 
             self.in_rd(serialized)
         }
+
+        /// In non-blocking operation of a single tuple, return the matching tuple with highest priority (the oldest
+        /// among the same priority) or NoMatchingTupleError in case of no matching tuples
+        pub fn in_one_non_bl(&mut self, tuple: Tuple) -> Result<Tuple, TupleError> {
+            self.request(Operation::InOneNonBl(tuple))
+        }
+
+        /// Count operation, return the number of tuples matching the pattern (the tuples are not sent by the server)
+        pub fn count(&mut self, tuple: Tuple) -> Result<usize, TupleError> {
+            self.request(Operation::Count(tuple))
+        }
+
+        /// Scan operation, return a page of at most page_size tuples matching the pattern (or all the tuples if the
+        /// pattern is None), in the order they were put. Pass the cursor of the returned page to get the next one,
+        /// until the cursor is None: every tuple present for the whole scan is returned exactly once
+        pub fn scan(
+            &mut self,
+            pattern: Option<Tuple>,
+            page_size: usize,
+            cursor: Option<ScanCursor>,
+        ) -> Result<ScanPage, TupleError> {
+            self.request(Operation::Scan(pattern, page_size, cursor))
+        }
+
+        /// Out operation with options, return the lease id of the tuple if a time to live is set in the options
+        pub fn out_with(
+            &mut self,
+            tuple: Tuple,
+            options: OutOptions,
+        ) -> Result<Option<u64>, TupleError> {
+            self.request(Operation::OutWith(tuple, options))
+        }
+
+        /// Renew the lease of a tuple put with a time to live, the tuple will expire after the new ttl.
+        /// Return LeaseNotFoundError if the tuple is already expired or it was taken out
+        pub fn renew_lease(&mut self, lease: u64, ttl: Duration) -> Result<(), TupleError> {
+            self.request_no_value(Operation::RenewLease(lease, ttl.as_millis() as u64))
+        }
+
+        /// Replace operation, atomically take out the first tuple matching the pattern and put the new tuple in its place.
+        /// Return the replaced tuple, or NoMatchingTupleError (and nothing is put) if no tuple is matching.
+        /// With a pattern made only of data it works as a compare-and-swap. The lease of the replaced tuple is dropped,
+        /// the new tuple stays until taken out
+        pub fn replace(&mut self, pattern: Tuple, tuple: Tuple) -> Result<Tuple, TupleError> {
+            self.request(Operation::Replace(pattern, tuple))
+        }
+
+        /// Transaction operation, execute the steps in order and atomically in the server.
+        /// Return the result of every executed step: if a step fails the whole transaction is rolled back, the last
+        /// result is the error of the failed step and the following steps are not executed.
+        /// An Ok does not mean that the transaction was committed, it is committed only if all the results are Ok
+        pub fn transaction(
+            &mut self,
+            steps: Vec<TransactionStep>,
+        ) -> Result<Vec<StepResult>, TupleError> {
+            let serialized = TupleSpace::serialize(Operation::Transaction(steps))?;
+
+            let res = self.send(serialized)?;
+            let results: Vec<StepResult> = match TupleSpace::deserialize_value(res.clone()) {
+                Ok(results) => results,
+                Err(_) => {
+                    return Err(TupleSpace::deserialize_error(res));
+                }
+            };
+
+            // The error code is the one of the failed step, already present in the results
+            self.read_response()?;
+
+            Ok(results)
+        }
+
+        /// Subscribe to the tuples matching the pattern, return the subscription id.
+        /// The server sends a notification for every matching tuple put from now on, use notifications() to receive them
+        pub fn subscribe(&mut self, pattern: Tuple) -> Result<u64, TupleError> {
+            self.request(Operation::Subscribe(pattern))
+        }
+
+        /// Cancel a subscription, the notifications of the subscription not yet received are discarded
+        pub fn unsubscribe(&mut self, subscription: u64) -> Result<(), TupleError> {
+            self.request_no_value(Operation::Unsubscribe(subscription))?;
+
+            self.notifications
+                .retain(|elem| elem.subscription != subscription);
+
+            Ok(())
+        }
+
+        /// Create a new named Tuple Space on the server, to use it connect to the path /spaces/<name>.
+        /// Return SpaceAlreadyPresentError if a space with the same name exists
+        pub fn create_space(
+            &mut self,
+            admin_token: &str,
+            name: &str,
+            options: SpaceOptions,
+        ) -> Result<(), TupleError> {
+            self.request_no_value(Operation::Admin(
+                admin_token.to_string(),
+                AdminOperation::CreateSpace(name.to_string(), options),
+            ))
+        }
+
+        /// List the Tuple Spaces of the server with their size
+        pub fn list_spaces(&mut self, admin_token: &str) -> Result<Vec<SpaceInfo>, TupleError> {
+            self.request(Operation::Admin(
+                admin_token.to_string(),
+                AdminOperation::ListSpaces,
+            ))
+        }
+
+        /// Remove all the tuples from a Tuple Space of the server
+        pub fn clear_space(&mut self, admin_token: &str, name: &str) -> Result<(), TupleError> {
+            self.request_no_value(Operation::Admin(
+                admin_token.to_string(),
+                AdminOperation::ClearSpace(name.to_string()),
+            ))
+        }
+
+        /// Remove a Tuple Space from the server, the clients still connected to it receive SpaceNotFoundError. The
+        /// default space cannot be removed (Error)
+        pub fn drop_space(&mut self, admin_token: &str, name: &str) -> Result<(), TupleError> {
+            self.request_no_value(Operation::Admin(
+                admin_token.to_string(),
+                AdminOperation::DropSpace(name.to_string()),
+            ))
+        }
+
+        /// Promote a backup server to primary, the changes made by its old primary and not yet replicated are lost
+        pub fn promote(&mut self, admin_token: &str) -> Result<(), TupleError> {
+            self.request_no_value(Operation::Admin(
+                admin_token.to_string(),
+                AdminOperation::Promote,
+            ))
+        }
+
+        /// Blocking iterator over the notifications of all the subscriptions, in the order they are sent by the server
+        pub fn notifications(&mut self) -> Notifications<'_> {
+            Notifications { space: self }
+        }
     }
 }
+
+/// Module that contains the server of the Tuple Spaces, which can be embedded in another program
+pub mod server;
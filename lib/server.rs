@@ -0,0 +1,3473 @@
+#[cfg(feature = "async")]
+mod async_server;
+mod cluster;
+mod delivery;
+#[cfg(feature = "disk")]
+mod disk;
+mod replication;
+mod snapshot;
+mod store;
+mod wal;
+mod watcher;
+
+use crate::data::{
+    AdminOperation, Field, Notification, Operation, OutOptions, ScanCursor, ScanPage, SpaceInfo,
+    SpaceOptions, StepResult, TransactionStep, Tuple, TupleError,
+};
+use cluster::Cluster;
+use delivery::Wakeup;
+use serde::Serialize;
+use snapshot::{Horizon, Snapshot, SpaceSnapshot};
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::io::{self, ErrorKind};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread::sleep;
+use std::thread::{spawn, JoinHandle};
+use std::time::{Duration, Instant};
+use std::vec;
+use store::{failed, Store};
+use wal::{Change, Record, Replica, SpaceLog, Wal};
+use watcher::Watcher;
+
+#[cfg(feature = "disk")]
+pub use disk::DiskBackend;
+pub use store::{Backend, Entry, Lease, MemoryBackend, MemoryStorage, Storage};
+use tungstenite::{
+    accept_hdr,
+    handshake::server::{ErrorResponse, Request, Response},
+    http::StatusCode,
+};
+use tungstenite::{Message, WebSocket};
+pub use wal::SyncPolicy;
+
+/// How often the server removes the expired tuples from the Tuple Space
+const EXPIRE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a connection waits for a request before pushing the pending notifications to the client, also how often a
+/// blocked request of a client with some subscriptions wakes up to push them
+const NOTIFY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Number of shards of a Tuple Space, the tuples are assigned to them by the hash of their arity and indexed field
+const SHARDS: usize = 16;
+
+/// Name of the space used by the clients connecting to a path that does not select a named space
+const DEFAULT_SPACE: &str = "default";
+
+/// Prefix of the paths selecting a named space, e.g. /spaces/election
+const SPACES_PATH: &str = "/spaces/";
+
+/// Path of the replication connections, opened by the backups to their primary
+const REPLICATION_PATH: &str = "/replication";
+
+/// Path of the connections between the nodes of a cluster
+const CLUSTER_PATH: &str = "/cluster";
+
+/// Identity of a queued blocking request, shared by its copies in the shards: the first that claims it serves it (or
+/// cancels it), the other copies are forgotten
+#[derive(Clone)]
+struct Ticket {
+    id: u64,
+    claimed: Arc<AtomicBool>,
+
+    /// Indexes of the shards where the request is queued (none if it waits with the spanning requests)
+    shards: Arc<[usize]>,
+}
+
+impl Ticket {
+    fn new(id: u64, shards: Vec<usize>) -> Self {
+        Ticket {
+            id,
+            claimed: Arc::new(AtomicBool::new(false)),
+            shards: shards.into(),
+        }
+    }
+
+    /// Return true if the request was not claimed yet, and from now on it is
+    fn claim(&self) -> bool {
+        !self.claimed.swap(true, Ordering::SeqCst)
+    }
+
+    fn is_claimed(&self) -> bool {
+        self.claimed.load(Ordering::SeqCst)
+    }
+}
+
+/// A blocked In or Rd request, waiting for a tuple matching one of its patterns to be delivered through its channel
+/// together with the index of the pattern
+#[derive(Clone)]
+struct Waiter {
+    ticket: Ticket,
+    patterns: Vec<Tuple>,
+
+    /// True for an In (the tuple is taken out), false for a Rd
+    take: bool,
+    sender: delivery::Sender<Result<(usize, Vec<Tuple>), TupleError>>,
+}
+
+impl Waiter {
+    /// Index of the first pattern matching the tuple
+    fn matches(&self, tuple: &Tuple) -> Option<usize> {
+        self.patterns
+            .iter()
+            .position(|pattern| pattern.len() == tuple.len() && tuple.matches(pattern))
+    }
+}
+
+/// A request blocked until no tuple matches its pattern, woken through its channel
+struct EmptyWaiter {
+    ticket: Ticket,
+    pattern: Tuple,
+    sender: delivery::Sender<Result<(), TupleError>>,
+}
+
+/// A request blocked until at least count tuples match its pattern, which are then delivered (and, if take is true,
+/// taken out) all together
+struct CountWaiter {
+    ticket: Ticket,
+    pattern: Tuple,
+    count: usize,
+    take: bool,
+    sender: delivery::Sender<Result<Vec<Tuple>, TupleError>>,
+}
+
+/// Result of a blocked request served by an operation, delivered once the changes of the operation are written in the
+/// log: if they cannot be the changes are rolled back, and the request fails with an Error like the operation
+enum Delivery {
+    Match(
+        delivery::Sender<Result<(usize, Vec<Tuple>), TupleError>>,
+        (usize, Vec<Tuple>),
+    ),
+    Empty(delivery::Sender<Result<(), TupleError>>),
+    Count(delivery::Sender<Result<Vec<Tuple>, TupleError>>, Vec<Tuple>),
+}
+
+impl Delivery {
+    fn deliver(self, committed: bool) {
+        match self {
+            Delivery::Match(sender, value) => {
+                let _ = sender.send(committed.then_some(value).ok_or(TupleError::Error));
+            }
+            Delivery::Empty(sender) => {
+                let _ = sender.send(committed.then_some(()).ok_or(TupleError::Error));
+            }
+            Delivery::Count(sender, value) => {
+                let _ = sender.send(committed.then_some(value).ok_or(TupleError::Error));
+            }
+        }
+    }
+}
+
+/// Outcome of a blocking request: its result if it can be served immediately, otherwise the ticket of the request
+/// queued in the waiters together with the channel where the result will be delivered
+enum Wait<T> {
+    Ready(Result<T, TupleError>),
+    Queued(Ticket, delivery::Receiver<Result<T, TupleError>>),
+}
+
+/// A client subscribed to the tuples matching a pattern, notified through its channel
+struct Subscriber {
+    id: u64,
+    pattern: Tuple,
+    sender: Sender<Notification>,
+}
+
+/// Subscriptions owned by a client connection, with the channel where their notifications are collected
+/// before being pushed on the socket
+struct Subscriptions {
+    ids: Vec<u64>,
+    sender: Sender<Notification>,
+    receiver: Receiver<Notification>,
+}
+
+impl Subscriptions {
+    fn new() -> Self {
+        let (sender, receiver) = channel();
+
+        Subscriptions {
+            ids: vec![],
+            sender,
+            receiver,
+        }
+    }
+
+    /// Push to the client the pending notifications of the subscriptions still active
+    fn flush(&mut self, socket: &mut impl Connection) {
+        while let Ok(notification) = self.receiver.try_recv() {
+            if !self.ids.contains(&notification.subscription) {
+                continue;
+            }
+
+            if let Ok(serialized) = serde_json::to_string(&notification) {
+                let _ = socket.write_text(serialized);
+            }
+        }
+
+        socket.flush_text();
+    }
+}
+
+/// Part of a Tuple Space holding the tuples with some hashes of the indexed field, together with the requests blocked
+/// on them
+struct Shard {
+    store: Store,
+
+    /// Blocked In and Rd requests in arrival order
+    waiters: VecDeque<Waiter>,
+
+    /// Requests waiting for the absence of the tuples matching a pattern
+    empty_waiters: Vec<EmptyWaiter>,
+
+    /// Requests waiting for a number of tuples matching a pattern
+    count_waiters: Vec<CountWaiter>,
+
+    /// Results of the requests served by the running operation, delivered when it commits
+    outbox: Vec<Delivery>,
+}
+
+impl Shard {
+    fn new(storage: Box<dyn Storage>) -> Self {
+        Shard {
+            store: Store::new(storage),
+            waiters: VecDeque::new(),
+            empty_waiters: vec![],
+            count_waiters: vec![],
+            outbox: vec![],
+        }
+    }
+
+    /// Hand a tuple just stored to the blocked requests: a copy to every matching Rd, then the tuple itself to the
+    /// oldest matching In, which takes it out of the Tuple Space. If no In takes it, serve the requests waiting for a
+    /// number of tuples that is now reached
+    fn dispatch(&mut self, seq: u64) -> Result<(), TupleError> {
+        let tuple = match self.store.get(seq)? {
+            Some(entry) => entry.tuple.clone(),
+            None => return Ok(()),
+        };
+
+        // The copies of the requests already served in another shard (or cancelled) are forgotten here
+        let outbox = &mut self.outbox;
+        self.waiters.retain(|elem| {
+            if elem.ticket.is_claimed() {
+                return false;
+            }
+
+            match elem.matches(&tuple) {
+                Some(pattern) if !elem.take => {
+                    if elem.ticket.claim() {
+                        let copy = (pattern, vec![tuple.clone()]);
+                        outbox.push(Delivery::Match(elem.sender.clone(), copy));
+                    }
+                    false
+                }
+                _ => true,
+            }
+        });
+
+        while let Some(pos) = self
+            .waiters
+            .iter()
+            .position(|elem| elem.take && elem.matches(&tuple).is_some())
+        {
+            let waiter = self.waiters.remove(pos).unwrap();
+            let pattern = waiter.matches(&tuple).unwrap();
+
+            // A disconnected waiter is forgotten and the tuple goes to the next one
+            if waiter.sender.is_connected() && waiter.ticket.claim() {
+                let taken = (pattern, vec![tuple.clone()]);
+                self.outbox.push(Delivery::Match(waiter.sender, taken));
+                self.store.remove(seq)?;
+                return self.removed();
+            }
+        }
+
+        self.reached()
+    }
+
+    /// Serve, in arrival order, the requests waiting for a number of matching tuples that is reached
+    fn reached(&mut self) -> Result<(), TupleError> {
+        let now = Instant::now();
+        let mut taken = false;
+        let mut pos = 0;
+
+        while pos < self.count_waiters.len() {
+            let waiter = &self.count_waiters[pos];
+
+            if !waiter.ticket.is_claimed()
+                && self.store.count(Some(&waiter.pattern), now)? < waiter.count
+            {
+                pos += 1;
+                continue;
+            }
+
+            let waiter = self.count_waiters.remove(pos);
+            if !waiter.ticket.claim() {
+                continue;
+            }
+
+            taken |= waiter.take;
+            match TupleSpace::gather([&mut self.store], &waiter.pattern, waiter.take, now) {
+                Ok(tuples) => self.outbox.push(Delivery::Count(waiter.sender, tuples)),
+                // The request fails together with the operation
+                Err(error) => {
+                    self.outbox.push(Delivery::Count(waiter.sender, vec![]));
+                    return Err(error);
+                }
+            }
+        }
+
+        if taken {
+            self.removed()?;
+        }
+        Ok(())
+    }
+
+    /// Wake the requests waiting for the absence of tuples that no longer match any, called after a removal
+    fn removed(&mut self) -> Result<(), TupleError> {
+        let now = Instant::now();
+        let mut pos = 0;
+
+        while pos < self.empty_waiters.len() {
+            let waiter = &self.empty_waiters[pos];
+
+            if !waiter.ticket.is_claimed() && self.store.contains(&waiter.pattern, now)? {
+                pos += 1;
+                continue;
+            }
+
+            let waiter = self.empty_waiters.remove(pos);
+            if waiter.ticket.claim() {
+                self.outbox.push(Delivery::Empty(waiter.sender));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Forget the copy of a blocked request queued in this shard, if any
+    fn forget(&mut self, ticket: &Ticket) {
+        self.waiters.retain(|elem| elem.ticket.id != ticket.id);
+        self.empty_waiters
+            .retain(|elem| elem.ticket.id != ticket.id);
+        self.count_waiters
+            .retain(|elem| elem.ticket.id != ticket.id);
+    }
+}
+
+/// Requests waiting for the absence or for a number of the tuples matching a pattern that can be in any shard (with a
+/// type in the indexed field), checked with all the shards locked after the changes of the space
+#[derive(Default)]
+struct Spanning {
+    empty_waiters: Vec<EmptyWaiter>,
+    count_waiters: Vec<CountWaiter>,
+}
+
+impl Spanning {
+    fn is_empty(&self) -> bool {
+        self.empty_waiters.is_empty() && self.count_waiters.is_empty()
+    }
+
+    fn forget(&mut self, ticket: &Ticket) {
+        self.empty_waiters
+            .retain(|elem| elem.ticket.id != ticket.id);
+        self.count_waiters
+            .retain(|elem| elem.ticket.id != ticket.id);
+    }
+}
+
+/// Shards locked by an operation, by index
+type Locked<'a> = BTreeMap<usize, RwLockWriteGuard<'a, Shard>>;
+
+/// Struct to create a new Tuple data space, which is mutually accessed by threads.
+/// The tuples are split in shards by the hash of their arity and of the value of the indexed field, each one behind a
+/// reader/writer lock: the operations lock only the shards that can hold the tuples they work on (always in increasing
+/// order), so the readers do not block each other and the operations on different values run in parallel. A pattern
+/// with a type in the indexed field can match the tuples of any shard, so it locks all of them
+#[derive(Clone)]
+struct TupleSpace {
+    shards: Arc<Vec<RwLock<Shard>>>,
+
+    /// Position of the field whose value selects the shard of a tuple
+    index_field: usize,
+
+    /// If true the space is a multiset: equal tuples are stored once for every out
+    multiset: bool,
+
+    /// Counter used to generate the lease ids
+    next_lease: Arc<AtomicU64>,
+
+    /// Counter used to generate the sequence numbers of the entries, shared by all the shards
+    next_seq: Arc<AtomicU64>,
+
+    /// Counter used to generate the waiter ids
+    next_waiter: Arc<AtomicU64>,
+
+    /// Requests waiting on the tuples of all the shards, locked after the shards
+    spanning: Arc<Mutex<Spanning>>,
+
+    /// Clients to notify when a matching tuple is put in the Tuple Space
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+
+    /// Counter used to generate the subscription ids
+    next_subscription: Arc<AtomicU64>,
+
+    /// Set when the space is dropped from the server, the clients still connected receive SpaceNotFoundError
+    dropped: Arc<AtomicBool>,
+
+    /// Write-ahead log of the changes, if the server is persistent
+    log: Option<SpaceLog>,
+}
+
+impl TupleSpace {
+    /// Construct a new Tuple Space, with set (multiset = false) or multiset semantics, sharding the tuples by the field
+    /// in the given position, writing its changes in the log (if any) and keeping the tuples of every shard in a
+    /// storage built by the given function, failing if a storage cannot be built
+    pub fn new(
+        multiset: bool,
+        index_field: usize,
+        log: Option<SpaceLog>,
+        storage: impl Fn() -> io::Result<Box<dyn Storage>>,
+    ) -> io::Result<Self> {
+        let shards = (0..SHARDS)
+            .map(|_| Ok(RwLock::new(Shard::new(storage()?))))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        Ok(TupleSpace {
+            shards: Arc::new(shards),
+            index_field,
+            multiset,
+            next_lease: Arc::new(AtomicU64::new(1)),
+            next_seq: Arc::new(AtomicU64::new(1)),
+            next_waiter: Arc::new(AtomicU64::new(1)),
+            spanning: Arc::new(Mutex::new(Spanning::default())),
+            subscribers: Arc::new(Mutex::new(vec![])),
+            next_subscription: Arc::new(AtomicU64::new(1)),
+            dropped: Arc::new(AtomicBool::new(false)),
+            log,
+        })
+    }
+
+    /// Needed for mutual exclusion to increment the strong reference counting of the Arc
+    pub fn clone(&self) -> Self {
+        TupleSpace {
+            shards: Arc::clone(&self.shards),
+            index_field: self.index_field,
+            multiset: self.multiset,
+            next_lease: Arc::clone(&self.next_lease),
+            next_seq: Arc::clone(&self.next_seq),
+            next_waiter: Arc::clone(&self.next_waiter),
+            spanning: Arc::clone(&self.spanning),
+            subscribers: Arc::clone(&self.subscribers),
+            next_subscription: Arc::clone(&self.next_subscription),
+            dropped: Arc::clone(&self.dropped),
+            log: self.log.clone(),
+        }
+    }
+
+    /// Index of the shard holding the tuples matching the pattern, None if they can be in any shard. The tuples
+    /// shorter than the indexed field are sharded by arity only
+    fn shard(&self, pattern: &Tuple) -> Option<usize> {
+        let mut hasher = DefaultHasher::new();
+        pattern.len().hash(&mut hasher);
+
+        match pattern.iter().nth(self.index_field) {
+            Some(Field::Value(val)) => val.hash(&mut hasher),
+            Some(Field::Type(_)) => return None,
+            None => (),
+        }
+
+        Some(hasher.finish() as usize % SHARDS)
+    }
+
+    /// Index of the shard holding a tuple made only of data
+    fn home(&self, tuple: &Tuple) -> usize {
+        self.shard(tuple).unwrap_or(0)
+    }
+
+    /// Indexes of the shards that can hold the tuples matching the pattern
+    fn shards_of(&self, pattern: &Tuple) -> Vec<usize> {
+        match self.shard(pattern) {
+            Some(idx) => vec![idx],
+            None => (0..SHARDS).collect(),
+        }
+    }
+
+    /// Lock the shards that can hold the tuples matching the pattern, for reading
+    fn read(&self, pattern: &Tuple) -> BTreeMap<usize, RwLockReadGuard<'_, Shard>> {
+        self.shards_of(pattern)
+            .into_iter()
+            .map(|idx| (idx, self.shards[idx].read().unwrap()))
+            .collect()
+    }
+
+    /// Lock the shards with the given indexes, in increasing order to avoid deadlocks between the operations locking
+    /// more than one shard
+    fn write_many(&self, idxs: impl Iterator<Item = usize>) -> Locked<'_> {
+        idxs.collect::<BTreeSet<usize>>()
+            .into_iter()
+            .map(|idx| (idx, self.shards[idx].write().unwrap()))
+            .collect()
+    }
+
+    /// Lock the shards that can hold the tuples matching the pattern, for writing
+    fn write(&self, pattern: &Tuple) -> Locked<'_> {
+        self.write_many(self.shards_of(pattern).into_iter())
+    }
+
+    /// Lock all the shards, for writing
+    fn write_all(&self) -> Locked<'_> {
+        self.write_many(0..SHARDS)
+    }
+
+    /// Stores of the locked shards that can hold the tuples matching the pattern
+    fn stores<'a, G: Deref<Target = Shard> + 'a>(
+        &self,
+        shards: impl IntoIterator<Item = (&'a usize, &'a G)>,
+        pattern: &Tuple,
+    ) -> Vec<(usize, &'a Store)> {
+        let only = self.shard(pattern);
+
+        shards
+            .into_iter()
+            .filter(|(idx, _)| only.is_none_or(|only| only == **idx))
+            .map(|(idx, shard)| (*idx, &shard.store))
+            .collect()
+    }
+
+    /// End an operation with the given result, while its shards are still locked: if it succeeded write its changes
+    /// in the log, then deliver the results of the requests it served. If it failed (also because the log cannot be
+    /// written) its changes are rolled back and the requests it served fail with an Error
+    fn commit<'a, T>(
+        &self,
+        shards: impl IntoIterator<Item = &'a mut Shard>,
+        res: Result<T, TupleError>,
+    ) -> Result<T, TupleError> {
+        let shards = shards.into_iter().collect::<Vec<&mut Shard>>();
+
+        let res = res.and_then(|ret| match &self.log {
+            Some(log) => log
+                .append(
+                    shards
+                        .iter()
+                        .flat_map(|shard| shard.store.journal())
+                        .map(Change::from)
+                        .collect(),
+                )
+                .map(|_| ret),
+            None => Ok(ret),
+        });
+
+        for shard in shards {
+            match res {
+                Ok(_) => shard.store.commit(),
+                Err(_) => shard.store.rollback(),
+            }
+
+            for delivery in shard.outbox.drain(..) {
+                delivery.deliver(res.is_ok());
+            }
+        }
+
+        res
+    }
+
+    /// Wake the waiters of the locked shards whose tuples were taken and commit the operation, at its end
+    fn finish<T>(
+        &self,
+        shards: &mut Locked<'_>,
+        res: Result<T, TupleError>,
+    ) -> Result<T, TupleError> {
+        let res = res.and_then(|ret| {
+            for shard in shards.values_mut() {
+                shard.removed()?;
+            }
+            Ok(ret)
+        });
+
+        self.commit(shards.values_mut().map(|shard| &mut **shard), res)
+    }
+
+    /// Apply a change read from the log at startup, without writing it again
+    fn apply(&self, change: Change) -> Result<(), TupleError> {
+        match change {
+            Change::Put {
+                seq,
+                tuple,
+                priority,
+                lease,
+            } => {
+                let mut shard = self.shards[self.home(&tuple)].write().unwrap();
+
+                self.next_seq.fetch_max(seq + 1, Ordering::Relaxed);
+                if let Some((id, _)) = lease {
+                    self.next_lease
+                        .fetch_max(id / SHARDS as u64 + 1, Ordering::Relaxed);
+                }
+
+                let res = shard.store.insert(Entry {
+                    seq,
+                    tuple,
+                    lease: lease.map(|(id, expires)| Lease {
+                        id,
+                        expires: Change::to_instant(expires),
+                    }),
+                    priority,
+                });
+                shard.store.commit();
+                res
+            }
+            Change::Take(seq) => {
+                for shard in self.shards.iter() {
+                    let mut shard = shard.write().unwrap();
+                    let res = shard.store.remove(seq);
+                    shard.store.commit();
+
+                    if res?.is_some() {
+                        break;
+                    }
+                }
+                Ok(())
+            }
+            Change::Clear => self
+                .shards
+                .iter()
+                .try_for_each(|shard| shard.write().unwrap().store.clear()),
+            // The creation and the drop of the space are applied by the registry
+            Change::Create(_) | Change::Drop => Ok(()),
+        }
+    }
+
+    /// Consistent state of the space, read while all the shards are locked: the records of the space written in the
+    /// log before it are all in the snapshot, the ones written after it are not
+    fn snapshot(&self, name: &str, wal: &Wal) -> Result<SpaceSnapshot, TupleError> {
+        let shards = self
+            .shards
+            .iter()
+            .map(|shard| shard.read().unwrap())
+            .collect::<Vec<_>>();
+        let now = Instant::now();
+
+        Ok(SpaceSnapshot {
+            name: name.to_string(),
+            multiset: self.multiset,
+            lsn: wal.next_lsn(),
+            next_seq: self.next_seq.load(Ordering::Relaxed),
+            next_lease: self.next_lease.load(Ordering::Relaxed),
+            tuples: shards
+                .iter()
+                .flat_map(|shard| shard.store.select(None, 0, now))
+                .map(|entry| entry.map(|entry| Change::from(entry.as_ref())))
+                .collect::<Result<Vec<Change>, TupleError>>()?,
+        })
+    }
+
+    /// Insert a new Tuple in the Tuple Space and return Ok(()) if Tuple Space not contain the specific Tuple, otherwise an Error.
+    /// In multiset mode the tuple is always inserted, even if an equal one is already present
+    pub fn out(&mut self, tuple: Tuple) -> Result<(), TupleError> {
+        self.out_with(tuple, None, 0).map(|_| ())
+    }
+
+    /// Same as out, but with a priority and, if a time to live is given, the tuple is leased and the lease id is returned
+    pub fn out_with(
+        &mut self,
+        tuple: Tuple,
+        ttl: Option<Duration>,
+        priority: i32,
+    ) -> Result<Option<u64>, TupleError> {
+        let mut shard = self.shards[self.home(&tuple)].write().unwrap();
+        let copy = tuple.clone();
+
+        let res = self
+            .insert(&mut shard.store, tuple, ttl, priority, Instant::now())
+            .and_then(|(seq, lease)| shard.dispatch(seq).map(|_| lease));
+        let lease = self.commit([&mut *shard], res)?;
+        drop(shard);
+
+        self.notify(&copy);
+        self.recheck();
+        Ok(lease)
+    }
+
+    /// Store a new tuple in its shard, returning its sequence number and its lease id (if any). The lease id is a
+    /// multiple of the number of shards plus the index of the shard, so that a renewal locks only that shard
+    fn insert(
+        &self,
+        space: &mut Store,
+        tuple: Tuple,
+        ttl: Option<Duration>,
+        priority: i32,
+        now: Instant,
+    ) -> Result<(u64, Option<u64>), TupleError> {
+        // A tuple with only data matches exactly the equal tuples
+        if !self.multiset && space.contains(&tuple, now)? {
+            return Err(TupleError::TupleAlreadyPresentError);
+        }
+
+        let lease = ttl.map(|ttl| Lease {
+            id: self.next_lease.fetch_add(1, Ordering::Relaxed) * SHARDS as u64
+                + self.home(&tuple) as u64,
+            expires: now + ttl,
+        });
+
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        space.insert(Entry {
+            seq,
+            tuple,
+            lease,
+            priority,
+        })?;
+
+        Ok((seq, lease.map(|lease| lease.id)))
+    }
+
+    /// Return all the tuples of the stores matching the pattern in the order of ranked, taking them out if take is
+    /// true
+    fn gather<'a>(
+        stores: impl IntoIterator<Item = &'a mut Store>,
+        pattern: &Tuple,
+        take: bool,
+        now: Instant,
+    ) -> Result<Vec<Tuple>, TupleError> {
+        let mut entries = stores
+            .into_iter()
+            .map(|store| match take {
+                true => store.remove_matching(pattern, now),
+                false => store
+                    .matching(pattern, now)
+                    .map(|entry| entry.map(Cow::into_owned))
+                    .collect(),
+            })
+            .collect::<Result<Vec<Vec<Entry>>, TupleError>>()?
+            .concat();
+
+        entries.sort_by_key(|entry| (Reverse(entry.priority), entry.seq));
+        Ok(TupleSpace::tuples(entries))
+    }
+
+    /// Blocking In (take = true) or Rd on one or more patterns: return the tuples matching the first pattern that has
+    /// any, otherwise queue the request after the ones already waiting, in the shards of every pattern
+    pub fn wait(&mut self, patterns: &[Tuple], take: bool) -> Wait<(usize, Vec<Tuple>)> {
+        let mut shards =
+            self.write_many(patterns.iter().flat_map(|pattern| self.shards_of(pattern)));
+        let now = Instant::now();
+
+        for (idx, pattern) in patterns.iter().enumerate() {
+            let ret = if take {
+                self.take(&mut shards, pattern, now).map(TupleSpace::tuples)
+            } else {
+                self.select(&shards, pattern, now)
+            };
+
+            if matches!(ret, Err(TupleError::NoMatchingTupleError)) {
+                continue;
+            }
+
+            let ret = self.finish(&mut shards, ret.map(|tuples| (idx, tuples)));
+            drop(shards);
+
+            self.recheck();
+            return Wait::Ready(ret);
+        }
+
+        self.queue(&mut shards, patterns, take)
+    }
+
+    /// Blocking In of a single tuple: take the matching one with highest priority, otherwise queue the request like
+    /// wait (a queued In is always served with a single tuple)
+    pub fn wait_one(&mut self, pattern: &Tuple) -> Wait<(usize, Vec<Tuple>)> {
+        let mut shards = self.write(pattern);
+
+        match self.take_one(&mut shards, pattern, Instant::now()) {
+            Err(TupleError::NoMatchingTupleError) => {
+                self.queue(&mut shards, std::slice::from_ref(pattern), true)
+            }
+            ret => {
+                let ret = self.finish(&mut shards, ret.map(|entry| (0, vec![entry.tuple])));
+                drop(shards);
+
+                self.recheck();
+                Wait::Ready(ret)
+            }
+        }
+    }
+
+    /// Queue a blocking request after the ones already waiting, in every locked shard
+    fn queue(
+        &self,
+        shards: &mut Locked<'_>,
+        patterns: &[Tuple],
+        take: bool,
+    ) -> Wait<(usize, Vec<Tuple>)> {
+        let ticket = Ticket::new(
+            self.next_waiter.fetch_add(1, Ordering::Relaxed),
+            shards.keys().copied().collect(),
+        );
+        let (sender, receiver) = delivery::channel();
+        let waiter = Waiter {
+            ticket: ticket.clone(),
+            patterns: patterns.to_vec(),
+            take,
+            sender,
+        };
+
+        for shard in shards.values_mut() {
+            shard.waiters.push_back(waiter.clone());
+        }
+
+        Wait::Queued(ticket, receiver)
+    }
+
+    /// Wait for the absence of the tuples matching the pattern
+    pub fn wait_empty(&mut self, pattern: &Tuple) -> Wait<()> {
+        let mut shards = self.write(pattern);
+
+        match self.matching(&shards, pattern, Instant::now()) {
+            Ok(0) => return Wait::Ready(Ok(())),
+            Ok(_) => (),
+            Err(error) => return Wait::Ready(Err(error)),
+        }
+
+        let ticket = self.ticket(pattern);
+        let (sender, receiver) = delivery::channel();
+        let waiter = EmptyWaiter {
+            ticket: ticket.clone(),
+            pattern: pattern.clone(),
+            sender,
+        };
+
+        match self.shard(pattern) {
+            Some(idx) => shards.get_mut(&idx).unwrap().empty_waiters.push(waiter),
+            None => self.spanning.lock().unwrap().empty_waiters.push(waiter),
+        }
+
+        Wait::Queued(ticket, receiver)
+    }
+
+    /// Wait until at least count tuples match the pattern, then return all of them (taking them out if take is true)
+    pub fn wait_count(&mut self, pattern: &Tuple, count: usize, take: bool) -> Wait<Vec<Tuple>> {
+        let mut shards = self.write(pattern);
+        let now = Instant::now();
+
+        match self.matching(&shards, pattern, now) {
+            Ok(matching) if matching < count => (),
+            matching => {
+                let ret = matching.and_then(|_| {
+                    TupleSpace::gather(
+                        shards.values_mut().map(|shard| &mut shard.store),
+                        pattern,
+                        take,
+                        now,
+                    )
+                });
+                let ret = self.finish(&mut shards, ret);
+                drop(shards);
+
+                self.recheck();
+                return Wait::Ready(ret);
+            }
+        }
+
+        let ticket = self.ticket(pattern);
+        let (sender, receiver) = delivery::channel();
+        let waiter = CountWaiter {
+            ticket: ticket.clone(),
+            pattern: pattern.clone(),
+            count,
+            take,
+            sender,
+        };
+
+        match self.shard(pattern) {
+            Some(idx) => shards.get_mut(&idx).unwrap().count_waiters.push(waiter),
+            None => self.spanning.lock().unwrap().count_waiters.push(waiter),
+        }
+
+        Wait::Queued(ticket, receiver)
+    }
+
+    /// Ticket of a request waiting on the tuples matching a pattern, queued in the shard of the pattern or, if the
+    /// tuples can be in any shard, with the spanning requests
+    fn ticket(&self, pattern: &Tuple) -> Ticket {
+        Ticket::new(
+            self.next_waiter.fetch_add(1, Ordering::Relaxed),
+            self.shard(pattern).into_iter().collect(),
+        )
+    }
+
+    /// Number of alive tuples of the locked shards matching the pattern
+    fn matching(
+        &self,
+        shards: &Locked<'_>,
+        pattern: &Tuple,
+        now: Instant,
+    ) -> Result<usize, TupleError> {
+        self.stores(shards, pattern)
+            .iter()
+            .map(|(_, store)| store.count(Some(pattern), now))
+            .sum()
+    }
+
+    /// Serve the spanning requests satisfied by the changes of an operation, locking all the shards. Called after the
+    /// operation released its shards, since the requests are waiting for a state and not for a change
+    fn recheck(&self) {
+        if self.spanning.lock().unwrap().is_empty() {
+            return;
+        }
+
+        let mut shards = self.write_all();
+        let mut spanning = self.spanning.lock().unwrap();
+        let mut served = vec![];
+
+        let res = self.serve(&mut shards, &mut spanning, &mut served);
+
+        // The operation that changed the space already replied, a failure only fails the requests served here
+        let committed = self.finish(&mut shards, res).is_ok();
+        for delivery in served {
+            delivery.deliver(committed);
+        }
+    }
+
+    /// Serve the spanning requests satisfied by the locked shards, collecting their results
+    fn serve(
+        &self,
+        shards: &mut Locked<'_>,
+        spanning: &mut Spanning,
+        served: &mut Vec<Delivery>,
+    ) -> Result<(), TupleError> {
+        let now = Instant::now();
+
+        // In arrival order, the requests taking their tuples can leave too few to the following ones
+        let mut pos = 0;
+        while pos < spanning.count_waiters.len() {
+            let waiter = &spanning.count_waiters[pos];
+
+            if !waiter.ticket.is_claimed()
+                && self.matching(shards, &waiter.pattern, now)? < waiter.count
+            {
+                pos += 1;
+                continue;
+            }
+
+            let waiter = spanning.count_waiters.remove(pos);
+            if !waiter.ticket.claim() {
+                continue;
+            }
+
+            match TupleSpace::gather(
+                shards.values_mut().map(|shard| &mut shard.store),
+                &waiter.pattern,
+                waiter.take,
+                now,
+            ) {
+                Ok(tuples) => served.push(Delivery::Count(waiter.sender, tuples)),
+                // The request fails together with the others served here
+                Err(error) => {
+                    served.push(Delivery::Count(waiter.sender, vec![]));
+                    return Err(error);
+                }
+            }
+        }
+
+        let mut pos = 0;
+        while pos < spanning.empty_waiters.len() {
+            let waiter = &spanning.empty_waiters[pos];
+
+            if !waiter.ticket.is_claimed() && self.matching(shards, &waiter.pattern, now)? > 0 {
+                pos += 1;
+                continue;
+            }
+
+            let waiter = spanning.empty_waiters.remove(pos);
+            if waiter.ticket.claim() {
+                served.push(Delivery::Empty(waiter.sender));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove a blocked request from the queues of its shards, return false if it was already served
+    pub fn leave(&mut self, ticket: &Ticket) -> bool {
+        let claimed = ticket.claim();
+
+        for idx in ticket.shards.iter() {
+            self.shards[*idx].write().unwrap().forget(ticket);
+        }
+        self.spanning.lock().unwrap().forget(ticket);
+
+        claimed
+    }
+
+    /// Extract some tuples out of the Tuple Space, returning Ok(Vec<Tuple>) if at least one is matching, otherwise return an Error.
+    /// In multiset mode only one copy of every matching tuple is extracted
+    pub fn _in(&mut self, tuple: &Tuple) -> Result<Vec<Tuple>, TupleError> {
+        let mut shards = self.write(tuple);
+
+        let ret = self.take(&mut shards, tuple, Instant::now());
+        let ret = self.finish(&mut shards, ret);
+        drop(shards);
+
+        self.recheck();
+        ret.map(TupleSpace::tuples)
+    }
+
+    /// Extract the matching tuple with highest priority (the oldest among the same priority), returning
+    /// NoMatchingTupleError if there is none
+    pub fn in_one(&mut self, tuple: &Tuple) -> Result<Tuple, TupleError> {
+        let mut shards = self.write(tuple);
+
+        let ret = self.take_one(&mut shards, tuple, Instant::now());
+        let ret = self.finish(&mut shards, ret);
+        drop(shards);
+
+        self.recheck();
+        ret.map(|entry| entry.tuple)
+    }
+
+    /// Take out only the first entry in the order of ranked
+    fn take_one(
+        &self,
+        shards: &mut Locked<'_>,
+        tuple: &Tuple,
+        now: Instant,
+    ) -> Result<Entry, TupleError> {
+        match self.ranked(shards, tuple, now)?.first() {
+            Some(&(idx, seq)) => Ok(shards.get_mut(&idx).unwrap().store.remove(seq)?.unwrap()),
+            None => Err(TupleError::NoMatchingTupleError),
+        }
+    }
+
+    /// Take out the entries matching the pattern, in the order of ranked
+    fn take(
+        &self,
+        shards: &mut Locked<'_>,
+        tuple: &Tuple,
+        now: Instant,
+    ) -> Result<Vec<Entry>, TupleError> {
+        let mut taken: Vec<Entry> = vec![];
+        // In a multiset only the first copy of every distinct tuple is taken
+        let mut seen: HashSet<Vec<Field>> = HashSet::new();
+
+        for (idx, seq) in self.ranked(shards, tuple, now)? {
+            let space = &mut shards.get_mut(&idx).unwrap().store;
+
+            if self.multiset {
+                let entry = space.get(seq)?.unwrap();
+                if !seen.insert(entry.tuple.iter().cloned().collect()) {
+                    continue;
+                }
+            }
+
+            taken.push(space.remove(seq)?.unwrap());
+        }
+
+        if taken.is_empty() {
+            Err(TupleError::NoMatchingTupleError)
+        } else {
+            Ok(taken)
+        }
+    }
+
+    fn tuples(entries: Vec<Entry>) -> Vec<Tuple> {
+        entries.into_iter().map(|entry| entry.tuple).collect()
+    }
+
+    /// Shards and sequence numbers of the entries matching the pattern, from the highest priority and, for the same
+    /// priority, from the oldest
+    fn ranked<G: Deref<Target = Shard>>(
+        &self,
+        shards: &BTreeMap<usize, G>,
+        tuple: &Tuple,
+        now: Instant,
+    ) -> Result<Vec<(usize, u64)>, TupleError> {
+        let mut ret = self
+            .stores(shards, tuple)
+            .into_iter()
+            .flat_map(|(idx, store)| {
+                store
+                    .matching(tuple, now)
+                    .map(move |entry| entry.map(|entry| (entry.priority, entry.seq, idx)))
+            })
+            .collect::<Result<Vec<(i32, u64, usize)>, TupleError>>()?;
+
+        ret.sort_by_key(|&(priority, seq, _)| (Reverse(priority), seq));
+        Ok(ret.into_iter().map(|(_, seq, idx)| (idx, seq)).collect())
+    }
+
+    /// Read some tuples of the Tuple Space, returning Ok(Vec<Tuple>) if at least one is matching, otherwise return an Error
+    pub fn _rd(&mut self, tuple: &Tuple) -> Result<Vec<Tuple>, TupleError> {
+        let shards = self.read(tuple);
+
+        self.select(&shards, tuple, Instant::now())
+    }
+
+    fn select<G: Deref<Target = Shard>>(
+        &self,
+        shards: &BTreeMap<usize, G>,
+        tuple: &Tuple,
+        now: Instant,
+    ) -> Result<Vec<Tuple>, TupleError> {
+        let ret = self
+            .ranked(shards, tuple, now)?
+            .into_iter()
+            .filter_map(|(idx, seq)| shards[&idx].store.get(seq).transpose())
+            .map(|entry| entry.map(|entry| entry.tuple.clone()))
+            .collect::<Result<Vec<Tuple>, TupleError>>()?;
+
+        if ret.is_empty() {
+            Err(TupleError::NoMatchingTupleError)
+        } else {
+            Ok(ret)
+        }
+    }
+
+    /// Count the tuples of the Tuple Space matching the pattern, without copying them out of the space
+    pub fn count(&self, tuple: &Tuple) -> Result<usize, TupleError> {
+        let now = Instant::now();
+
+        self.read(tuple)
+            .values()
+            .map(|shard| shard.store.count(Some(tuple), now))
+            .sum()
+    }
+
+    /// Return a page of at most page_size tuples matching the pattern (or any tuple if there is no pattern),
+    /// starting after the cursor. The tuples are returned in insertion order, so a scan returns exactly once every
+    /// tuple present for its whole duration, even if other tuples are put or taken in the meantime
+    pub fn scan(
+        &self,
+        pattern: Option<&Tuple>,
+        page_size: usize,
+        cursor: Option<ScanCursor>,
+    ) -> Result<ScanPage, TupleError> {
+        if page_size == 0 {
+            return Err(TupleError::Error);
+        }
+
+        // The shards of the pattern (all of them without a pattern) are locked together, to read a consistent page
+        let shards = match pattern {
+            Some(pattern) => self.read(pattern).into_values().collect(),
+            None => self
+                .shards
+                .iter()
+                .map(|shard| shard.read().unwrap())
+                .collect::<Vec<_>>(),
+        };
+        let now = Instant::now();
+        let after = cursor.map_or(0, |cursor| cursor.position());
+
+        // The first page_size + 1 entries of every shard, to know if there is a next page (a page as large as the
+        // address space simply takes all of them)
+        let mut matching = shards
+            .iter()
+            .flat_map(|shard| {
+                let page = page_size.saturating_add(1);
+                shard.store.select(pattern, after, now).take(page)
+            })
+            .collect::<Result<Vec<Cow<Entry>>, TupleError>>()?;
+        matching.sort_by_key(|elem| elem.seq);
+
+        let cursor = if matching.len() > page_size {
+            Some(ScanCursor::after(matching[page_size - 1].seq))
+        } else {
+            None
+        };
+
+        Ok(ScanPage {
+            tuples: matching
+                .into_iter()
+                .take(page_size)
+                .map(|elem| elem.tuple.clone())
+                .collect(),
+            cursor,
+        })
+    }
+
+    /// Renew the lease with the given id, returning LeaseNotFoundError if the leased tuple is expired or was taken out.
+    /// Only the shard of the lease is locked
+    pub fn renew(&mut self, lease: u64, ttl: Duration) -> Result<(), TupleError> {
+        let mut shard = self.shards[lease as usize % SHARDS].write().unwrap();
+        let now = Instant::now();
+
+        let seq = match shard.store.leased(lease)? {
+            Some(seq) => seq,
+            None => return Err(TupleError::LeaseNotFoundError),
+        };
+
+        if !shard
+            .store
+            .get(seq)?
+            .is_some_and(|entry| entry.is_alive(now))
+        {
+            return Err(TupleError::LeaseNotFoundError);
+        }
+
+        let res = shard.store.set_lease(
+            seq,
+            Lease {
+                id: lease,
+                expires: now + ttl,
+            },
+        );
+        self.commit([&mut *shard], res)
+    }
+
+    /// Atomically replace the first tuple matching the pattern (the one with highest priority) with a new one,
+    /// returning the replaced tuple. The new tuple keeps the priority of the replaced one but not its lease: it stays
+    /// in the Tuple Space until taken out
+    pub fn replace(&mut self, pattern: &Tuple, tuple: Tuple) -> Result<Tuple, TupleError> {
+        let to = self.home(&tuple);
+        let mut shards = self.write_many(self.shards_of(pattern).into_iter().chain([to]));
+        let now = Instant::now();
+
+        let (from, idx) = match self.ranked(&shards, pattern, now)?.first() {
+            Some(&first) => first,
+            None => return Err(TupleError::NoMatchingTupleError),
+        };
+
+        if !self.multiset {
+            for elem in shards[&to].store.matching(&tuple, now) {
+                if elem?.seq != idx {
+                    return Err(TupleError::TupleAlreadyPresentError);
+                }
+            }
+        }
+
+        let copy = tuple.clone();
+        let res = self.swap(&mut shards, (from, idx), tuple);
+        let replaced = self.finish(&mut shards, res)?;
+        drop(shards);
+
+        self.notify(&copy);
+        self.recheck();
+        Ok(replaced.tuple)
+    }
+
+    /// Take out the entry replaced by replace and put the new tuple, handing it to the blocked requests
+    fn swap(
+        &self,
+        shards: &mut Locked<'_>,
+        (from, idx): (usize, u64),
+        tuple: Tuple,
+    ) -> Result<Entry, TupleError> {
+        let replaced = shards.get_mut(&from).unwrap().store.remove(idx)?.unwrap();
+
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        let shard = shards.get_mut(&self.home(&tuple)).unwrap();
+        // The new tuple keeps the priority of the replaced one, the lease is dropped with it
+        shard.store.insert(Entry {
+            seq,
+            tuple,
+            lease: None,
+            priority: replaced.priority,
+        })?;
+        shard.dispatch(seq)?;
+
+        Ok(replaced)
+    }
+
+    /// Execute the steps of a transaction in order, locking together the shards of all the steps.
+    /// If a step fails the Tuple Space is rolled back to its state before the transaction and the remaining steps are
+    /// not executed. Return the result of every executed step and the error that aborted the transaction (if any)
+    pub fn transaction(
+        &mut self,
+        steps: Vec<TransactionStep>,
+    ) -> (Vec<StepResult>, Result<(), TupleError>) {
+        let mut shards = self.write_many(steps.iter().flat_map(|step| match step {
+            TransactionStep::In(val)
+            | TransactionStep::Rd(val)
+            | TransactionStep::Out(val)
+            | TransactionStep::OutWithPriority(val, _) => self.shards_of(val),
+        }));
+        let now = Instant::now();
+        let mut results: Vec<StepResult> = vec![];
+        let mut put: Vec<(u64, Tuple, i32)> = vec![];
+
+        for step in steps {
+            let res = match step {
+                TransactionStep::In(val) if val.has_data_only() => {
+                    Err(TupleError::TupleOnlyDataError)
+                }
+                TransactionStep::In(val) => {
+                    self.take(&mut shards, &val, now).map(TupleSpace::tuples)
+                }
+                TransactionStep::Rd(val) if val.has_data_only() => {
+                    Err(TupleError::TupleOnlyDataError)
+                }
+                TransactionStep::Rd(val) => self.select(&shards, &val, now),
+                TransactionStep::Out(val) => self.put_step(&mut shards, &mut put, val, 0, now),
+                TransactionStep::OutWithPriority(val, priority) => {
+                    self.put_step(&mut shards, &mut put, val, priority, now)
+                }
+            };
+
+            if let Err(error) = res {
+                results.push(Err(error));
+
+                // The changes of the executed steps are rolled back, without writing them in the log
+                let aborted = self.finish(&mut shards, Err(error));
+                return (results, aborted);
+            }
+
+            results.push(res);
+        }
+
+        // The blocked requests are served with the tuples of higher priority first, the same order as ranked
+        put.sort_by_key(|&(_, _, priority)| Reverse(priority));
+        let res = put.iter().try_for_each(|(seq, tuple, _)| {
+            shards.get_mut(&self.home(tuple)).unwrap().dispatch(*seq)
+        });
+        if let Err(error) = self.finish(&mut shards, res) {
+            return (results, Err(error));
+        }
+        drop(shards);
+
+        for (_, tuple, _) in put.iter() {
+            self.notify(tuple);
+        }
+        self.recheck();
+        (results, Ok(()))
+    }
+
+    /// Out step of a transaction, remembering the tuple put to hand it to the blocked requests once committed
+    fn put_step(
+        &self,
+        shards: &mut Locked<'_>,
+        put: &mut Vec<(u64, Tuple, i32)>,
+        tuple: Tuple,
+        priority: i32,
+        now: Instant,
+    ) -> StepResult {
+        if !tuple.has_data_only() {
+            return Err(TupleError::TupleNotOnlyDataError);
+        }
+
+        let shard = shards.get_mut(&self.home(&tuple)).unwrap();
+        let copy = tuple.clone();
+
+        self.insert(&mut shard.store, tuple, None, priority, now)
+            .map(|(seq, _)| {
+                put.push((seq, copy, priority));
+                vec![]
+            })
+    }
+    /// Subscribe a client to the tuples matching the pattern, returning the subscription id
+    pub fn subscribe(&mut self, pattern: Tuple, sender: Sender<Notification>) -> u64 {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let id = self.next_subscription.fetch_add(1, Ordering::Relaxed);
+
+        subscribers.push(Subscriber {
+            id,
+            pattern,
+            sender,
+        });
+
+        id
+    }
+
+    pub fn unsubscribe(&mut self, id: u64) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+
+        subscribers.retain(|elem| elem.id != id);
+    }
+
+    /// Send a tuple just put in the Tuple Space to the subscribers with a matching pattern,
+    /// forgetting the ones whose client is disconnected
+    fn notify(&self, tuple: &Tuple) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+
+        subscribers.retain(|elem| {
+            if elem.pattern.len() != tuple.len() || !tuple.matches(&elem.pattern) {
+                return true;
+            }
+
+            elem.sender
+                .send(Notification {
+                    subscription: elem.id,
+                    tuple: tuple.clone(),
+                })
+                .is_ok()
+        });
+    }
+
+    /// Remove the expired tuples from the Tuple Space
+    pub fn expire(&mut self) {
+        for shard in self.shards.iter() {
+            let mut shard = shard.write().unwrap();
+            let now = Instant::now();
+
+            let res = shard.store.expired(now).and_then(|expired| {
+                for seq in expired {
+                    shard.store.remove(seq)?;
+                }
+                shard.removed()
+            });
+
+            // A failure keeps the tuples, they are removed again at the next run
+            let _ = self.commit([&mut *shard], res);
+        }
+
+        self.recheck();
+    }
+
+    /// Number of tuples in the Tuple Space
+    pub fn size(&self) -> Result<usize, TupleError> {
+        let now = Instant::now();
+
+        self.shards
+            .iter()
+            .map(|shard| shard.read().unwrap().store.count(None, now))
+            .sum()
+    }
+
+    /// Remove all the tuples from the Tuple Space. The removal is not journaled, so it is written in the log before
+    /// the tuples are removed: an error of the storage can leave some of them, which a new clear removes
+    pub fn clear(&mut self) -> Result<(), TupleError> {
+        let mut shards = self.write_all();
+
+        if let Some(log) = &self.log {
+            log.append(vec![Change::Clear])?;
+        }
+
+        let res = shards
+            .values_mut()
+            .try_for_each(|shard| shard.store.clear());
+        self.finish(&mut shards, res)?;
+        drop(shards);
+
+        self.recheck();
+        Ok(())
+    }
+
+    pub fn is_dropped(&self) -> bool {
+        self.dropped.load(Ordering::SeqCst)
+    }
+
+    /// Mark the Tuple Space as dropped and wake all its blocked requests, which fail with SpaceNotFoundError
+    fn close(&self) {
+        self.dropped.store(true, Ordering::SeqCst);
+        self.interrupt();
+    }
+
+    /// Wake all the blocked requests without serving them
+    fn interrupt(&self) {
+        let mut shards = self.write_all();
+
+        for shard in shards.values_mut() {
+            shard.waiters.clear();
+            shard.empty_waiters.clear();
+            shard.count_waiters.clear();
+        }
+
+        let mut spanning = self.spanning.lock().unwrap();
+        spanning.empty_waiters.clear();
+        spanning.count_waiters.clear();
+    }
+}
+
+/// Registry of the named Tuple Spaces of the server, each one isolated from the others
+#[derive(Clone)]
+struct Spaces {
+    spaces: Arc<Mutex<HashMap<String, TupleSpace>>>,
+
+    /// Semantics of the spaces created on first use
+    multiset: bool,
+
+    /// If true a space is created the first time a client connects to it, otherwise only with CreateSpace
+    auto_create: bool,
+
+    /// Position of the field used to index the tuples of every space
+    index_field: usize,
+
+    /// Write-ahead log of the changes of all the spaces, if the server is persistent
+    log: Option<Arc<Wal>>,
+
+    /// Constructor of the storages of the spaces
+    backend: Arc<dyn Backend>,
+
+    /// Set while the server is a backup following a primary, cleared when it is promoted
+    backup: Arc<AtomicBool>,
+
+    /// Set on a node of a cluster, which is not promoted but elected leader by the other nodes
+    elected: bool,
+
+    /// Held by a backup while it writes a record of its primary in the log and applies it to the spaces, and by the
+    /// snapshots for their whole duration: a snapshot would otherwise see the record in the log but not its changes,
+    /// or write the same file as another snapshot
+    following: Arc<Mutex<()>>,
+}
+
+impl Spaces {
+    /// Construct the registry, containing only the default space, failing if its storage cannot be built
+    pub fn new(
+        multiset: bool,
+        auto_create: bool,
+        index_field: usize,
+        log: Option<Arc<Wal>>,
+        backend: Arc<dyn Backend>,
+        backup: Arc<AtomicBool>,
+        elected: bool,
+    ) -> io::Result<Self> {
+        let spaces = Spaces {
+            spaces: Arc::new(Mutex::new(HashMap::new())),
+            multiset,
+            auto_create,
+            index_field,
+            log,
+            backend,
+            backup,
+            elected,
+            following: Arc::new(Mutex::new(())),
+        };
+
+        let default = spaces.space(DEFAULT_SPACE, multiset)?;
+        spaces
+            .spaces
+            .lock()
+            .unwrap()
+            .insert(DEFAULT_SPACE.to_string(), default);
+
+        Ok(spaces)
+    }
+
+    /// Construct a new empty space on the storage of the server, writing its changes in the log of the server
+    fn space(&self, name: &str, multiset: bool) -> io::Result<TupleSpace> {
+        let log = self
+            .log
+            .as_ref()
+            .map(|wal| SpaceLog::new(Arc::clone(wal), name));
+
+        TupleSpace::new(multiset, self.index_field, log, || {
+            self.backend.storage(name, self.index_field)
+        })
+    }
+
+    /// Write in the log some changes of a space
+    fn log(&self, name: &str, changes: Vec<Change>) -> Result<(), TupleError> {
+        match &self.log {
+            Some(wal) => SpaceLog::new(Arc::clone(wal), name).append(changes),
+            None => Ok(()),
+        }
+    }
+
+    /// Rebuild the spaces from the snapshot (if any) and the records of the log not in it, in the order they were
+    /// written
+    pub fn replay(
+        &self,
+        snapshot: Option<Snapshot>,
+        records: Vec<Record>,
+    ) -> Result<(), TupleError> {
+        let mut horizon = Horizon::default();
+
+        if let Some(snapshot) = snapshot {
+            horizon = snapshot.horizon();
+            self.restore(snapshot)?;
+        }
+
+        for record in records {
+            if !horizon.contains(&record) {
+                self.apply(record)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replace the content of all the spaces with the ones of the snapshot. A space also in the snapshot (with the
+    /// same semantics) is emptied and filled again in place, so that its connections stay open, the other ones are
+    /// closed. An error of the storage stops the restore in the middle, keeping the spaces not restored yet
+    fn restore(&self, snapshot: Snapshot) -> Result<(), TupleError> {
+        let mut spaces = self.spaces.lock().unwrap();
+        let mut replaced = std::mem::take(&mut *spaces);
+
+        let res = snapshot.spaces.into_iter().try_for_each(|state| {
+            let space = match replaced.remove(&state.name) {
+                Some(space) if space.multiset == state.multiset => space,
+                Some(space) => {
+                    space.close();
+                    self.space(&state.name, state.multiset).map_err(failed)?
+                }
+                None => self.space(&state.name, state.multiset).map_err(failed)?,
+            };
+            spaces.insert(state.name, space.clone());
+
+            space.apply(Change::Clear)?;
+            for change in state.tuples {
+                space.apply(change)?;
+            }
+            space.next_seq.fetch_max(state.next_seq, Ordering::Relaxed);
+            space
+                .next_lease
+                .fetch_max(state.next_lease, Ordering::Relaxed);
+
+            Ok(())
+        });
+
+        if res.is_ok() {
+            for (_, space) in replaced {
+                space.close();
+            }
+        } else {
+            spaces.extend(replaced);
+        }
+
+        res
+    }
+
+    /// Write a record of the primary in the log (if any) and apply its changes, as a single step for the snapshots
+    pub fn follow(&self, record: Record) -> Result<(), TupleError> {
+        let _following = self.following.lock().unwrap();
+
+        self.log(&record.space, record.changes.clone())?;
+        self.apply(record)
+    }
+
+    /// Apply the changes of a record of the log, without writing them again
+    fn apply(&self, record: Record) -> Result<(), TupleError> {
+        let mut spaces = self.spaces.lock().unwrap();
+
+        for change in record.changes {
+            match change {
+                Change::Create(multiset) => {
+                    let space = self.space(&record.space, multiset).map_err(failed)?;
+
+                    if let Some(replaced) = spaces.insert(record.space.clone(), space) {
+                        replaced.close();
+                    }
+                }
+                Change::Drop => {
+                    if let Some(dropped) = spaces.remove(&record.space) {
+                        dropped.close();
+                    }
+                }
+                change => {
+                    if let Some(space) = spaces.get(&record.space) {
+                        space.apply(change)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Name of the space selected by the path of the request, /spaces/<name> or the default space for any other path
+    pub fn space_name(path: &str) -> &str {
+        match path.strip_prefix(SPACES_PATH) {
+            Some(name) if !name.is_empty() => name,
+            _ => DEFAULT_SPACE,
+        }
+    }
+
+    /// Return the space with the given name, creating it if the auto creation is enabled (and the server is not a
+    /// backup, whose spaces are created by its primary)
+    pub fn get(&self, name: &str) -> Option<TupleSpace> {
+        let mut spaces = self.spaces.lock().unwrap();
+
+        if let Some(space) = spaces.get(name) {
+            return Some(space.clone());
+        }
+
+        if !self.auto_create || self.is_backup() {
+            return None;
+        }
+
+        // A space whose storage cannot be built is not created, the connection is refused
+        let space = self.space(name, self.multiset).map_err(failed).ok()?;
+        self.log(name, vec![Change::Create(self.multiset)]).ok()?;
+        spaces.insert(name.to_string(), space.clone());
+
+        Some(space)
+    }
+
+    /// Create a new empty space, returning SpaceAlreadyPresentError if a space with the same name exists
+    pub fn create(&self, name: &str, options: SpaceOptions) -> Result<(), TupleError> {
+        let mut spaces = self.spaces.lock().unwrap();
+
+        if name.is_empty() {
+            return Err(TupleError::Error);
+        }
+
+        if spaces.contains_key(name) {
+            return Err(TupleError::SpaceAlreadyPresentError);
+        }
+
+        let space = self.space(name, options.multiset).map_err(failed)?;
+        self.log(name, vec![Change::Create(options.multiset)])?;
+        spaces.insert(name.to_string(), space);
+
+        Ok(())
+    }
+
+    /// Name, size and semantics of all the spaces, sorted by name
+    pub fn list(&self) -> Result<Vec<SpaceInfo>, TupleError> {
+        let spaces = self.spaces.lock().unwrap();
+
+        let mut ret = spaces
+            .iter()
+            .map(|(name, space)| {
+                Ok(SpaceInfo {
+                    name: name.clone(),
+                    size: space.size()?,
+                    multiset: space.multiset,
+                })
+            })
+            .collect::<Result<Vec<SpaceInfo>, TupleError>>()?;
+        ret.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(ret)
+    }
+
+    /// Remove all the tuples from a space, returning SpaceNotFoundError if the space does not exist
+    pub fn clear(&self, name: &str) -> Result<(), TupleError> {
+        let spaces = self.spaces.lock().unwrap();
+
+        match spaces.get(name) {
+            Some(space) => space.clone().clear(),
+            None => Err(TupleError::SpaceNotFoundError),
+        }
+    }
+
+    /// Remove a space from the server, returning SpaceNotFoundError if the space does not exist. The default space
+    /// is selected by every path without a name, so it cannot be removed (returning Error)
+    pub fn drop_space(&self, name: &str) -> Result<(), TupleError> {
+        let mut spaces = self.spaces.lock().unwrap();
+
+        if name == DEFAULT_SPACE {
+            return Err(TupleError::Error);
+        }
+
+        if !spaces.contains_key(name) {
+            return Err(TupleError::SpaceNotFoundError);
+        }
+
+        self.log(name, vec![Change::Drop])?;
+        if let Some(space) = spaces.remove(name) {
+            space.close();
+        }
+
+        Ok(())
+    }
+
+    /// Close all the spaces, waking their blocked requests. The spaces stay in the registry (and in the log), the
+    /// later requests to them fail with SpaceNotFoundError
+    pub fn close(&self) {
+        let spaces = self.spaces.lock().unwrap();
+
+        for space in spaces.values() {
+            space.close();
+        }
+    }
+
+    /// Wake the blocked requests of all the spaces, when the server stops leading its cluster
+    pub fn interrupt(&self) {
+        let spaces = self.spaces.lock().unwrap();
+
+        for space in spaces.values() {
+            space.interrupt();
+        }
+    }
+
+    /// Take a snapshot of all the spaces. The registry is locked while the spaces are read, so that no space is
+    /// created or dropped in the meantime, and start is called first, returning the sequence number of the first
+    /// record of the log not in the snapshot
+    fn capture<T>(
+        &self,
+        wal: &Wal,
+        start: impl FnOnce() -> (u64, T),
+    ) -> Result<(Snapshot, T), TupleError> {
+        let spaces = self.spaces.lock().unwrap();
+        let (lsn, value) = start();
+
+        let snapshot = Snapshot {
+            lsn,
+            term: 0,
+            spaces: spaces
+                .iter()
+                .map(|(name, space)| space.snapshot(name, wal))
+                .collect::<Result<Vec<SpaceSnapshot>, TupleError>>()?,
+        };
+
+        Ok((snapshot, value))
+    }
+
+    /// Write a snapshot of all the spaces and cut the records of the log before it
+    pub fn snapshot(&self, wal: &Wal, path: &Path) -> io::Result<()> {
+        let _following = self.following.lock().unwrap();
+        let (mut snapshot, (offset, term)) = self
+            .capture(wal, || {
+                let (lsn, offset, term) = wal.mark();
+                (lsn, (offset, term))
+            })
+            .map_err(|_| io::Error::other("Error reading the storage of the tuples"))?;
+        snapshot.term = term;
+
+        snapshot.save(path)?;
+        wal.compact(offset)
+    }
+
+    /// Stream the changes to a backup, returning the snapshot of the spaces it starts from. Return
+    /// NotPrimaryError if the server does not log its changes
+    pub fn replicate(&self, replica: Replica) -> Result<Snapshot, TupleError> {
+        let wal = self.log.as_ref().ok_or(TupleError::NotPrimaryError)?;
+        let _following = self.following.lock().unwrap();
+        let (snapshot, _) = self.capture(wal, || (wal.subscribe(replica), ()))?;
+
+        Ok(snapshot)
+    }
+
+    /// True while the server is a backup, refusing the operations of the clients
+    pub fn is_backup(&self) -> bool {
+        self.backup.load(Ordering::SeqCst)
+    }
+
+    /// Turn a backup into a primary, serving the clients. Fail on a node of a cluster, whose leader is elected
+    pub fn promote(&self) -> Result<(), TupleError> {
+        if self.elected {
+            return Err(TupleError::Error);
+        }
+
+        self.backup.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Remove the expired tuples from all the spaces
+    pub fn expire(&self) {
+        let spaces = self.spaces.lock().unwrap();
+
+        for space in spaces.values() {
+            space.clone().expire();
+        }
+    }
+}
+
+/// Client connection where the replies of the operations are written
+trait Connection {
+    /// Queue a message for the client, sent at the latest by the next flush
+    fn write_text(&mut self, text: String) -> Result<(), TupleError>;
+
+    /// Send the queued messages
+    fn flush_text(&mut self);
+}
+
+impl Connection for WebSocket<TcpStream> {
+    fn write_text(&mut self, text: String) -> Result<(), TupleError> {
+        match self.write(Message::Text(text)) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(TupleError::Error),
+        }
+    }
+
+    fn flush_text(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// Replies collected in memory, sent later by the owner of the connection
+impl Connection for Vec<String> {
+    fn write_text(&mut self, text: String) -> Result<(), TupleError> {
+        self.push(text);
+        Ok(())
+    }
+
+    fn flush_text(&mut self) {}
+}
+
+fn deserialize(message: String) -> Result<Operation, TupleError> {
+    match serde_json::from_str(&message) {
+        Ok(res) => Ok(res),
+        Err(e) => {
+            eprintln!("Error serializing! Error: {}", e);
+            Err(TupleError::Error)
+        }
+    }
+}
+
+fn serialize_vector(vector: Vec<Tuple>) -> Result<String, TupleError> {
+    match serde_json::to_string(&vector) {
+        Ok(res) => Ok(res),
+        Err(e) => {
+            eprintln!("Error serializing! Error: {}", e);
+            Err(TupleError::Error)
+        }
+    }
+}
+
+fn write_value<T: Serialize>(socket: &mut impl Connection, value: &T) -> Result<(), TupleError> {
+    let serialized = match serde_json::to_string(value) {
+        Ok(res) => res,
+        Err(e) => {
+            eprintln!("Error serializing! Error: {}", e);
+            return Err(TupleError::Error);
+        }
+    };
+
+    socket.write_text(serialized)
+}
+
+fn handle_out(space: &mut TupleSpace, tuple: Tuple) -> Result<(), TupleError> {
+    if !tuple.has_data_only() {
+        return Err(TupleError::TupleNotOnlyDataError);
+    }
+
+    space.out(tuple)
+}
+
+fn handle_out_with(
+    space: &mut TupleSpace,
+    socket: &mut impl Connection,
+    tuple: Tuple,
+    options: OutOptions,
+) -> Result<(), TupleError> {
+    if !tuple.has_data_only() {
+        return Err(TupleError::TupleNotOnlyDataError);
+    }
+
+    let lease = space.out_with(
+        tuple,
+        options.ttl.map(Duration::from_millis),
+        options.priority,
+    )?;
+
+    write_value(socket, &lease)
+}
+
+fn handle_renew_lease(space: &mut TupleSpace, lease: u64, ttl: u64) -> Result<(), TupleError> {
+    space.renew(lease, Duration::from_millis(ttl))
+}
+
+/// Read the messages already arrived on the socket while a blocking request is pending (without waiting for more),
+/// looking for a Cancel of the client, replying an Error to any other operation, and push the notifications.
+/// Return CancelledError if the request is cancelled, or an Error if the connection is closed
+fn poll_cancel(
+    socket: &mut WebSocket<TcpStream>,
+    subscriptions: &mut Subscriptions,
+) -> Result<(), TupleError> {
+    let _ = socket.get_ref().set_nonblocking(true);
+
+    let ret = loop {
+        match socket.read() {
+            // Other operations are refused while a blocking request is pending, the client is waiting for its reply
+            Ok(Message::Text(val)) => match deserialize(val) {
+                Ok(Operation::Cancel) => break Err(TupleError::CancelledError),
+                _ => {
+                    let refused = serde_json::to_string(&TupleError::Error).unwrap();
+                    let _ = socket.send(Message::Text(refused));
+                }
+            },
+            Ok(Message::Close(_)) => break Err(TupleError::Error),
+            // Ping, pong and binary frames carry no operation
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(e))
+                if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut =>
+            {
+                subscriptions.flush(socket);
+                break Ok(());
+            }
+            Err(_) => break Err(TupleError::Error),
+        }
+    };
+
+    let _ = socket.get_ref().set_nonblocking(false);
+    ret
+}
+
+/// Wait until a queued blocking request is served, which wakes it as soon as its result is delivered. The watcher of
+/// the connection wakes the wait when the client sends a message, to look for a Cancel, and if the client has some
+/// subscriptions the wait also wakes every NOTIFY_INTERVAL to push their notifications. If the request is cancelled
+/// (or the connection is closed, or the timeout expires) before it is served it leaves the queue
+fn wait_delivery<T: Send + 'static>(
+    space: &mut TupleSpace,
+    socket: &mut WebSocket<TcpStream>,
+    subscriptions: &mut Subscriptions,
+    watcher: &mut Option<Watcher>,
+    wait: Wait<T>,
+    timeout: Option<Duration>,
+) -> Result<T, TupleError> {
+    let (ticket, receiver) = match wait {
+        Wait::Ready(res) => return res,
+        Wait::Queued(ticket, receiver) => (ticket, receiver),
+    };
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+    // The watcher blocks on the socket until the client sends a message
+    let _ = socket.get_ref().set_read_timeout(None);
+    let mut armed = false;
+
+    let served = loop {
+        // A Cancel may have arrived together with the request, so the socket is looked at before the first wait
+        if !armed {
+            if let Err(error) = poll_cancel(socket, subscriptions) {
+                break Err(error);
+            }
+
+            if watcher.is_none() {
+                match Watcher::new(socket.get_ref()) {
+                    Ok(started) => *watcher = Some(started),
+                    Err(_) => break Err(TupleError::Error),
+                }
+            }
+            if watcher
+                .as_ref()
+                .unwrap()
+                .arm(receiver.interrupter())
+                .is_err()
+            {
+                break Err(TupleError::Error);
+            }
+            armed = true;
+        }
+
+        let notify = (!subscriptions.ids.is_empty()).then(|| Instant::now() + NOTIFY_INTERVAL);
+        match receiver.recv_until(deadline.into_iter().chain(notify).min()) {
+            Ok(res) => break Ok(res),
+            // The queues are emptied without serving the requests only when the space is dropped
+            Err(Wakeup::Disconnected) => break Ok(Err(TupleError::SpaceNotFoundError)),
+            // The client sent a message, the watcher is armed again after reading it
+            Err(Wakeup::Interrupted) => armed = false,
+            Err(Wakeup::Timeout) if deadline.is_some_and(|deadline| Instant::now() >= deadline) => {
+                break Err(TupleError::TimeoutError);
+            }
+            Err(Wakeup::Timeout) => subscriptions.flush(socket),
+        }
+    };
+
+    let _ = socket.get_ref().set_read_timeout(Some(NOTIFY_INTERVAL));
+
+    let error = match served {
+        Ok(res) => return res,
+        Err(error) => error,
+    };
+
+    if space.leave(&ticket) {
+        return Err(error);
+    }
+
+    // The request was served in the meantime, so it is not cancelled
+    receiver
+        .recv()
+        .map_err(|_| TupleError::SpaceNotFoundError)
+        .and_then(|res| res)
+}
+
+fn handle_in_non_bl(
+    space: &mut TupleSpace,
+    socket: &mut impl Connection,
+    tuple: Tuple,
+) -> Result<(), TupleError> {
+    if tuple.has_data_only() {
+        return Err(TupleError::TupleOnlyDataError);
+    }
+
+    let ret = space._in(&tuple)?;
+    let serialized = serialize_vector(ret)?;
+
+    socket.write_text(serialized)
+}
+
+fn handle_in_one_non_bl(
+    space: &mut TupleSpace,
+    socket: &mut impl Connection,
+    tuple: Tuple,
+) -> Result<(), TupleError> {
+    if tuple.has_data_only() {
+        return Err(TupleError::TupleOnlyDataError);
+    }
+
+    let ret = space.in_one(&tuple)?;
+
+    write_value(socket, &ret)
+}
+
+fn handle_rd_non_bl(
+    space: &mut TupleSpace,
+    socket: &mut impl Connection,
+    tuple: Tuple,
+) -> Result<(), TupleError> {
+    if tuple.has_data_only() {
+        return Err(TupleError::TupleOnlyDataError);
+    }
+
+    let ret = space._rd(&tuple)?;
+    let serialized = serialize_vector(ret)?;
+
+    socket.write_text(serialized)
+}
+
+fn handle_count(
+    space: &mut TupleSpace,
+    socket: &mut impl Connection,
+    tuple: Tuple,
+) -> Result<(), TupleError> {
+    write_value(socket, &space.count(&tuple)?)
+}
+
+fn handle_scan(
+    space: &mut TupleSpace,
+    socket: &mut impl Connection,
+    pattern: Option<Tuple>,
+    page_size: usize,
+    cursor: Option<ScanCursor>,
+) -> Result<(), TupleError> {
+    let page = space.scan(pattern.as_ref(), page_size, cursor)?;
+
+    write_value(socket, &page)
+}
+
+fn handle_replace(
+    space: &mut TupleSpace,
+    socket: &mut impl Connection,
+    pattern: Tuple,
+    tuple: Tuple,
+) -> Result<(), TupleError> {
+    if !tuple.has_data_only() {
+        return Err(TupleError::TupleNotOnlyDataError);
+    }
+
+    let replaced = space.replace(&pattern, tuple)?;
+
+    write_value(socket, &replaced)
+}
+
+fn handle_transaction(
+    space: &mut TupleSpace,
+    socket: &mut impl Connection,
+    steps: Vec<TransactionStep>,
+) -> Result<(), TupleError> {
+    let (results, committed) = space.transaction(steps);
+
+    write_value(socket, &results)?;
+
+    committed
+}
+
+fn handle_subscribe(
+    space: &mut TupleSpace,
+    socket: &mut impl Connection,
+    subscriptions: &mut Subscriptions,
+    pattern: Tuple,
+) -> Result<(), TupleError> {
+    let id = space.subscribe(pattern, subscriptions.sender.clone());
+    subscriptions.ids.push(id);
+
+    write_value(socket, &id)
+}
+
+fn handle_unsubscribe(
+    space: &mut TupleSpace,
+    subscriptions: &mut Subscriptions,
+    id: u64,
+) -> Result<(), TupleError> {
+    if !subscriptions.ids.contains(&id) {
+        return Err(TupleError::SubscriptionNotFoundError);
+    }
+
+    space.unsubscribe(id);
+    subscriptions.ids.retain(|elem| *elem != id);
+
+    Ok(())
+}
+
+fn handle_admin(
+    spaces: &Spaces,
+    socket: &mut impl Connection,
+    admin_token: &Option<String>,
+    token: String,
+    operation: AdminOperation,
+) -> Result<(), TupleError> {
+    match admin_token {
+        Some(admin_token) if *admin_token == token => (),
+        _ => return Err(TupleError::UnauthorizedError),
+    }
+
+    // The spaces of a backup are changed only by its primary
+    match operation {
+        AdminOperation::CreateSpace(_, _)
+        | AdminOperation::ClearSpace(_)
+        | AdminOperation::DropSpace(_)
+            if spaces.is_backup() =>
+        {
+            Err(TupleError::NotPrimaryError)
+        }
+        AdminOperation::CreateSpace(name, options) => spaces.create(&name, options),
+        AdminOperation::ListSpaces => write_value(socket, &spaces.list()?),
+        AdminOperation::ClearSpace(name) => spaces.clear(&name),
+        AdminOperation::DropSpace(name) => spaces.drop_space(&name),
+        // A replication is only opened on its own path
+        AdminOperation::Replicate => Err(TupleError::Error),
+        AdminOperation::Promote => spaces.promote(),
+    }
+}
+
+/// Reply of a blocked In or Rd, made of the index of the pattern matched and its tuples
+#[derive(Clone, Copy)]
+enum Reply {
+    /// The tuples (InBl and RdBl)
+    Tuples,
+
+    /// The first tuple (InOneBl)
+    First,
+
+    /// The index of the pattern together with the tuples (InAny and RdAny)
+    Indexed,
+}
+
+impl Reply {
+    fn write(
+        self,
+        socket: &mut impl Connection,
+        (idx, mut tuples): (usize, Vec<Tuple>),
+    ) -> Result<(), TupleError> {
+        match self {
+            Reply::Tuples => write_value(socket, &tuples),
+            Reply::First => write_value(socket, &tuples.remove(0)),
+            Reply::Indexed => write_value(socket, &(idx, tuples)),
+        }
+    }
+}
+
+/// Outcome of an operation of a client: done, or a blocking request queued (or already served) in its space, whose
+/// delivery is waited for by the connection loop
+enum Dispatch {
+    Done(Result<(), TupleError>),
+    Match(Wait<(usize, Vec<Tuple>)>, Reply),
+    Empty(Wait<()>, Option<Duration>),
+    Count(Wait<Vec<Tuple>>),
+}
+
+/// Execute an operation of a client, or validate and queue it if it is blocking. Shared by the threaded and the async
+/// server, which wait for the delivery of the blocking requests each in its own way
+fn dispatch(
+    spaces: &Spaces,
+    admin_token: &Option<String>,
+    space: &mut TupleSpace,
+    socket: &mut impl Connection,
+    subscriptions: &mut Subscriptions,
+    operation: Operation,
+) -> Dispatch {
+    if let Operation::Admin(token, val) = operation {
+        return Dispatch::Done(handle_admin(spaces, socket, admin_token, token, val));
+    }
+
+    if spaces.is_backup() {
+        return Dispatch::Done(Err(TupleError::NotPrimaryError));
+    }
+
+    if space.is_dropped() {
+        return Dispatch::Done(Err(TupleError::SpaceNotFoundError));
+    }
+
+    match operation {
+        Operation::InBl(val) | Operation::InOneBl(val) | Operation::RdBl(val)
+            if val.has_data_only() =>
+        {
+            Dispatch::Done(Err(TupleError::TupleOnlyDataError))
+        }
+        Operation::InBl(val) => {
+            Dispatch::Match(space.wait(std::slice::from_ref(&val), true), Reply::Tuples)
+        }
+        Operation::InOneBl(val) => Dispatch::Match(space.wait_one(&val), Reply::First),
+        Operation::RdBl(val) => {
+            Dispatch::Match(space.wait(std::slice::from_ref(&val), false), Reply::Tuples)
+        }
+        Operation::InAny(patterns) | Operation::RdAny(patterns) if patterns.is_empty() => {
+            Dispatch::Done(Err(TupleError::Error))
+        }
+        Operation::InAny(patterns) | Operation::RdAny(patterns)
+            if patterns.iter().any(|pattern| pattern.has_data_only()) =>
+        {
+            Dispatch::Done(Err(TupleError::TupleOnlyDataError))
+        }
+        Operation::InAny(patterns) => Dispatch::Match(space.wait(&patterns, true), Reply::Indexed),
+        Operation::RdAny(patterns) => Dispatch::Match(space.wait(&patterns, false), Reply::Indexed),
+        Operation::WaitEmpty(pattern, timeout) => Dispatch::Empty(
+            space.wait_empty(&pattern),
+            timeout.map(Duration::from_millis),
+        ),
+        Operation::WaitCount(pattern, count, take) => {
+            Dispatch::Count(space.wait_count(&pattern, count, take))
+        }
+        operation => Dispatch::Done(execute(space, socket, subscriptions, operation)),
+    }
+}
+
+fn incoming_operations(
+    spaces: &Spaces,
+    admin_token: &Option<String>,
+    space: &mut TupleSpace,
+    socket: &mut WebSocket<TcpStream>,
+    subscriptions: &mut Subscriptions,
+    watcher: &mut Option<Watcher>,
+    operation: Operation,
+) -> Result<(), TupleError> {
+    match dispatch(spaces, admin_token, space, socket, subscriptions, operation) {
+        Dispatch::Done(res) => res,
+        Dispatch::Match(wait, reply) => {
+            let ret = wait_delivery(space, socket, subscriptions, watcher, wait, None)?;
+            reply.write(socket, ret)
+        }
+        Dispatch::Empty(wait, timeout) => {
+            wait_delivery(space, socket, subscriptions, watcher, wait, timeout)
+        }
+        Dispatch::Count(wait) => {
+            let ret = wait_delivery(space, socket, subscriptions, watcher, wait, None)?;
+            write_value(socket, &ret)
+        }
+    }
+}
+
+/// Execute an operation answered immediately, writing its result (if any) on the connection. The blocking operations
+/// are handled by the connection loops, which wait for their delivery
+fn execute(
+    space: &mut TupleSpace,
+    socket: &mut impl Connection,
+    subscriptions: &mut Subscriptions,
+    operation: Operation,
+) -> Result<(), TupleError> {
+    match operation {
+        Operation::Out(val) => handle_out(space, val),
+        Operation::InNonBl(val) => handle_in_non_bl(space, socket, val),
+        Operation::InOneNonBl(val) => handle_in_one_non_bl(space, socket, val),
+        Operation::RdNonBl(val) => handle_rd_non_bl(space, socket, val),
+        Operation::Count(val) => handle_count(space, socket, val),
+        Operation::Scan(pattern, page_size, cursor) => {
+            handle_scan(space, socket, pattern, page_size, cursor)
+        }
+        Operation::OutWith(val, options) => handle_out_with(space, socket, val, options),
+        Operation::RenewLease(lease, ttl) => handle_renew_lease(space, lease, ttl),
+        Operation::Replace(pattern, val) => handle_replace(space, socket, pattern, val),
+        Operation::Transaction(steps) => handle_transaction(space, socket, steps),
+        Operation::Subscribe(val) => handle_subscribe(space, socket, subscriptions, val),
+        Operation::Unsubscribe(id) => handle_unsubscribe(space, subscriptions, id),
+        // Blocking and admin operations are handled by the caller, and a cancel without a pending blocking request
+        // is discarded by the connection loop
+        Operation::InBl(_)
+        | Operation::InOneBl(_)
+        | Operation::RdBl(_)
+        | Operation::InAny(_)
+        | Operation::RdAny(_)
+        | Operation::WaitEmpty(_, _)
+        | Operation::WaitCount(_, _, _)
+        | Operation::Admin(_, _)
+        | Operation::Cancel => Ok(()),
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn callback(spaces: &Spaces, req: &Request, response: Response) -> Result<Response, ErrorResponse> {
+    if req.uri().path() != REPLICATION_PATH
+        && req.uri().path() != CLUSTER_PATH
+        && spaces.get(Spaces::space_name(req.uri().path())).is_none()
+    {
+        let mut error = ErrorResponse::new(Some("Tuple Space not found".to_string()));
+        *error.status_mut() = StatusCode::NOT_FOUND;
+        return Err(error);
+    }
+
+    Ok(response)
+}
+
+/// Outcome of an operation served by the leader of a cluster: the blocked requests interrupted when it stops leading
+/// fail with NotPrimaryError, so that their clients look for the new leader
+fn interrupted(spaces: &Spaces, res: Result<(), TupleError>) -> Result<(), TupleError> {
+    match res {
+        Err(TupleError::SpaceNotFoundError) if spaces.is_backup() => {
+            Err(TupleError::NotPrimaryError)
+        }
+        res => res,
+    }
+}
+
+/// Serve a client connection on its own thread, until the client closes it or the server shuts down
+fn serve(
+    spaces: Spaces,
+    cluster: Option<Arc<Cluster>>,
+    admin_token: Option<String>,
+    shutdown: Arc<AtomicBool>,
+    stream: TcpStream,
+) {
+    let mut path = String::new();
+    #[allow(clippy::result_large_err)]
+    let handshake = accept_hdr(stream, |req: &Request, response| {
+        path = req.uri().path().to_string();
+        callback(&spaces, req, response)
+    });
+
+    let mut websocket = match handshake {
+        Ok(websocket) => websocket,
+        Err(_) => return,
+    };
+
+    if path == REPLICATION_PATH {
+        replication::stream(&spaces, &admin_token, &shutdown, &mut websocket);
+        return;
+    }
+
+    if path == CLUSTER_PATH {
+        if let Some(cluster) = &cluster {
+            cluster::serve(cluster, &admin_token, &shutdown, &mut websocket);
+        }
+        return;
+    }
+
+    let mut cloned = match spaces.get(Spaces::space_name(&path)) {
+        Some(space) => space,
+        None => return,
+    };
+    let mut subscriptions = Subscriptions::new();
+    let mut watcher = None;
+
+    // Wake up periodically to push the notifications of the subscriptions
+    let _ = websocket.get_ref().set_read_timeout(Some(NOTIFY_INTERVAL));
+
+    while !shutdown.load(Ordering::SeqCst) {
+        let msg = websocket.read();
+        let leader = !spaces.is_backup();
+
+        let mut res = match msg {
+            Ok(mex) => match mex {
+                Message::Text(val) => match deserialize(val) {
+                    // The blocking request to cancel was already served, nothing to reply
+                    Ok(Operation::Cancel) => continue,
+                    Ok(operation) => incoming_operations(
+                        &spaces,
+                        &admin_token,
+                        &mut cloned,
+                        &mut websocket,
+                        &mut subscriptions,
+                        &mut watcher,
+                        operation,
+                    ),
+                    Err(error) => Err(error),
+                },
+                Message::Close(_) => {
+                    break;
+                }
+                // Ping, pong and binary frames carry no operation
+                _ => continue,
+            },
+            Err(tungstenite::Error::Io(e))
+                if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut =>
+            {
+                subscriptions.flush(&mut websocket);
+                continue;
+            }
+            Err(_) => {
+                break;
+            }
+        };
+
+        if let (true, Some(cluster)) = (leader, &cluster) {
+            res = interrupted(&spaces, res);
+
+            // The reply waits for the changes to be committed: if the leadership is lost in the meantime the operation
+            // may or may not survive, the connection is closed without a reply
+            if !matches!(res, Err(TupleError::NotPrimaryError)) && !cluster.barrier() {
+                break;
+            }
+        }
+
+        match res {
+            Ok(_) => {
+                let _ = websocket.send(Message::Text(
+                    serde_json::to_string(&TupleError::NoError).unwrap(),
+                ));
+            }
+            Err(error) => {
+                let _ = websocket.send(Message::Text(serde_json::to_string(&error).unwrap()));
+            }
+        }
+
+        // A client sending requests without pause never lets the read time out, so it is notified after every reply
+        subscriptions.flush(&mut websocket);
+    }
+
+    for id in subscriptions.ids.iter() {
+        cloned.unsubscribe(*id);
+    }
+}
+
+/// A Tuple Space server listening on an address, configured with the builder methods and then started with run
+/// (on the current thread) or spawn (on a new one)
+///
+/// ```no_run
+/// use rustuple::server::Server;
+///
+/// let handle = Server::bind("127.0.0.1:0").unwrap().multiset(true).spawn().unwrap();
+/// println!("Listening on {}", handle.local_addr());
+/// handle.shutdown();
+/// ```
+pub struct Server {
+    listener: TcpListener,
+    multiset: bool,
+    auto_create: bool,
+    admin_token: Option<String>,
+    index_field: usize,
+    #[cfg(feature = "async")]
+    async_io: bool,
+
+    /// Path and sync policy of the write-ahead log, if the server is persistent
+    wal: Option<(PathBuf, SyncPolicy)>,
+
+    /// Interval between the snapshots of the spaces, which compact the write-ahead log
+    snapshot_interval: Option<Duration>,
+
+    /// Constructor of the storages of the spaces
+    backend: Arc<dyn Backend>,
+
+    /// If true the changes are streamed to the backups, even if the server has no write-ahead log
+    primary: bool,
+
+    /// Address of the primary followed by the server, if it is a backup
+    primary_addr: Option<String>,
+
+    /// Addresses of the other nodes, if the server is a node of a cluster
+    peers: Option<Vec<String>>,
+
+    /// Set while the server is a backup, shared with the spaces
+    backup: Arc<AtomicBool>,
+    shutdown: Arc<AtomicBool>,
+
+    /// Held while a snapshot is written, so that a server shut down does not compact its log afterwards
+    snapshotting: Arc<Mutex<()>>,
+}
+
+impl Server {
+    /// Bind the server to the address (port 0 selects a free port, see local_addr), with the default settings: set
+    /// semantics, named spaces created only by the admin, admin operations disabled and tuples indexed by their first
+    /// field
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Ok(Server {
+            listener: TcpListener::bind(addr)?,
+            multiset: false,
+            auto_create: false,
+            admin_token: None,
+            index_field: 0,
+            #[cfg(feature = "async")]
+            async_io: false,
+            wal: None,
+            snapshot_interval: None,
+            backend: Arc::new(MemoryBackend),
+            primary: false,
+            primary_addr: None,
+            peers: None,
+            backup: Arc::new(AtomicBool::new(false)),
+            shutdown: Arc::new(AtomicBool::new(false)),
+            snapshotting: Arc::new(Mutex::new(())),
+        })
+    }
+
+    /// Store duplicate tuples (multiset semantics) in the default space and in the spaces created on first use
+    pub fn multiset(mut self, multiset: bool) -> Self {
+        self.multiset = multiset;
+        self
+    }
+
+    /// Create a named space the first time a client connects to it. By default the named spaces are created only
+    /// with the CreateSpace admin operation, so the clients cannot make the server grow them without limit
+    pub fn auto_create(mut self, auto_create: bool) -> Self {
+        self.auto_create = auto_create;
+        self
+    }
+
+    /// Enable the admin operations, authenticated by the given credential
+    pub fn admin_token(mut self, token: impl Into<String>) -> Self {
+        self.admin_token = Some(token.into());
+        self
+    }
+
+    /// Position of the field used to index the tuples
+    pub fn index_field(mut self, index_field: usize) -> Self {
+        self.index_field = index_field;
+        self
+    }
+
+    /// Serve the connections as tasks of a tokio runtime instead of one thread each
+    #[cfg(feature = "async")]
+    pub fn async_io(mut self, async_io: bool) -> Self {
+        self.async_io = async_io;
+        self
+    }
+
+    /// Persist the spaces in a write-ahead log, replayed when the server starts to rebuild them
+    pub fn wal(mut self, path: impl Into<PathBuf>, sync: SyncPolicy) -> Self {
+        self.wal = Some((path.into(), sync));
+        self
+    }
+
+    /// Periodically write a snapshot of the spaces next to the write-ahead log (in <path>.snapshot) and cut the
+    /// records before it, so that the log does not grow without bound. Requires the write-ahead log
+    pub fn snapshot_interval(mut self, interval: Duration) -> Self {
+        self.snapshot_interval = Some(interval);
+        self
+    }
+
+    /// Keep the tuples in the storages built by the given backend instead of memory
+    pub fn backend(mut self, backend: impl Backend + 'static) -> Self {
+        self.backend = Arc::new(backend);
+        self
+    }
+
+    /// Accept backup servers, streaming them every change of the spaces (a server with a write-ahead log always
+    /// accepts them). The backups authenticate with the admin token
+    pub fn primary(mut self, primary: bool) -> Self {
+        self.primary = primary;
+        self
+    }
+
+    /// Run as a backup of the primary server at the given address (IP:PORT), authenticated by the admin token. The
+    /// backup copies the spaces of the primary and refuses the operations of the clients until it is promoted, with
+    /// the Promote admin operation or the handle of the server. The replication is asynchronous: the primary replies
+    /// without waiting for its backups, so the last operations replied before its failure can be missing from the
+    /// promoted backup
+    pub fn backup_of(mut self, primary_addr: impl Into<String>) -> Self {
+        self.primary_addr = Some(primary_addr.into());
+        self.backup.store(true, Ordering::SeqCst);
+        self
+    }
+
+    /// Run as a node of a cluster together with the servers at the given addresses (IP:PORT), authenticated by the
+    /// admin token. The nodes elect a leader, the only one serving the clients, and replicate its log of the changes:
+    /// an operation is replied only after its changes are synced to the log of a majority of the nodes, so the
+    /// cluster survives the failure of a minority of them. Requires the write-ahead log, whatever its sync policy
+    pub fn cluster<S: Into<String>>(mut self, peers: impl IntoIterator<Item = S>) -> Self {
+        self.peers = Some(peers.into_iter().map(Into::into).collect());
+        self.backup.store(true, Ordering::SeqCst);
+        self
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.listener.local_addr().unwrap()
+    }
+
+    /// Handle to shut down the server, e.g. from another thread while it runs
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle {
+            local_addr: self.local_addr(),
+            backup: Arc::clone(&self.backup),
+            elected: self.peers.is_some(),
+            shutdown: Arc::clone(&self.shutdown),
+            snapshotting: Arc::clone(&self.snapshotting),
+            thread: None,
+        }
+    }
+
+    /// Start the server in a new thread, returning its handle. Fail if the log cannot be read, or if a snapshot
+    /// interval or a cluster is set without a write-ahead log
+    pub fn spawn(self) -> io::Result<ServerHandle> {
+        let (spaces, cluster) = self.start()?;
+        let mut handle = self.handle();
+        handle.thread = Some(spawn(move || {
+            if let Err(e) = self.accept(spaces, cluster) {
+                eprintln!("Error accepting the clients: {}", e);
+            }
+        }));
+
+        Ok(handle)
+    }
+
+    /// Serve the clients on the current thread, until the server is shut down. Fail as spawn does
+    pub fn run(self) -> io::Result<()> {
+        let (spaces, cluster) = self.start()?;
+        self.accept(spaces, cluster)
+    }
+
+    /// Rebuild the spaces from the snapshot and the log (if any) and start the background threads, and the node of
+    /// the cluster (if any)
+    fn start(&self) -> io::Result<(Spaces, Option<Arc<Cluster>>)> {
+        if self.snapshot_interval.is_some() && self.wal.is_none() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Snapshots require a write-ahead log",
+            ));
+        }
+
+        if self.peers.is_some() && self.wal.is_none() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "A node of a cluster requires a write-ahead log",
+            ));
+        }
+
+        let (log, snapshot, records) = match &self.wal {
+            Some((path, sync)) => {
+                let snapshot = Snapshot::load(&Snapshot::path(path))?;
+                let first_lsn = snapshot.as_ref().map_or(0, |snapshot| snapshot.lsn);
+                let (wal, records) = Wal::open(path, *sync, first_lsn)?;
+
+                (Some(Arc::new(wal)), snapshot, records)
+            }
+            None if self.primary => (Some(Arc::new(Wal::memory())), None, vec![]),
+            None => (None, None, vec![]),
+        };
+        let horizon = snapshot.as_ref().map(Snapshot::horizon).unwrap_or_default();
+
+        let spaces = Spaces::new(
+            self.multiset,
+            self.auto_create,
+            self.index_field,
+            log.clone(),
+            Arc::clone(&self.backend),
+            Arc::clone(&self.backup),
+            self.peers.is_some(),
+        )?;
+
+        // The records written since the snapshot are sent by a leader to the nodes behind it
+        if let (Some(wal), Some(_)) = (&log, &self.peers) {
+            let (lsn, term) = snapshot
+                .as_ref()
+                .map_or((0, 0), |snapshot| (snapshot.lsn, snapshot.term));
+            let start = records
+                .iter()
+                .position(|record| record.lsn >= lsn)
+                .unwrap_or(records.len());
+            wal.keep(cluster::TAIL, &records[start..], term)?;
+        }
+        spaces.replay(snapshot, records).map_err(|_| {
+            io::Error::other("Error rebuilding the spaces from the write-ahead log")
+        })?;
+
+        let cluster = match (&log, &self.wal, &self.peers) {
+            (Some(wal), Some((path, _)), Some(peers)) => {
+                let cluster = Cluster::new(
+                    peers.clone(),
+                    self.admin_token.clone().unwrap_or_default(),
+                    spaces.clone(),
+                    Arc::clone(wal),
+                    path,
+                    horizon,
+                )?;
+                cluster.start(&self.shutdown);
+
+                Some(cluster)
+            }
+            _ => None,
+        };
+
+        if let Some(primary_addr) = &self.primary_addr {
+            let url = format!("ws://{}{}", primary_addr, REPLICATION_PATH);
+            let admin_token = self.admin_token.clone().unwrap_or_default();
+            let snapshot_path = self.wal.as_ref().map(|(path, _)| Snapshot::path(path));
+            let spaces = spaces.clone();
+            let shutdown = Arc::clone(&self.shutdown);
+            spawn(move || {
+                replication::follow(&url, &admin_token, &spaces, snapshot_path, &shutdown)
+            });
+        }
+
+        let expiring = spaces.clone();
+        let shutdown = Arc::clone(&self.shutdown);
+        spawn(move || {
+            while !shutdown.load(Ordering::SeqCst) {
+                sleep(EXPIRE_INTERVAL);
+
+                // The tuples of a backup expire with the records of its primary
+                if !expiring.is_backup() {
+                    expiring.expire();
+                }
+            }
+        });
+
+        if let (Some(wal), Some((path, _)), Some(interval)) =
+            (&log, &self.wal, self.snapshot_interval)
+        {
+            let wal = Arc::clone(wal);
+            let path = Snapshot::path(path);
+            let spaces = spaces.clone();
+            let cluster = cluster.clone();
+            let shutdown = Arc::clone(&self.shutdown);
+            let snapshotting = Arc::clone(&self.snapshotting);
+            spawn(move || loop {
+                sleep(interval);
+
+                let _snapshotting = snapshotting.lock().unwrap();
+                if shutdown.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                let res = match &cluster {
+                    Some(cluster) => cluster.snapshot(),
+                    None => spaces.snapshot(&wal, &path),
+                };
+                if let Err(e) = res {
+                    eprintln!("Error writing the snapshot: {}", e);
+                }
+            });
+        }
+
+        if let (Some(wal), Some((_, SyncPolicy::Periodic(interval)))) = (log, &self.wal) {
+            let interval = *interval;
+            let shutdown = Arc::clone(&self.shutdown);
+            spawn(move || loop {
+                sleep(interval);
+                let _ = wal.sync();
+
+                if shutdown.load(Ordering::SeqCst) {
+                    break;
+                }
+            });
+        }
+
+        Ok((spaces, cluster))
+    }
+
+    /// Accept the clients until the server is shut down. Fail if the runtime of the async server cannot be started
+    fn accept(self, spaces: Spaces, cluster: Option<Arc<Cluster>>) -> io::Result<()> {
+        #[cfg(feature = "async")]
+        if self.async_io {
+            return async_server::run(
+                self.listener,
+                spaces,
+                cluster,
+                self.admin_token,
+                self.shutdown,
+            );
+        }
+
+        for stream in self.listener.incoming() {
+            if self.shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+
+            let spaces = spaces.clone();
+            let cluster = cluster.clone();
+            let admin_token = self.admin_token.clone();
+            let shutdown = Arc::clone(&self.shutdown);
+            spawn(move || serve(spaces, cluster, admin_token, shutdown, stream));
+        }
+
+        // Wake the blocked requests, their connections are closed at the next check of the shutdown
+        spaces.close();
+
+        Ok(())
+    }
+}
+
+/// Handle of a running server
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    backup: Arc<AtomicBool>,
+
+    /// Set on a node of a cluster, which is not promoted
+    elected: bool,
+    shutdown: Arc<AtomicBool>,
+    snapshotting: Arc<Mutex<()>>,
+
+    /// Thread of the server, if it was spawned
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ServerHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Promote a backup server to primary: it stops following its primary and serves the clients. A node of a
+    /// cluster is not promoted, its leader is elected
+    pub fn promote(&self) {
+        if !self.elected {
+            self.backup.store(false, Ordering::SeqCst);
+        }
+    }
+
+    /// True while the server serves the clients: it is not a backup, or it is the leader of its cluster
+    pub fn is_primary(&self) -> bool {
+        !self.backup.load(Ordering::SeqCst)
+    }
+
+    /// Stop the server: it stops accepting connections, the blocked requests fail with SpaceNotFoundError and the
+    /// connections are closed within NOTIFY_INTERVAL. Wait for the snapshot being written (if any), and if the server was
+    /// spawned for its thread to end
+    pub fn shutdown(self) {
+        // The requests still arriving to a node of a cluster are refused, so that their clients look for the new leader
+        if self.elected {
+            self.backup.store(true, Ordering::SeqCst);
+        }
+        self.shutdown.store(true, Ordering::SeqCst);
+
+        // No snapshot starts after the flag, the log can be opened again once the current one is written
+        drop(self.snapshotting.lock().unwrap());
+
+        // Wake the server waiting for a new connection
+        let mut addr = self.local_addr;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        let _ = TcpStream::connect(addr);
+
+        if let Some(thread) = self.thread {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    use crate::data::{Field, Type, Value};
+
+    use super::*;
+
+    fn int(val: i32) -> Field {
+        Field::Value(Value::Integer(val))
+    }
+
+    fn string(val: &str) -> Field {
+        Field::Value(Value::String(val.to_string()))
+    }
+
+    /// Producers put jobs of two arities while consumers take them with blocking requests on both patterns (leaving
+    /// the queue now and then) and readers look at the space: every job must be taken exactly once
+    #[test]
+    fn concurrent_jobs_are_taken_exactly_once() {
+        const PRODUCERS: i32 = 4;
+        const JOBS: i32 = 500;
+        let total = (PRODUCERS * JOBS) as usize;
+
+        let space =
+            TupleSpace::new(false, 0, None, || Ok(Box::new(MemoryStorage::new(0)))).unwrap();
+        let taken = Arc::new(Mutex::new(HashSet::new()));
+        let done = Arc::new(AtomicUsize::new(0));
+        let patterns = [
+            tuple!(string("job"), Field::Type(Type::Integer)),
+            tuple!(
+                string("job"),
+                Field::Type(Type::Integer),
+                Field::Type(Type::Integer)
+            ),
+        ];
+        let mut handles = vec![];
+
+        for producer in 0..PRODUCERS {
+            let mut space = space.clone();
+
+            handles.push(thread::spawn(move || {
+                for job in 0..JOBS {
+                    let tuple = match job % 2 {
+                        0 => tuple!(string("job"), int(producer * JOBS + job)),
+                        _ => tuple!(string("job"), int(producer * JOBS + job), int(producer)),
+                    };
+                    space.out(tuple).unwrap();
+                }
+            }));
+        }
+
+        for _ in 0..4 {
+            let mut space = space.clone();
+            let taken = Arc::clone(&taken);
+            let done = Arc::clone(&done);
+            let patterns = patterns.clone();
+
+            handles.push(thread::spawn(move || {
+                while done.load(Ordering::SeqCst) < total {
+                    let tuples = match space.wait(&patterns, true) {
+                        Wait::Ready(res) => res.unwrap().1,
+                        Wait::Queued(ticket, receiver) => {
+                            match receiver
+                                .recv_until(Some(Instant::now() + Duration::from_millis(5)))
+                            {
+                                Ok(res) => res.unwrap().1,
+                                Err(_) if space.leave(&ticket) => continue,
+                                Err(_) => receiver.recv().unwrap().unwrap().1,
+                            }
+                        }
+                    };
+
+                    let mut taken = taken.lock().unwrap();
+                    for tuple in tuples {
+                        let job = match tuple.iter().nth(1) {
+                            Some(Field::Value(Value::Integer(job))) => *job,
+                            _ => panic!("unexpected tuple {}", tuple),
+                        };
+                        assert!(taken.insert(job), "job {} taken twice", job);
+                        done.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+            }));
+        }
+
+        for _ in 0..2 {
+            let mut space = space.clone();
+            let done = Arc::clone(&done);
+            let patterns = patterns.clone();
+
+            handles.push(thread::spawn(move || {
+                while done.load(Ordering::SeqCst) < total {
+                    for pattern in patterns.iter() {
+                        let _ = space._rd(pattern);
+                        space.count(pattern).unwrap();
+                    }
+                    space.scan(None, 100, None).unwrap();
+                }
+            }));
+        }
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(taken.lock().unwrap().len(), total);
+        assert_eq!(space.size().unwrap(), 0);
+    }
+
+    /// A single In takes the tuple with highest priority, in insertion order among the same priority, and a blocked
+    /// In is served first with the tuple of highest priority put by a transaction
+    #[test]
+    fn single_in_follows_the_priorities() {
+        let mut space =
+            TupleSpace::new(true, 0, None, || Ok(Box::new(MemoryStorage::new(0)))).unwrap();
+        let pattern = tuple!(string("job"), Field::Type(Type::Integer));
+
+        for (job, priority) in [(1, 0), (2, 5), (3, 0), (4, 5)] {
+            space
+                .out_with(tuple!(string("job"), int(job)), None, priority)
+                .unwrap();
+        }
+
+        let taken = (0..4)
+            .map(|_| space.in_one(&pattern).unwrap().to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(taken, ["(job, 2)", "(job, 4)", "(job, 1)", "(job, 3)"]);
+        assert!(matches!(
+            space.in_one(&pattern),
+            Err(TupleError::NoMatchingTupleError)
+        ));
+
+        let receiver = match space.wait_one(&pattern) {
+            Wait::Queued(_, receiver) => receiver,
+            Wait::Ready(_) => panic!("the space is empty"),
+        };
+        let (results, res) = space.transaction(vec![
+            TransactionStep::Out(tuple!(string("job"), int(5))),
+            TransactionStep::OutWithPriority(tuple!(string("job"), int(6)), 9),
+        ]);
+        assert!(res.is_ok() && results.iter().all(Result::is_ok));
+
+        let (_, tuples) = receiver.recv().unwrap().unwrap();
+        assert_eq!(tuples[0].to_string(), "(job, 6)");
+        assert_eq!(space.in_one(&pattern).unwrap().to_string(), "(job, 5)");
+    }
+
+    /// The tuples with different values of the indexed field are in different shards: a pattern with a type in that
+    /// field sees all of them, and the requests waiting on it are served by the changes of any shard
+    #[test]
+    fn patterns_span_the_shards() {
+        let mut space =
+            TupleSpace::new(false, 0, None, || Ok(Box::new(MemoryStorage::new(0)))).unwrap();
+        let any = tuple!(Field::Type(Type::String), Field::Type(Type::Integer));
+        let keys = ["a", "b", "c", "d", "e", "f"];
+
+        let counted = match space.wait_count(&any, keys.len(), true) {
+            Wait::Queued(_, receiver) => receiver,
+            Wait::Ready(_) => panic!("the space is empty"),
+        };
+
+        let mut leases = vec![];
+        for (val, key) in keys.iter().enumerate() {
+            let ttl = Some(Duration::from_secs(60));
+            leases.push(
+                space
+                    .out_with(tuple!(string(key), int(val as i32)), ttl, 0)
+                    .unwrap(),
+            );
+        }
+
+        // All the tuples are taken together by the waiting request, in insertion order
+        let taken = counted.recv().unwrap().unwrap();
+        assert_eq!(taken.len(), keys.len());
+        assert_eq!(taken[0].to_string(), "(a, 0)");
+        assert_eq!(space.size().unwrap(), 0);
+        for lease in leases.into_iter().flatten() {
+            assert!(matches!(
+                space.renew(lease, Duration::from_secs(1)),
+                Err(TupleError::LeaseNotFoundError)
+            ));
+        }
+
+        let lease = space
+            .out_with(
+                tuple!(string("a"), int(1)),
+                Some(Duration::from_secs(60)),
+                0,
+            )
+            .unwrap()
+            .unwrap();
+        space.out(tuple!(string("b"), int(2))).unwrap();
+        space.renew(lease, Duration::from_secs(1)).unwrap();
+        assert_eq!(space.count(&any).unwrap(), 2);
+        assert_eq!(space._rd(&any).unwrap().len(), 2);
+
+        let emptied = match space.wait_empty(&any) {
+            Wait::Queued(_, receiver) => receiver,
+            Wait::Ready(_) => panic!("the space is not empty"),
+        };
+        space.in_one(&any).unwrap();
+        assert!(emptied.recv_until(Some(Instant::now())).is_err());
+        space.in_one(&any).unwrap();
+        emptied.recv().unwrap().unwrap();
+    }
+
+    /// Memory storage failing the next inserts, as many as the armed failures
+    struct Failing {
+        storage: MemoryStorage,
+        failures: Arc<AtomicUsize>,
+    }
+
+    impl Storage for Failing {
+        fn insert(&mut self, entry: Entry) -> io::Result<()> {
+            let armed = self
+                .failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+            if armed.is_ok() {
+                return Err(io::Error::other("failed insert"));
+            }
+
+            self.storage.insert(entry)
+        }
+
+        fn remove(&mut self, seq: u64) -> io::Result<Option<Entry>> {
+            self.storage.remove(seq)
+        }
+
+        fn get(&self, seq: u64) -> io::Result<Option<Cow<'_, Entry>>> {
+            self.storage.get(seq)
+        }
+
+        fn leased(&self, lease: u64) -> io::Result<Option<u64>> {
+            self.storage.leased(lease)
+        }
+
+        fn scan<'a>(
+            &'a self,
+            pattern: Option<&'a Tuple>,
+            after: u64,
+            now: Instant,
+        ) -> Box<dyn Iterator<Item = io::Result<Cow<'a, Entry>>> + 'a> {
+            self.storage.scan(pattern, after, now)
+        }
+
+        fn expired(&self, now: Instant) -> io::Result<Vec<u64>> {
+            self.storage.expired(now)
+        }
+
+        fn clear(&mut self) -> io::Result<()> {
+            self.storage.clear()
+        }
+    }
+
+    /// An operation failing because of an error of the storage returns an Error and leaves the space as it was, also
+    /// the changes it already made
+    #[test]
+    fn storage_errors_roll_back_the_operations() {
+        let failures = Arc::new(AtomicUsize::new(0));
+        let armed = Arc::clone(&failures);
+        let mut space = TupleSpace::new(false, 0, None, move || {
+            Ok(Box::new(Failing {
+                storage: MemoryStorage::new(0),
+                failures: Arc::clone(&armed),
+            }))
+        })
+        .unwrap();
+        let job = tuple!(string("job"), int(1));
+        space.out(job.clone()).unwrap();
+
+        failures.store(1, Ordering::SeqCst);
+        assert!(matches!(
+            space.out(tuple!(string("job"), int(2))),
+            Err(TupleError::Error)
+        ));
+
+        // The job taken by the first step is put back
+        failures.store(1, Ordering::SeqCst);
+        let (results, res) = space.transaction(vec![
+            TransactionStep::In(tuple!(string("job"), Field::Type(Type::Integer))),
+            TransactionStep::Out(tuple!(string("log"), int(1))),
+        ]);
+        assert!(matches!(res, Err(TupleError::Error)));
+        assert!(results[0].is_ok());
+
+        assert_eq!(space.count(&job).unwrap(), 1);
+        assert_eq!(space.size().unwrap(), 1);
+        assert_eq!(space.in_one(&job).unwrap().to_string(), "(job, 1)");
+    }
+
+    /// Tokens move back and forth between two arities (so different shards) with transactions, while a reader scans the
+    /// whole space: every scan must see every token exactly once
+    #[test]
+    fn transactions_are_atomic_across_shards() {
+        const TOKENS: i32 = 64;
+        let mut space =
+            TupleSpace::new(false, 0, None, || Ok(Box::new(MemoryStorage::new(0)))).unwrap();
+
+        for token in 0..TOKENS {
+            space.out(tuple!(int(token), string("a"))).unwrap();
+        }
+
+        let running = Arc::new(AtomicBool::new(true));
+        let mut workers = vec![];
+
+        for worker in 0..8 {
+            let mut space = space.clone();
+
+            workers.push(thread::spawn(move || {
+                for step in 0..2000 {
+                    let token = (worker * 7919 + step * 31) % TOKENS;
+                    let to_b = vec![
+                        TransactionStep::In(tuple!(int(token), Field::Type(Type::String))),
+                        TransactionStep::Out(tuple!(int(token), string("b"), string("b"))),
+                    ];
+                    let to_a = vec![
+                        TransactionStep::In(tuple!(
+                            int(token),
+                            Field::Type(Type::String),
+                            Field::Type(Type::String)
+                        )),
+                        TransactionStep::Out(tuple!(int(token), string("a"))),
+                    ];
+
+                    // Another worker can move the token back in the meantime, so the second move can fail too
+                    if space.transaction(to_b).1.is_err() {
+                        let _ = space.transaction(to_a);
+                    }
+                }
+            }));
+        }
+
+        let reader = {
+            let space = space.clone();
+            let running = Arc::clone(&running);
+
+            thread::spawn(move || {
+                let mut scans = 0;
+
+                while running.load(Ordering::SeqCst) || scans == 0 {
+                    let page = space.scan(None, TOKENS as usize + 1, None).unwrap();
+                    let mut seen = HashSet::new();
+
+                    for tuple in page.tuples.iter() {
+                        match tuple.iter().next() {
+                            Some(Field::Value(Value::Integer(token))) => {
+                                assert!(seen.insert(*token), "token {} seen twice", token)
+                            }
+                            _ => panic!("unexpected tuple {}", tuple),
+                        }
+                    }
+                    assert_eq!(seen.len(), TOKENS as usize);
+                    scans += 1;
+                }
+            })
+        };
+
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        running.store(false, Ordering::SeqCst);
+        reader.join().unwrap();
+
+        assert_eq!(space.size().unwrap(), TOKENS as usize);
+    }
+
+    /// Spaces persisted in a write-ahead log, with a multiset semantics so that a record replayed twice would be seen
+    fn persistent(path: &Path) -> (Spaces, Arc<Wal>) {
+        let snapshot = Snapshot::load(&Snapshot::path(path)).unwrap();
+        let first_lsn = snapshot.as_ref().map_or(0, |snapshot| snapshot.lsn);
+        let (wal, records) = Wal::open(path, SyncPolicy::Never, first_lsn).unwrap();
+        let wal = Arc::new(wal);
+
+        let spaces = Spaces::new(
+            true,
+            true,
+            0,
+            Some(Arc::clone(&wal)),
+            Arc::new(MemoryBackend),
+            Arc::new(AtomicBool::new(false)),
+            false,
+        )
+        .unwrap();
+        spaces.replay(snapshot, records).unwrap();
+
+        (spaces, wal)
+    }
+
+    /// Take a snapshot while the log is written, write more records and restart: the spaces are rebuilt from the
+    /// snapshot and the records after it. A record written to a space between the start of the snapshot and the
+    /// moment the space is read is in both, and must be skipped when the log is replayed
+    #[test]
+    fn restart_replays_the_log_after_the_snapshot() {
+        let path =
+            std::env::temp_dir().join(format!("rustuple-snapshot-{}.wal", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(Snapshot::path(&path));
+
+        let (spaces, wal) = persistent(&path);
+        let mut jobs = spaces.get("jobs").unwrap();
+        let mut logs = spaces.get("logs").unwrap();
+        jobs.out(tuple!(string("job"), int(1))).unwrap();
+        jobs.out(tuple!(string("job"), int(2))).unwrap();
+
+        let (snapshot, offset) = spaces
+            .capture(&wal, || {
+                let (lsn, offset, _) = wal.mark();
+                logs.out(tuple!(string("log"), int(1))).unwrap();
+
+                (lsn, offset)
+            })
+            .unwrap();
+        assert!(snapshot.spaces.iter().any(|space| space.lsn > snapshot.lsn));
+        snapshot.save(&Snapshot::path(&path)).unwrap();
+        wal.compact(offset).unwrap();
+
+        jobs._in(&tuple!(string("job"), int(1))).unwrap();
+        logs.out(tuple!(string("log"), int(2))).unwrap();
+        drop((spaces, wal, jobs, logs));
+
+        // Only the record of the logs written while the snapshot was taken is skipped
+        let horizon = Snapshot::load(&Snapshot::path(&path))
+            .unwrap()
+            .unwrap()
+            .horizon();
+        let (_, records) = Wal::open(&path, SyncPolicy::Never, 0).unwrap();
+        let skipped = records
+            .iter()
+            .map(|record| (record.space.as_str(), horizon.contains(record)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            skipped,
+            vec![("logs", true), ("jobs", false), ("logs", false)]
+        );
+
+        let (spaces, _) = persistent(&path);
+        let jobs = spaces.get("jobs").unwrap();
+        let logs = spaces.get("logs").unwrap();
+        assert_eq!(jobs.count(&tuple!(string("job"), int(1))).unwrap(), 0);
+        assert_eq!(jobs.count(&tuple!(string("job"), int(2))).unwrap(), 1);
+        assert_eq!(logs.count(&tuple!(string("log"), int(1))).unwrap(), 1);
+        assert_eq!(logs.count(&tuple!(string("log"), int(2))).unwrap(), 1);
+
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(Snapshot::path(&path));
+    }
+
+    /// The operations whose changes cannot be written in the log fail with an error and leave the space as it was,
+    /// and a blocked request served by one of them receives the error instead of the tuples
+    #[test]
+    fn unlogged_operations_are_rolled_back() {
+        let path =
+            std::env::temp_dir().join(format!("rustuple-rollback-{}.wal", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let (spaces, wal) = persistent(&path);
+        let mut jobs = spaces.get("jobs").unwrap();
+        jobs.out(tuple!(string("job"), int(1))).unwrap();
+        let patterns = [tuple!(string("task"), Field::Type(Type::Integer))];
+        let receiver = match jobs.wait(&patterns, true) {
+            Wait::Queued(_, receiver) => receiver,
+            Wait::Ready(_) => panic!("no task in the space"),
+        };
+
+        wal::tests::break_file(&wal);
+        assert!(matches!(
+            jobs.out(tuple!(string("task"), int(1))),
+            Err(TupleError::Error)
+        ));
+        assert!(matches!(receiver.recv().unwrap(), Err(TupleError::Error)));
+        assert!(matches!(
+            jobs._in(&tuple!(string("job"), int(1))),
+            Err(TupleError::Error)
+        ));
+        assert_eq!(jobs.count(&tuple!(string("job"), int(1))).unwrap(), 1);
+        assert_eq!(jobs.count(&patterns[0]).unwrap(), 0);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn snapshots_require_a_wal() {
+        let server = Server::bind("127.0.0.1:0")
+            .unwrap()
+            .snapshot_interval(Duration::from_secs(1));
+
+        assert_eq!(
+            server.spawn().err().map(|e| e.kind()),
+            Some(ErrorKind::InvalidInput)
+        );
+    }
+
+    #[test]
+    fn clusters_require_a_wal() {
+        let server = Server::bind("127.0.0.1:0")
+            .unwrap()
+            .admin_token("token")
+            .cluster(["127.0.0.1:1"]);
+
+        assert_eq!(
+            server.spawn().err().map(|e| e.kind()),
+            Some(ErrorKind::InvalidInput)
+        );
+    }
+
+    /// A backup connecting again to its primary restores a new copy of the spaces: the spaces still in the copy keep
+    /// serving their connections with the new content, the other ones are closed
+    #[test]
+    fn restore_keeps_the_spaces_in_place() {
+        let spaces = Spaces::new(
+            false,
+            true,
+            0,
+            None,
+            Arc::new(MemoryBackend),
+            Arc::new(AtomicBool::new(false)),
+            false,
+        )
+        .unwrap();
+        let mut jobs = spaces.get("jobs").unwrap();
+        let mut logs = spaces.get("logs").unwrap();
+        jobs.out(tuple!(string("job"), int(1))).unwrap();
+        logs.out(tuple!(string("log"), int(1))).unwrap();
+
+        let snapshot = Snapshot {
+            lsn: 0,
+            term: 0,
+            spaces: vec![SpaceSnapshot {
+                name: "jobs".to_string(),
+                multiset: false,
+                lsn: 0,
+                next_seq: 6,
+                next_lease: 0,
+                tuples: vec![Change::Put {
+                    seq: 5,
+                    tuple: tuple!(string("job"), int(2)),
+                    priority: 0,
+                    lease: None,
+                }],
+            }],
+        };
+        spaces.restore(snapshot).unwrap();
+
+        assert!(!jobs.is_dropped());
+        assert_eq!(jobs.count(&tuple!(string("job"), int(1))).unwrap(), 0);
+        assert_eq!(jobs.count(&tuple!(string("job"), int(2))).unwrap(), 1);
+        jobs.out(tuple!(string("job"), int(3))).unwrap();
+        assert_eq!(jobs.size().unwrap(), 2);
+        assert!(logs.is_dropped());
+    }
+}
@@ -0,0 +1,405 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::data::{Operation, TupleError};
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio::task::{block_in_place, JoinSet};
+use tokio_tungstenite::{accept_hdr_async, WebSocketStream};
+use tungstenite::handshake::server::Request;
+use tungstenite::Message;
+
+use super::cluster::{self, Cluster};
+use super::{
+    callback, deserialize, dispatch, interrupted, replication, write_value, Dispatch, Spaces,
+    Subscriptions, TupleSpace, Wait, CLUSTER_PATH, NOTIFY_INTERVAL, REPLICATION_PATH,
+};
+
+type Socket = WebSocketStream<TcpStream>;
+
+/// Serve the connections accepted by the listener as tasks of a tokio runtime, so a blocked request is a pending
+/// future instead of a blocked thread. The operations are executed by the same Tuple Spaces of the threaded server.
+/// Return when the server is shut down, or fail if the runtime cannot be started
+pub fn run(
+    listener: std::net::TcpListener,
+    spaces: Spaces,
+    cluster: Option<Arc<Cluster>>,
+    admin_token: Option<String>,
+    shutdown: Arc<AtomicBool>,
+) -> io::Result<()> {
+    let runtime = tokio::runtime::Runtime::new()?;
+
+    runtime.block_on(async move {
+        listener.set_nonblocking(true)?;
+        let listener = tokio::net::TcpListener::from_std(listener)?;
+
+        let mut connections = JoinSet::new();
+
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                // Forget the connections already closed
+                Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+            };
+
+            if shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+
+            let stream = match accepted {
+                Ok((stream, _)) => stream,
+                Err(_) => continue,
+            };
+
+            connections.spawn(serve(
+                spaces.clone(),
+                cluster.clone(),
+                admin_token.clone(),
+                Arc::clone(&shutdown),
+                stream,
+            ));
+        }
+
+        // Wake the blocked requests and wait for the connections to reply and close, before the runtime is dropped
+        block_in_place(|| spaces.close());
+        while connections.join_next().await.is_some() {}
+
+        Ok(())
+    })
+}
+
+async fn serve(
+    spaces: Spaces,
+    cluster: Option<Arc<Cluster>>,
+    admin_token: Option<String>,
+    shutdown: Arc<AtomicBool>,
+    stream: TcpStream,
+) {
+    let mut path = String::new();
+    #[allow(clippy::result_large_err)]
+    let handshake = accept_hdr_async(stream, |req: &Request, response| {
+        path = req.uri().path().to_string();
+        callback(&spaces, req, response)
+    })
+    .await;
+
+    let mut websocket = match handshake {
+        Ok(websocket) => websocket,
+        Err(_) => return,
+    };
+
+    if path == REPLICATION_PATH {
+        replication_stream(&spaces, &admin_token, &shutdown, websocket).await;
+        return;
+    }
+
+    if path == CLUSTER_PATH {
+        if let Some(cluster) = cluster {
+            cluster_stream(cluster, &admin_token, &shutdown, websocket).await;
+        }
+        return;
+    }
+
+    let mut space = match spaces.get(Spaces::space_name(&path)) {
+        Some(space) => space,
+        None => return,
+    };
+    let mut subscriptions = Subscriptions::new();
+
+    // Wake up periodically to push the notifications of the subscriptions
+    let mut ticker = tokio::time::interval(NOTIFY_INTERVAL);
+
+    while !shutdown.load(Ordering::SeqCst) {
+        let msg = tokio::select! {
+            msg = websocket.next() => msg,
+            _ = ticker.tick() => {
+                flush(&mut websocket, &mut subscriptions).await;
+                continue;
+            }
+        };
+
+        let mut replies: Vec<String> = vec![];
+        let leader = !spaces.is_backup();
+
+        let mut res = match msg {
+            Some(Ok(Message::Text(val))) => match deserialize(val) {
+                // The blocking request to cancel was already served, nothing to reply
+                Ok(Operation::Cancel) => continue,
+                Ok(operation) => {
+                    incoming_operations(
+                        &spaces,
+                        &admin_token,
+                        &mut space,
+                        &mut websocket,
+                        &mut replies,
+                        &mut subscriptions,
+                        operation,
+                    )
+                    .await
+                }
+                Err(error) => Err(error),
+            },
+            Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+            // Ping, pong and binary frames carry no operation
+            Some(Ok(_)) => continue,
+        };
+
+        if let (true, Some(cluster)) = (leader, &cluster) {
+            res = interrupted(&spaces, res);
+
+            // Same as the threaded server, waiting for the commit on a blocking thread
+            let cluster = Arc::clone(cluster);
+            if !matches!(res, Err(TupleError::NotPrimaryError))
+                && !tokio::task::spawn_blocking(move || cluster.barrier())
+                    .await
+                    .unwrap_or(false)
+            {
+                break;
+            }
+        }
+
+        let status = match res {
+            Ok(_) => TupleError::NoError,
+            Err(error) => error,
+        };
+
+        for reply in replies {
+            let _ = websocket.feed(Message::Text(reply)).await;
+        }
+        let _ = websocket
+            .send(Message::Text(serde_json::to_string(&status).unwrap()))
+            .await;
+
+        // Same as the threaded server, a busy client is notified after every reply
+        flush(&mut websocket, &mut subscriptions).await;
+    }
+
+    for id in subscriptions.ids.iter() {
+        space.unsubscribe(*id);
+    }
+}
+
+/// Same as the replication stream of the threaded server, with the records passed to the task by a bounded tokio
+/// channel
+async fn replication_stream(
+    spaces: &Spaces,
+    admin_token: &Option<String>,
+    shutdown: &AtomicBool,
+    mut socket: Socket,
+) {
+    let request = match socket.next().await {
+        Some(Ok(Message::Text(val))) => deserialize(val),
+        _ => return,
+    };
+
+    let (sender, mut receiver) = tokio::sync::mpsc::channel::<String>(replication::BACKLOG);
+    let replica = Box::new(move |line: &str| sender.try_send(line.to_string()).is_ok());
+
+    let snapshot = match replication::open(spaces, admin_token, request, replica) {
+        Ok(snapshot) => snapshot,
+        Err(error) => {
+            let _ = socket
+                .send(Message::Text(serde_json::to_string(&error).unwrap()))
+                .await;
+            return;
+        }
+    };
+
+    let _ = socket
+        .feed(Message::Text(
+            serde_json::to_string(&TupleError::NoError).unwrap(),
+        ))
+        .await;
+    if socket
+        .send(Message::Text(serde_json::to_string(&snapshot).unwrap()))
+        .await
+        .is_err()
+    {
+        return;
+    }
+
+    // Wake up periodically to check the shutdown
+    let mut ticker = tokio::time::interval(NOTIFY_INTERVAL);
+
+    while !shutdown.load(Ordering::SeqCst) {
+        let line = tokio::select! {
+            line = receiver.recv() => match line {
+                Some(line) => line,
+                None => break,
+            },
+            _ = ticker.tick() => continue,
+        };
+
+        if socket.send(Message::Text(line)).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Same as the connections of the other nodes of the threaded server, the requests are handled on blocking threads
+async fn cluster_stream(
+    cluster: Arc<Cluster>,
+    admin_token: &Option<String>,
+    shutdown: &AtomicBool,
+    mut socket: Socket,
+) {
+    let request = match socket.next().await {
+        Some(Ok(Message::Text(val))) => deserialize(val),
+        _ => return,
+    };
+
+    let status = match cluster::open(admin_token, request) {
+        Ok(_) => TupleError::NoError,
+        Err(error) => error,
+    };
+    let opened = matches!(status, TupleError::NoError);
+
+    if socket
+        .send(Message::Text(serde_json::to_string(&status).unwrap()))
+        .await
+        .is_err()
+        || !opened
+    {
+        return;
+    }
+
+    // Wake up periodically to check the shutdown
+    let mut ticker = tokio::time::interval(NOTIFY_INTERVAL);
+
+    while !shutdown.load(Ordering::SeqCst) {
+        let request = tokio::select! {
+            msg = socket.next() => match msg {
+                Some(Ok(Message::Text(val))) => match serde_json::from_str(&val) {
+                    Ok(request) => request,
+                    Err(_) => break,
+                },
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            },
+            _ = ticker.tick() => continue,
+        };
+
+        let cluster = Arc::clone(&cluster);
+        let reply = match tokio::task::spawn_blocking(move || cluster.handle(request)).await {
+            Ok(reply) => reply,
+            Err(_) => break,
+        };
+
+        if socket
+            .send(Message::Text(serde_json::to_string(&reply).unwrap()))
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+/// Push to the client the pending notifications of its subscriptions
+async fn flush(socket: &mut Socket, subscriptions: &mut Subscriptions) {
+    let mut pending: Vec<String> = vec![];
+    subscriptions.flush(&mut pending);
+
+    for notification in pending {
+        let _ = socket.feed(Message::Text(notification)).await;
+    }
+    let _ = socket.flush().await;
+}
+
+/// Same as the incoming_operations of the threaded server: the replies are collected and sent by the caller, while
+/// the socket is read only by a pending blocking request, looking for a Cancel of the client. The operations lock the
+/// spaces and write the log, so they run with block_in_place to let the other tasks move to another worker
+async fn incoming_operations(
+    spaces: &Spaces,
+    admin_token: &Option<String>,
+    space: &mut TupleSpace,
+    socket: &mut Socket,
+    replies: &mut Vec<String>,
+    subscriptions: &mut Subscriptions,
+    operation: Operation,
+) -> Result<(), TupleError> {
+    let dispatched = block_in_place(|| {
+        dispatch(
+            spaces,
+            admin_token,
+            space,
+            replies,
+            subscriptions,
+            operation,
+        )
+    });
+
+    match dispatched {
+        Dispatch::Done(res) => res,
+        Dispatch::Match(wait, reply) => {
+            let ret = wait_delivery(space, socket, subscriptions, wait, None).await?;
+            reply.write(replies, ret)
+        }
+        Dispatch::Empty(wait, timeout) => {
+            wait_delivery(space, socket, subscriptions, wait, timeout).await
+        }
+        Dispatch::Count(wait) => {
+            let ret = wait_delivery(space, socket, subscriptions, wait, None).await?;
+            write_value(replies, &ret)
+        }
+    }
+}
+
+/// Await the delivery of a queued blocking request, together with the messages of the client (looking for a Cancel,
+/// refusing the other operations), the timeout and, if the client has some subscriptions, the notifications to push
+/// every NOTIFY_INTERVAL. If the request is cancelled (or the connection is closed, or the timeout expires) before it
+/// is served it leaves the queue
+async fn wait_delivery<T>(
+    space: &mut TupleSpace,
+    socket: &mut Socket,
+    subscriptions: &mut Subscriptions,
+    wait: Wait<T>,
+    timeout: Option<Duration>,
+) -> Result<T, TupleError> {
+    let (ticket, mut receiver) = match wait {
+        Wait::Ready(res) => return res,
+        Wait::Queued(ticket, receiver) => (ticket, receiver),
+    };
+    let expired = async {
+        match timeout {
+            Some(timeout) => tokio::time::sleep(timeout).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(expired);
+    let mut ticker = tokio::time::interval(NOTIFY_INTERVAL);
+
+    let error = loop {
+        tokio::select! {
+            // The queues are emptied without serving the requests only when the space is dropped
+            res = &mut receiver => return res.map_err(|_| TupleError::SpaceNotFoundError).and_then(|res| res),
+            msg = socket.next() => match msg {
+                // Other operations are refused while a blocking request is pending, the client is waiting for its reply
+                Some(Ok(Message::Text(val))) => match deserialize(val) {
+                    Ok(Operation::Cancel) => break TupleError::CancelledError,
+                    _ => {
+                        let refused = serde_json::to_string(&TupleError::Error).unwrap();
+                        let _ = socket.send(Message::Text(refused)).await;
+                    }
+                },
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break TupleError::Error,
+                // Ping, pong and binary frames carry no operation
+                Some(Ok(_)) => {}
+            },
+            _ = &mut expired => break TupleError::TimeoutError,
+            _ = ticker.tick(), if !subscriptions.ids.is_empty() => flush(socket, subscriptions).await,
+        }
+    };
+
+    if block_in_place(|| space.leave(&ticket)) {
+        return Err(error);
+    }
+
+    // The request was served in the meantime, so it is not cancelled
+    receiver
+        .await
+        .map_err(|_| TupleError::SpaceNotFoundError)
+        .and_then(|res| res)
+}
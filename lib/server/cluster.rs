@@ -0,0 +1,903 @@
+use std::collections::hash_map::RandomState;
+use std::fs::{self, File};
+use std::hash::BuildHasher;
+use std::io::{self, ErrorKind, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{Message, WebSocket};
+
+use super::snapshot::{Horizon, Snapshot};
+use super::wal::{self, Record, Wal};
+use super::{deserialize, Spaces, CLUSTER_PATH, NOTIFY_INTERVAL};
+use crate::data::{AdminOperation, Operation, TupleError};
+
+/// Interval between the requests of the leader to every other node, when it has nothing new to send
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long a node waits for the leader before starting an election, randomized up to twice as much so that the
+/// nodes do not start it together
+const ELECTION_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// How long a node waits for the reply of another one
+const RPC_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How long a node waits before connecting again to another one
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long the reply of an operation waits for its changes to be committed
+const COMMIT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Number of records kept in memory to bring up to date the other nodes, a node further behind gets a snapshot
+pub const TAIL: usize = 4096;
+
+/// Maximum number of records sent together
+const BATCH: usize = 256;
+
+const WAL_ERROR: &str = "Error writing the write-ahead log";
+
+/// Request of a node to another one
+#[derive(Serialize, Deserialize)]
+pub enum Request {
+    /// A candidate asks for the vote, its log ends before next with a record of last_term
+    Vote {
+        term: u64,
+        candidate: u64,
+        next: u64,
+        last_term: u64,
+    },
+
+    /// The leader sends the records starting from the given sequence number, which follow a record of prev_term.
+    /// The records before commit are committed
+    Append {
+        term: u64,
+        from: u64,
+        prev_term: u64,
+        records: Vec<String>,
+        commit: u64,
+    },
+
+    /// The leader replaces the spaces of a node whose log differs from its own or is too far behind
+    Install { term: u64, snapshot: Snapshot },
+}
+
+impl Request {
+    fn term(&self) -> u64 {
+        match self {
+            Request::Vote { term, .. }
+            | Request::Append { term, .. }
+            | Request::Install { term, .. } => *term,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum Reply {
+    Vote { term: u64, granted: bool },
+    Append { term: u64, result: Appended },
+}
+
+/// Outcome of an Append (or Install) request
+#[derive(Serialize, Deserialize)]
+pub enum Appended {
+    /// The log of the node is the same as the one of the leader before the given sequence number
+    Matched(u64),
+
+    /// The log of the node ends before the given sequence number
+    Missing(u64),
+
+    /// The log of the node has records that are not in the one of the leader: since they are already applied to the
+    /// spaces, the node needs a snapshot
+    Diverged,
+}
+
+/// Id, term and vote of the node, which must survive a restart so that it never votes twice in the same term (also
+/// for itself, under another id)
+#[derive(Serialize, Deserialize)]
+struct Ballot {
+    #[serde(default = "random")]
+    id: u64,
+    term: u64,
+    vote: Option<u64>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// Another node, as seen by the leader (or by a candidate)
+struct Peer {
+    /// Sequence number of the next record to send
+    next: u64,
+
+    /// The log of the node is the same as the one of the leader before this sequence number
+    matched: u64,
+
+    /// When the last request accepted by the node in the current term was sent
+    acked: Option<Instant>,
+
+    /// When the last request was sent
+    sent: Option<Instant>,
+
+    /// Last term the node was asked for the vote
+    asked: u64,
+
+    /// Set when the node needs a snapshot
+    install: bool,
+}
+
+impl Peer {
+    fn new(next: u64) -> Self {
+        Peer {
+            next,
+            matched: 0,
+            acked: None,
+            sent: None,
+            asked: 0,
+            install: false,
+        }
+    }
+}
+
+struct State {
+    term: u64,
+    vote: Option<u64>,
+    role: Role,
+
+    /// The records before this sequence number are committed
+    commit: u64,
+
+    /// Votes received as a candidate in the current term
+    votes: usize,
+
+    /// When the node starts an election, if it does not hear from a leader before
+    deadline: Instant,
+
+    /// Start of the latest reply waiting for a confirmation of the leadership, the leader asks it to the other nodes
+    /// without waiting for the next heartbeat
+    demand: Option<Instant>,
+    peers: Vec<Peer>,
+
+    /// Records already contained in the last snapshot installed, which are not applied again
+    horizon: Horizon,
+}
+
+/// A node of a cluster replicating the log of the changes with a Raft-style consensus: the nodes elect a leader,
+/// which is the only one serving the clients, and the leader replies to an operation only after its changes are in
+/// the log of a majority of the nodes. Every node applies the records to its spaces as soon as they are in its log, a
+/// node whose log turns out to differ from the one of the leader gets a snapshot of the spaces of the leader
+pub struct Cluster {
+    id: u64,
+
+    /// Addresses of the other nodes
+    peers: Vec<String>,
+    admin_token: String,
+    spaces: Spaces,
+    wal: Arc<Wal>,
+
+    /// Files of the ballot and of the snapshot, next to the write-ahead log
+    ballot_path: PathBuf,
+    snapshot_path: PathBuf,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl Cluster {
+    /// Join the cluster of the other nodes at the given addresses, as a follower. The spaces are already rebuilt from
+    /// the log, the records in the horizon are in the snapshot they were rebuilt from. Fail if the ballot of the node
+    /// cannot be read or written
+    pub fn new(
+        peers: Vec<String>,
+        admin_token: String,
+        spaces: Spaces,
+        wal: Arc<Wal>,
+        wal_path: &Path,
+        horizon: Horizon,
+    ) -> io::Result<Arc<Self>> {
+        let ballot_path = Cluster::ballot_path(wal_path);
+        let ballot = Cluster::load(&ballot_path)?;
+
+        let cluster = Cluster {
+            id: ballot.id,
+            peers: peers.clone(),
+            admin_token,
+            spaces,
+            wal,
+            ballot_path,
+            snapshot_path: Snapshot::path(wal_path),
+            state: Mutex::new(State {
+                term: ballot.term,
+                vote: ballot.vote,
+                role: Role::Follower,
+                commit: 0,
+                votes: 0,
+                deadline: election_deadline(),
+                demand: None,
+                peers: peers.iter().map(|_| Peer::new(0)).collect(),
+                horizon,
+            }),
+            changed: Condvar::new(),
+        };
+
+        // The id of a new node is written right away
+        cluster.save(&cluster.state.lock().unwrap())?;
+        Ok(Arc::new(cluster))
+    }
+
+    /// Start the election timer and the connections to the other nodes
+    pub fn start(self: &Arc<Self>, shutdown: &Arc<AtomicBool>) {
+        let cluster = Arc::clone(self);
+        let stop = Arc::clone(shutdown);
+        spawn(move || cluster.elect(&stop));
+
+        for idx in 0..self.peers.len() {
+            let cluster = Arc::clone(self);
+            let stop = Arc::clone(shutdown);
+            spawn(move || cluster.follow(idx, &stop));
+        }
+    }
+
+    /// Path of the ballot of the given log
+    fn ballot_path(wal: &Path) -> PathBuf {
+        let mut path = wal.to_path_buf().into_os_string();
+        path.push(".ballot");
+
+        path.into()
+    }
+
+    /// Read the ballot of the node, a new node gets a random id
+    fn load(path: &Path) -> io::Result<Ballot> {
+        match fs::read(path) {
+            Ok(content) => serde_json::from_slice(&content)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Ballot {
+                id: random(),
+                term: 0,
+                vote: None,
+            }),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Persist the term and the vote, before they are sent to another node
+    fn save(&self, state: &State) -> io::Result<()> {
+        let path = &self.ballot_path;
+        let ballot = Ballot {
+            id: self.id,
+            term: state.term,
+            vote: state.vote,
+        };
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+
+        let mut file = File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec(&ballot).unwrap())?;
+        file.sync_all()?;
+        wal::replace(&tmp, path)
+    }
+
+    /// Persist the term and the vote, stepping down if they cannot be: the node does not ask for or grant a vote it
+    /// could forget. Return true if they are persisted
+    fn persist(&self, state: &mut State) -> bool {
+        match self.save(state) {
+            Ok(()) => true,
+            Err(e) => {
+                eprintln!("Error writing the ballot: {}", e);
+                self.step_down(state, state.term);
+                false
+            }
+        }
+    }
+
+    fn majority(&self) -> usize {
+        let nodes = self.peers.len() + 1;
+
+        nodes / 2 + 1
+    }
+
+    /// Wait until the changes made by an operation (and all the ones before) are committed and a majority of the
+    /// nodes confirmed the leadership after it, so that its reply reflects all the operations replied before, on
+    /// any node. Return false if the server is not the leader anymore or the commit takes too long
+    pub fn barrier(&self) -> bool {
+        let start = Instant::now();
+        let next = self.wal.next_lsn();
+
+        // The leader counts in the majority too, so its own records are synced whatever the sync policy. If they
+        // cannot be, another node has to lead
+        let synced = self.wal.sync();
+        let mut state = self.state.lock().unwrap();
+
+        if let Err(e) = synced {
+            eprintln!("{}: {}", WAL_ERROR, e);
+            let term = state.term;
+            self.step_down(&mut state, term);
+            return false;
+        }
+
+        state.demand = state.demand.max(Some(start));
+        self.changed.notify_all();
+
+        loop {
+            if state.role != Role::Leader {
+                return false;
+            }
+
+            self.advance(&mut state);
+            if state.commit >= next && self.confirmed(&state) >= Some(start) {
+                return true;
+            }
+
+            let waited = start.elapsed();
+            if waited >= COMMIT_TIMEOUT {
+                return false;
+            }
+
+            state = self
+                .changed
+                .wait_timeout(state, (COMMIT_TIMEOUT - waited).min(HEARTBEAT_INTERVAL))
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// Commit the records of the current term that are in the log of a majority of the nodes, together with all the
+    /// ones before them
+    fn advance(&self, state: &mut State) {
+        let mut matched = state
+            .peers
+            .iter()
+            .map(|peer| peer.matched)
+            .collect::<Vec<u64>>();
+        matched.push(self.wal.next_lsn());
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+
+        let commit = matched[self.majority() - 1];
+        if commit > state.commit && self.wal.term(commit - 1) == Some(state.term) {
+            state.commit = commit;
+        }
+    }
+
+    /// Latest time a majority of the nodes (including this one) accepted the leadership
+    fn confirmed(&self, state: &State) -> Option<Instant> {
+        let mut acked = state
+            .peers
+            .iter()
+            .map(|peer| peer.acked)
+            .collect::<Vec<Option<Instant>>>();
+        acked.push(Some(Instant::now()));
+        acked.sort_unstable_by(|a, b| b.cmp(a));
+
+        acked[self.majority() - 1]
+    }
+
+    /// Start an election whenever the leader is not heard for too long, until the server is shut down
+    fn elect(&self, shutdown: &AtomicBool) {
+        let mut state = self.state.lock().unwrap();
+
+        while !shutdown.load(Ordering::SeqCst) {
+            let now = Instant::now();
+
+            if state.role != Role::Leader && now >= state.deadline {
+                state.term += 1;
+                state.role = Role::Candidate;
+                state.vote = Some(self.id);
+                state.votes = 1;
+                state.deadline = election_deadline();
+                if !self.persist(&mut state) {
+                    continue;
+                }
+                eprintln!("Starting an election for the term {}", state.term);
+
+                if state.votes >= self.majority() {
+                    self.lead(&mut state);
+                }
+                self.changed.notify_all();
+            }
+
+            let wait = state.deadline.saturating_duration_since(now);
+            state = self
+                .changed
+                .wait_timeout(state, wait.min(NOTIFY_INTERVAL))
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// Become the leader of the current term: the records of the previous terms are committed together with the
+    /// first one of the new term, written right away. If it cannot be the node steps down
+    fn lead(&self, state: &mut State) {
+        let next = self.wal.next_lsn();
+
+        self.wal.set_term(state.term);
+        if let Err(e) = self.wal.append("", vec![]) {
+            eprintln!("{}: {}", WAL_ERROR, e);
+            self.step_down(state, state.term);
+            return;
+        }
+
+        state.role = Role::Leader;
+        state.demand = None;
+        for peer in state.peers.iter_mut() {
+            *peer = Peer::new(next);
+        }
+        self.spaces.backup.store(false, Ordering::SeqCst);
+        eprintln!("Elected leader of the cluster for the term {}", state.term);
+    }
+
+    /// Follow the leader of the given term (or wait for one to be elected)
+    fn step_down(&self, state: &mut State, term: u64) {
+        // If the new term cannot be persisted the node has not voted in it yet, a vote is written together with it
+        if term > state.term {
+            state.term = term;
+            state.vote = None;
+
+            if let Err(e) = self.save(state) {
+                eprintln!("Error writing the ballot: {}", e);
+            }
+        }
+
+        self.spaces.backup.store(true, Ordering::SeqCst);
+        if state.role == Role::Leader {
+            eprintln!("Not the leader of the cluster anymore");
+
+            // The blocked requests fail, so that their clients look for the new leader
+            self.spaces.interrupt();
+        }
+
+        state.role = Role::Follower;
+        state.deadline = election_deadline();
+    }
+
+    /// Send the requests of this node to another one, until the server is shut down
+    fn follow(&self, idx: usize, shutdown: &AtomicBool) {
+        let url = format!("ws://{}{}", self.peers[idx], CLUSTER_PATH);
+        let mut socket = None;
+
+        while !shutdown.load(Ordering::SeqCst) {
+            if socket.is_none() {
+                socket = connect(&url, &self.admin_token).ok();
+
+                if socket.is_none() {
+                    sleep(RETRY_INTERVAL);
+                    continue;
+                }
+            }
+
+            let (request, sent) = match self.request(idx) {
+                Some(request) => request,
+                None => continue,
+            };
+            let term = request.term();
+
+            match exchange(socket.as_mut().unwrap(), &request) {
+                Ok(reply) => self.receive(idx, term, sent, reply),
+                Err(_) => socket = None,
+            }
+        }
+    }
+
+    /// Wait for the next request to send to the node, return None if there is none within a heartbeat
+    fn request(&self, idx: usize) -> Option<(Request, Instant)> {
+        let deadline = Instant::now() + HEARTBEAT_INTERVAL;
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            let now = Instant::now();
+            let term = state.term;
+            let commit = state.commit;
+            let demand = state.demand;
+            let role = state.role;
+            let peer = &mut state.peers[idx];
+
+            if role == Role::Candidate && peer.asked < term {
+                peer.asked = term;
+                let (next, last_term) = self.wal.last();
+
+                let request = Request::Vote {
+                    term,
+                    candidate: self.id,
+                    next,
+                    last_term,
+                };
+                return Some((request, now));
+            }
+
+            let due = match peer.sent {
+                Some(sent) => now >= sent + HEARTBEAT_INTERVAL || demand > Some(sent),
+                None => true,
+            };
+
+            if role == Role::Leader && (due || peer.install || peer.next < self.wal.next_lsn()) {
+                peer.sent = Some(now);
+
+                let prev_term = match peer.next {
+                    0 => Some(0),
+                    next => self.wal.term(next - 1),
+                };
+
+                if let (false, Some(prev_term), Some(records)) =
+                    (peer.install, prev_term, self.wal.lines(peer.next, BATCH))
+                {
+                    let request = Request::Append {
+                        term,
+                        from: peer.next,
+                        prev_term,
+                        records,
+                        commit,
+                    };
+                    return Some((request, now));
+                }
+
+                // The snapshot is taken without blocking the other nodes, and again later if it cannot be
+                drop(state);
+                return self.install(term).map(|request| (request, now));
+            }
+
+            if now >= deadline {
+                return None;
+            }
+
+            state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Snapshot of the spaces for a node whose log differs from the one of the leader or is too far behind
+    fn install(&self, term: u64) -> Option<Request> {
+        let (mut snapshot, last_term) = self.spaces.capture(&self.wal, || self.wal.last()).ok()?;
+        snapshot.term = last_term;
+
+        Some(Request::Install { term, snapshot })
+    }
+
+    /// Handle the reply of a node to a request of the given term, sent at the given time
+    fn receive(&self, idx: usize, term: u64, sent: Instant, reply: Reply) {
+        let mut state = self.state.lock().unwrap();
+
+        let reply_term = match reply {
+            Reply::Vote { term, .. } | Reply::Append { term, .. } => term,
+        };
+
+        if reply_term > state.term {
+            self.step_down(&mut state, reply_term);
+            return;
+        }
+
+        // A reply to a request of a previous term
+        if term != state.term {
+            return;
+        }
+
+        match reply {
+            Reply::Vote { granted: true, .. } if state.role == Role::Candidate => {
+                state.votes += 1;
+
+                if state.votes >= self.majority() {
+                    self.lead(&mut state);
+                }
+            }
+            Reply::Append { result, .. } if state.role == Role::Leader => {
+                let peer = &mut state.peers[idx];
+                peer.acked = peer.acked.max(Some(sent));
+
+                match result {
+                    Appended::Matched(next) => {
+                        peer.matched = peer.matched.max(next);
+                        peer.next = next;
+                        peer.install = false;
+                    }
+                    Appended::Missing(next) => peer.next = next,
+                    Appended::Diverged => peer.install = true,
+                }
+
+                self.advance(&mut state);
+            }
+            _ => (),
+        }
+
+        self.changed.notify_all();
+    }
+
+    /// Handle a request of another node
+    pub fn handle(&self, request: Request) -> Reply {
+        let mut state = self.state.lock().unwrap();
+
+        if request.term() > state.term {
+            self.step_down(&mut state, request.term());
+        }
+
+        let reply = match request {
+            Request::Vote {
+                term,
+                candidate,
+                next,
+                last_term,
+            } => {
+                let (our_next, our_last_term) = self.wal.last();
+                let mut granted = term == state.term
+                    && state.vote.is_none_or(|vote| vote == candidate)
+                    && (last_term, next) >= (our_last_term, our_next);
+
+                if granted {
+                    state.vote = Some(candidate);
+                    state.deadline = election_deadline();
+                    granted = self.persist(&mut state);
+                }
+
+                Reply::Vote {
+                    term: state.term,
+                    granted,
+                }
+            }
+            Request::Append { term, .. } | Request::Install { term, .. } if term < state.term => {
+                Reply::Append {
+                    term: state.term,
+                    result: Appended::Missing(self.wal.next_lsn()),
+                }
+            }
+            Request::Append {
+                from,
+                prev_term,
+                records,
+                commit,
+                ..
+            } => {
+                self.heard(&mut state);
+
+                Reply::Append {
+                    term: state.term,
+                    result: self.append(&mut state, from, prev_term, records, commit),
+                }
+            }
+            Request::Install { snapshot, .. } => {
+                self.heard(&mut state);
+                let next = snapshot.lsn;
+                let result = match self.restore(&mut state, snapshot) {
+                    Ok(()) => Appended::Matched(next),
+                    // The leader sends a snapshot again
+                    Err(_) => Appended::Diverged,
+                };
+
+                Reply::Append {
+                    term: state.term,
+                    result,
+                }
+            }
+        };
+
+        self.changed.notify_all();
+        reply
+    }
+
+    /// A request of the leader of the current term is received
+    fn heard(&self, state: &mut State) {
+        if state.role != Role::Follower {
+            self.step_down(state, state.term);
+        }
+
+        state.deadline = election_deadline();
+    }
+
+    /// Add the records of the leader to the log and apply them to the spaces
+    fn append(
+        &self,
+        state: &mut State,
+        from: u64,
+        prev_term: u64,
+        records: Vec<String>,
+        commit: u64,
+    ) -> Appended {
+        let next = self.wal.next_lsn();
+        if from > next {
+            return Appended::Missing(next);
+        }
+
+        // The records before the commit are the same on all the nodes, the other ones are compared by term
+        if from > state.commit && from > 0 && self.wal.term(from - 1) != Some(prev_term) {
+            return Appended::Diverged;
+        }
+
+        let end = from + records.len() as u64;
+
+        for (lsn, line) in (from..).zip(records) {
+            let record: Record = match serde_json::from_str(&line) {
+                Ok(record) => record,
+                Err(_) => return Appended::Diverged,
+            };
+
+            if lsn < self.wal.next_lsn() {
+                if lsn < state.commit || self.wal.term(lsn) == Some(record.term) {
+                    continue;
+                }
+
+                return Appended::Diverged;
+            }
+
+            if record.lsn != lsn {
+                return Appended::Diverged;
+            }
+
+            match self.wal.write(&record, line) {
+                Ok(_) => (),
+                // A record written by an operation still running when the node stopped leading
+                Err(e) if e.kind() == ErrorKind::InvalidInput => return Appended::Diverged,
+                // The leader sends the record again
+                Err(e) => {
+                    eprintln!("{}: {}", WAL_ERROR, e);
+                    return Appended::Missing(lsn);
+                }
+            }
+            // The spaces no longer follow the log, they are replaced with a snapshot of the leader
+            if !state.horizon.contains(&record) && self.spaces.apply(record).is_err() {
+                return Appended::Diverged;
+            }
+        }
+
+        // The leader counts the records as in the log of the node, so they must survive a crash of the machine. If
+        // they cannot be synced the leader sends them again
+        if let Err(e) = self.wal.sync() {
+            eprintln!("{}: {}", WAL_ERROR, e);
+            return Appended::Missing(from);
+        }
+
+        state.commit = state.commit.max(commit.min(end));
+        Appended::Matched(end)
+    }
+
+    /// Replace the spaces (and the log) with a snapshot of the leader
+    fn restore(&self, state: &mut State, snapshot: Snapshot) -> Result<(), TupleError> {
+        // The snapshot is written first, so that the records it contains are never lost: after a crash in the middle
+        // the node restarts from it with the records of its old log after it, which the leader finds either the same
+        // as its own or different (and replaces with a snapshot again)
+        let saved = snapshot
+            .save(&self.snapshot_path)
+            .and_then(|_| self.wal.reset(snapshot.lsn, snapshot.term));
+        if let Err(e) = saved {
+            eprintln!("Error installing the snapshot: {}", e);
+            return Err(TupleError::Error);
+        }
+
+        state.horizon = snapshot.horizon();
+        self.spaces.restore(snapshot)
+    }
+
+    /// Write a snapshot of the spaces and cut the records of the log before it. The state is locked meanwhile, since
+    /// a follower writes the records of the leader in its log before applying them to the spaces
+    pub fn snapshot(&self) -> io::Result<()> {
+        let _state = self.state.lock().unwrap();
+
+        self.spaces.snapshot(&self.wal, &self.snapshot_path)
+    }
+}
+
+/// Random number, used for the node ids and the election timeouts
+fn random() -> u64 {
+    RandomState::new().hash_one(Instant::now())
+}
+
+fn election_deadline() -> Instant {
+    let timeout = ELECTION_TIMEOUT.as_millis() as u64;
+
+    Instant::now() + Duration::from_millis(timeout + random() % timeout)
+}
+
+type Socket = WebSocket<MaybeTlsStream<TcpStream>>;
+
+/// Open a connection to another node, authenticated by the admin token
+fn connect(url: &str, admin_token: &str) -> Result<Socket, TupleError> {
+    let (mut socket, _) = tungstenite::connect(url).map_err(|_| TupleError::Error)?;
+
+    if let MaybeTlsStream::Plain(stream) = socket.get_ref() {
+        let _ = stream.set_read_timeout(Some(RPC_TIMEOUT));
+    }
+
+    let request = Operation::Admin(admin_token.to_string(), AdminOperation::Replicate);
+    socket
+        .send(Message::Text(serde_json::to_string(&request).unwrap()))
+        .map_err(|_| TupleError::Error)?;
+
+    match serde_json::from_str(&read(&mut socket)?) {
+        Ok(TupleError::NoError) => Ok(socket),
+        Ok(error) => Err(error),
+        Err(_) => Err(TupleError::Error),
+    }
+}
+
+/// Send a request to another node and wait for its reply
+fn exchange(socket: &mut Socket, request: &Request) -> Result<Reply, TupleError> {
+    socket
+        .send(Message::Text(serde_json::to_string(request).unwrap()))
+        .map_err(|_| TupleError::Error)?;
+
+    serde_json::from_str(&read(socket)?).map_err(|_| TupleError::Error)
+}
+
+fn read(socket: &mut Socket) -> Result<String, TupleError> {
+    loop {
+        match socket.read() {
+            Ok(Message::Text(text)) => return Ok(text),
+            Ok(Message::Close(_)) | Err(_) => return Err(TupleError::Error),
+            Ok(_) => (),
+        }
+    }
+}
+
+/// Check the request opening a connection of another node, which is authenticated by the admin token
+pub fn open(
+    admin_token: &Option<String>,
+    request: Result<Operation, TupleError>,
+) -> Result<(), TupleError> {
+    match request? {
+        Operation::Admin(token, AdminOperation::Replicate)
+            if admin_token.as_ref() == Some(&token) =>
+        {
+            Ok(())
+        }
+        Operation::Admin(_, AdminOperation::Replicate) => Err(TupleError::UnauthorizedError),
+        _ => Err(TupleError::Error),
+    }
+}
+
+/// Serve the connection opened by another node: reply to its requests until it closes the connection or the server
+/// shuts down
+pub fn serve(
+    cluster: &Cluster,
+    admin_token: &Option<String>,
+    shutdown: &AtomicBool,
+    socket: &mut WebSocket<TcpStream>,
+) {
+    let request = match socket.read() {
+        Ok(Message::Text(val)) => deserialize(val),
+        _ => return,
+    };
+
+    let status = match open(admin_token, request) {
+        Ok(_) => TupleError::NoError,
+        Err(error) => error,
+    };
+    let opened = matches!(status, TupleError::NoError);
+
+    if socket
+        .send(Message::Text(serde_json::to_string(&status).unwrap()))
+        .is_err()
+        || !opened
+    {
+        return;
+    }
+
+    // Wake up periodically to check the shutdown
+    let _ = socket.get_ref().set_read_timeout(Some(NOTIFY_INTERVAL));
+
+    while !shutdown.load(Ordering::SeqCst) {
+        let request = match socket.read() {
+            Ok(Message::Text(val)) => match serde_json::from_str(&val) {
+                Ok(request) => request,
+                Err(_) => break,
+            },
+            Ok(Message::Close(_)) => break,
+            Ok(_) => continue,
+            Err(tungstenite::Error::Io(e))
+                if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut =>
+            {
+                continue
+            }
+            Err(_) => break,
+        };
+
+        let reply = cluster.handle(request);
+        if socket
+            .send(Message::Text(serde_json::to_string(&reply).unwrap()))
+            .is_err()
+        {
+            break;
+        }
+    }
+}
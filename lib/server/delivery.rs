@@ -0,0 +1,228 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::mpsc::RecvError;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Instant;
+
+/// State shared by the two ends of a delivery channel
+struct State<T> {
+    value: Option<T>,
+
+    /// Number of live senders, the receiver is disconnected when it drops to zero without a value
+    senders: usize,
+    receiver: bool,
+
+    /// Set by an Interrupter, the thread waiting in recv_until wakes up without a value
+    interrupted: bool,
+
+    /// Task waiting for the value, if the receiver is awaited
+    waker: Option<Waker>,
+}
+
+struct Inner<T> {
+    state: Mutex<State<T>>,
+    ready: Condvar,
+}
+
+/// Channel delivering the result of a blocked request, at most once: the receiver can be waited on by a thread (like
+/// a std::sync::mpsc receiver) or awaited by an async task
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let inner = Arc::new(Inner {
+        state: Mutex::new(State {
+            value: None,
+            senders: 1,
+            receiver: true,
+            interrupted: false,
+            waker: None,
+        }),
+        ready: Condvar::new(),
+    });
+
+    (
+        Sender {
+            inner: Arc::clone(&inner),
+        },
+        Receiver { inner },
+    )
+}
+
+pub struct Sender<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Deliver the value, returning it back if the receiver is dropped or a value was already delivered
+    pub fn send(&self, value: T) -> Result<(), T> {
+        let mut state = self.inner.state.lock().unwrap();
+
+        if !state.receiver || state.value.is_some() {
+            return Err(value);
+        }
+
+        state.value = Some(value);
+        self.inner.ready.notify_all();
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+
+        Ok(())
+    }
+}
+
+impl<T> Sender<T> {
+    /// False if the receiver is dropped, so a value sent would be lost
+    pub fn is_connected(&self) -> bool {
+        self.inner.state.lock().unwrap().receiver
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.inner.state.lock().unwrap().senders += 1;
+
+        Sender {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.inner.state.lock().unwrap();
+
+        state.senders -= 1;
+        if state.senders == 0 {
+            self.inner.ready.notify_all();
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// Why recv_until returned without a value
+#[derive(Debug, PartialEq, Eq)]
+pub enum Wakeup {
+    Interrupted,
+    Timeout,
+    Disconnected,
+}
+
+/// Wakes the thread waiting on a receiver in recv_until, e.g. when the client of the blocked request sends a message.
+/// It does not depend on the type of the value, so that the same thread can interrupt the waits of any request
+#[derive(Clone)]
+pub struct Interrupter {
+    inner: Arc<dyn Interrupt + Send + Sync>,
+}
+
+impl Interrupter {
+    pub fn interrupt(&self) {
+        self.inner.interrupt();
+    }
+}
+
+trait Interrupt {
+    fn interrupt(&self);
+}
+
+impl<T> Interrupt for Inner<T> {
+    fn interrupt(&self) {
+        self.state.lock().unwrap().interrupted = true;
+        self.ready.notify_all();
+    }
+}
+
+pub struct Receiver<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T: Send + 'static> Receiver<T> {
+    pub fn interrupter(&self) -> Interrupter {
+        Interrupter {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Block until the value is delivered, all the senders are dropped, the deadline (if any) expires or an
+    /// Interrupter is called (also if it was called since the last wakeup)
+    pub fn recv_until(&self, deadline: Option<Instant>) -> Result<T, Wakeup> {
+        let mut state = self.inner.state.lock().unwrap();
+
+        loop {
+            if let Some(value) = state.value.take() {
+                return Ok(value);
+            }
+
+            if state.senders == 0 {
+                return Err(Wakeup::Disconnected);
+            }
+
+            if state.interrupted {
+                state.interrupted = false;
+                return Err(Wakeup::Interrupted);
+            }
+
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(Wakeup::Timeout);
+                    }
+
+                    self.inner
+                        .ready
+                        .wait_timeout(state, deadline - now)
+                        .unwrap()
+                        .0
+                }
+                None => self.inner.ready.wait(state).unwrap(),
+            };
+        }
+    }
+
+    /// Block until the value is delivered, or all the senders are dropped
+    pub fn recv(&self) -> Result<T, RecvError> {
+        let mut state = self.inner.state.lock().unwrap();
+
+        loop {
+            if let Some(value) = state.value.take() {
+                return Ok(value);
+            }
+
+            if state.senders == 0 {
+                return Err(RecvError);
+            }
+
+            state = self.inner.ready.wait(state).unwrap();
+        }
+    }
+}
+
+/// Awaiting the receiver (by reference, so that it can be polled again) resolves like recv
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.inner.state.lock().unwrap();
+
+        if let Some(value) = state.value.take() {
+            return Poll::Ready(Ok(value));
+        }
+
+        if state.senders == 0 {
+            return Poll::Ready(Err(RecvError));
+        }
+
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.inner.state.lock().unwrap().receiver = false;
+    }
+}
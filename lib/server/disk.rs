@@ -0,0 +1,363 @@
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use super::store::{Backend, Entry, Lease, Storage};
+use crate::data::{Field, Tuple};
+
+/// Prefix of the names of the trees written by the backend
+const TREE_PREFIX: &str = "rustuple/";
+
+/// Kinds of the keys of a tree, which start with one of them followed by big endian numbers: an entry is stored
+/// under its sequence number, and indexed by keys ending with it
+const ENTRY: u8 = 0;
+const BY_ARITY: u8 = 1;
+const BY_VALUE: u8 = 2;
+const BY_HASH: u8 = 3;
+const LEASE: u8 = 4;
+const EXPIRING: u8 = 5;
+
+/// Backend keeping the tuples on disk, in an embedded sled database, together with their indexes: memory only holds
+/// the cache of the database, so it is meant for large spaces that do not fit in memory. The database only holds the
+/// tuples while the server runs, the spaces survive a restart only with the write-ahead log
+pub struct DiskBackend {
+    db: sled::Db,
+
+    /// Origin of the expirations of the leases written on disk
+    origin: Instant,
+}
+
+impl DiskBackend {
+    /// Open (or create) the database in the given directory, dropping the trees left by a previous run of the
+    /// server. Fail with InvalidData if the database contains data not written by the backend, which is left as is
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let db = sled::open(path)?;
+
+        let foreign = db
+            .tree_names()
+            .iter()
+            .any(|name| *name != db.name() && !name.starts_with(TREE_PREFIX.as_bytes()));
+        if foreign || !db.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "The database was not written by the disk backend",
+            ));
+        }
+
+        // The default tree cannot be dropped, and it is never used
+        for name in db.tree_names() {
+            if name != db.name() {
+                db.drop_tree(name)?;
+            }
+        }
+
+        Ok(DiskBackend {
+            db,
+            origin: Instant::now(),
+        })
+    }
+}
+
+impl Backend for DiskBackend {
+    fn storage(&self, space: &str, index_field: usize) -> io::Result<Box<dyn Storage>> {
+        // The tree of a dropped space lives until its last connection is closed, so a space created again with the
+        // same name needs another one
+        let id = self.db.generate_id()?;
+        let tree = self
+            .db
+            .open_tree(format!("{}{}/{}", TREE_PREFIX, space, id))?;
+
+        Ok(Box::new(DiskStorage {
+            db: self.db.clone(),
+            tree,
+            field: index_field,
+            origin: self.origin,
+        }))
+    }
+}
+
+/// An entry as written on disk, keyed by its sequence number
+#[derive(Serialize, Deserialize)]
+struct Record {
+    tuple: Tuple,
+    priority: i32,
+
+    /// Lease id and expiration, as the time elapsed from the origin of the backend
+    lease: Option<(u64, Duration)>,
+}
+
+/// Storage of a shard on a tree of the database, holding the entries and their indexes (the same ones as the memory
+/// storage, with the values and the tuples indexed by their hash)
+struct DiskStorage {
+    db: sled::Db,
+    tree: sled::Tree,
+
+    /// Position of the indexed field
+    field: usize,
+    origin: Instant,
+}
+
+/// Key of the given kind, followed by the numbers
+fn key(kind: u8, numbers: &[u64]) -> Vec<u8> {
+    let mut key = vec![kind];
+
+    for number in numbers {
+        key.extend_from_slice(&number.to_be_bytes());
+    }
+
+    key
+}
+
+/// Number at the end of a key (or a value), the sequence number of an index key
+fn last(bytes: &[u8]) -> io::Result<u64> {
+    bytes
+        .len()
+        .checked_sub(8)
+        .and_then(|start| bytes[start..].try_into().ok())
+        .map(u64::from_be_bytes)
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "Malformed key of the disk storage"))
+}
+
+fn hash<'a, T: Hash + 'a>(values: impl IntoIterator<Item = &'a T>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+
+    for value in values {
+        value.hash(&mut hasher);
+    }
+
+    hasher.finish()
+}
+
+impl DiskStorage {
+    fn encode(&self, entry: &Entry) -> Record {
+        Record {
+            tuple: entry.tuple.clone(),
+            priority: entry.priority,
+            lease: entry.lease.map(|lease| {
+                (
+                    lease.id,
+                    lease.expires.saturating_duration_since(self.origin),
+                )
+            }),
+        }
+    }
+
+    fn decode(&self, seq: u64, record: Record) -> Entry {
+        Entry {
+            seq,
+            tuple: record.tuple,
+            lease: record.lease.map(|(id, expires)| Lease {
+                id,
+                expires: self.origin + expires,
+            }),
+            priority: record.priority,
+        }
+    }
+
+    /// Index keys of an entry, with their values
+    fn index(&self, seq: u64, record: &Record) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let arity = record.tuple.len() as u64;
+        let mut keys = vec![
+            (key(BY_ARITY, &[arity, seq]), vec![]),
+            (key(BY_HASH, &[hash(record.tuple.iter()), seq]), vec![]),
+        ];
+
+        if let Some(Field::Value(val)) = record.tuple.iter().nth(self.field) {
+            keys.push((key(BY_VALUE, &[arity, hash([val]), seq]), vec![]));
+        }
+
+        if let Some((id, expires)) = record.lease {
+            keys.push((key(LEASE, &[id]), seq.to_be_bytes().to_vec()));
+            keys.push((key(EXPIRING, &[expires.as_nanos() as u64, seq]), vec![]));
+        }
+
+        keys
+    }
+
+    /// Sequence numbers under the keys of the given kind starting with the prefix, greater than after
+    fn range<'a>(
+        &'a self,
+        kind: u8,
+        prefix: &[u64],
+        after: u64,
+    ) -> Box<dyn Iterator<Item = io::Result<u64>> + 'a> {
+        let first = match after.checked_add(1) {
+            Some(first) => first,
+            None => return Box::new(std::iter::empty()),
+        };
+
+        let start = key(kind, &[prefix, &[first]].concat());
+        let end = key(kind, &[prefix, &[u64::MAX]].concat());
+
+        Box::new(self.tree.range(start..=end).map(|item| last(&item?.0)))
+    }
+
+    /// Sequence numbers of the entries that can match the pattern inserted after the given one, in insertion order
+    fn candidates<'a>(
+        &'a self,
+        pattern: &Tuple,
+        after: u64,
+    ) -> Box<dyn Iterator<Item = io::Result<u64>> + 'a> {
+        let arity = pattern.len() as u64;
+
+        match pattern.iter().nth(self.field) {
+            _ if pattern.has_data_only() => self.range(BY_HASH, &[hash(pattern.iter())], after),
+            Some(Field::Value(val)) => self.range(BY_VALUE, &[arity, hash([val])], after),
+            _ => self.range(BY_ARITY, &[arity], after),
+        }
+    }
+}
+
+impl Storage for DiskStorage {
+    fn insert(&mut self, entry: Entry) -> io::Result<()> {
+        let record = self.encode(&entry);
+        let mut batch = sled::Batch::default();
+
+        batch.insert(key(ENTRY, &[entry.seq]), serde_json::to_vec(&record)?);
+        for (key, value) in self.index(entry.seq, &record) {
+            batch.insert(key, value);
+        }
+
+        Ok(self.tree.apply_batch(batch)?)
+    }
+
+    fn remove(&mut self, seq: u64) -> io::Result<Option<Entry>> {
+        let value = match self.tree.get(key(ENTRY, &[seq]))? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let record: Record = serde_json::from_slice(&value)?;
+        let mut batch = sled::Batch::default();
+
+        batch.remove(key(ENTRY, &[seq]));
+        for (key, _) in self.index(seq, &record) {
+            batch.remove(key);
+        }
+        self.tree.apply_batch(batch)?;
+
+        Ok(Some(self.decode(seq, record)))
+    }
+
+    fn get(&self, seq: u64) -> io::Result<Option<Cow<'_, Entry>>> {
+        match self.tree.get(key(ENTRY, &[seq]))? {
+            Some(value) => {
+                let record = serde_json::from_slice(&value)?;
+                Ok(Some(Cow::Owned(self.decode(seq, record))))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn leased(&self, lease: u64) -> io::Result<Option<u64>> {
+        self.tree
+            .get(key(LEASE, &[lease]))?
+            .map(|seq| last(&seq))
+            .transpose()
+    }
+
+    fn scan<'a>(
+        &'a self,
+        pattern: Option<&'a Tuple>,
+        after: u64,
+        now: Instant,
+    ) -> Box<dyn Iterator<Item = io::Result<Cow<'a, Entry>>> + 'a> {
+        let seqs = match pattern {
+            Some(pattern) => self.candidates(pattern, after),
+            None => self.range(ENTRY, &[], after),
+        };
+
+        Box::new(
+            seqs.map(move |seq| self.get(seq?))
+                .filter_map(Result::transpose)
+                .filter(move |entry| match entry {
+                    Ok(entry) => {
+                        entry.is_alive(now) && pattern.is_none_or(|pattern| entry.matches(pattern))
+                    }
+                    Err(_) => true,
+                }),
+        )
+    }
+
+    fn expired(&self, now: Instant) -> io::Result<Vec<u64>> {
+        let elapsed = now.saturating_duration_since(self.origin).as_nanos() as u64;
+
+        self.tree
+            .range(key(EXPIRING, &[0, 0])..=key(EXPIRING, &[elapsed, u64::MAX]))
+            .map(|item| last(&item?.0))
+            .collect()
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        Ok(self.tree.clear()?)
+    }
+}
+
+impl Drop for DiskStorage {
+    fn drop(&mut self) {
+        let _ = self.db.drop_tree(self.tree.name());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::super::store::tests::check;
+    use super::*;
+
+    fn db_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("rustuple-disk-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&path);
+
+        path
+    }
+
+    #[test]
+    fn disk_storage() {
+        let path = db_path("storage");
+        let backend = DiskBackend::open(&path).unwrap();
+
+        check(&mut *backend.storage("jobs", 0).unwrap());
+
+        drop(backend);
+        let _ = fs::remove_dir_all(&path);
+    }
+
+    /// The trees of a previous run are dropped, but a database written by someone else is left untouched
+    #[test]
+    fn open_only_drops_its_own_trees() {
+        let path = db_path("open");
+
+        let db = sled::open(&path).unwrap();
+        let tree = db.open_tree(format!("{}jobs/0", TREE_PREFIX)).unwrap();
+        tree.insert("key", "value").unwrap();
+        db.flush().unwrap();
+        drop((tree, db));
+
+        let backend = DiskBackend::open(&path).unwrap();
+        assert_eq!(backend.db.tree_names().len(), 1);
+        drop(backend);
+
+        let db = sled::open(&path).unwrap();
+        db.insert("key", "value").unwrap();
+        db.flush().unwrap();
+        drop(db);
+
+        assert_eq!(
+            DiskBackend::open(&path).err().map(|e| e.kind()),
+            Some(ErrorKind::InvalidData)
+        );
+        let db = sled::open(&path).unwrap();
+        assert!(db.get("key").unwrap().is_some());
+
+        drop(db);
+        let _ = fs::remove_dir_all(&path);
+    }
+}
@@ -0,0 +1,192 @@
+use std::io::ErrorKind;
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, RecvTimeoutError};
+use std::thread::sleep;
+use std::time::Duration;
+
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{Message, WebSocket};
+
+use super::snapshot::Snapshot;
+use super::wal::{Record, Replica};
+use super::{deserialize, Spaces, NOTIFY_INTERVAL};
+use crate::data::{AdminOperation, Operation, TupleError};
+
+/// How long a backup waits before connecting again to its primary
+const RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Records queued for a backup before it is considered too slow: the records are passed to the backups while the log
+/// is locked, so a backup falling behind is disconnected instead of blocking the writes. It connects again and starts
+/// over from a new snapshot
+pub const BACKLOG: usize = 10_000;
+
+/// Answer the request opening a replication connection: if the backup is authorized, the changes are passed to the
+/// replica from now on and the snapshot of the spaces it starts from is returned
+pub fn open(
+    spaces: &Spaces,
+    admin_token: &Option<String>,
+    request: Result<Operation, TupleError>,
+    replica: Replica,
+) -> Result<Snapshot, TupleError> {
+    match request? {
+        Operation::Admin(token, AdminOperation::Replicate)
+            if admin_token.as_ref() == Some(&token) =>
+        {
+            spaces.replicate(replica)
+        }
+        Operation::Admin(_, AdminOperation::Replicate) => Err(TupleError::UnauthorizedError),
+        _ => Err(TupleError::Error),
+    }
+}
+
+/// Serve a replication connection opened by a backup: reply to its request, send the snapshot of the spaces and then
+/// every record of the log, until the backup closes the connection or the server shuts down
+pub fn stream(
+    spaces: &Spaces,
+    admin_token: &Option<String>,
+    shutdown: &AtomicBool,
+    socket: &mut WebSocket<TcpStream>,
+) {
+    let request = match socket.read() {
+        Ok(Message::Text(val)) => deserialize(val),
+        _ => return,
+    };
+
+    let (sender, receiver) = sync_channel::<String>(BACKLOG);
+    let replica = Box::new(move |line: &str| sender.try_send(line.to_string()).is_ok());
+
+    let snapshot = match open(spaces, admin_token, request, replica) {
+        Ok(snapshot) => snapshot,
+        Err(error) => {
+            let _ = socket.send(Message::Text(serde_json::to_string(&error).unwrap()));
+            return;
+        }
+    };
+
+    let _ = socket.write(Message::Text(
+        serde_json::to_string(&TupleError::NoError).unwrap(),
+    ));
+    if socket
+        .send(Message::Text(serde_json::to_string(&snapshot).unwrap()))
+        .is_err()
+    {
+        return;
+    }
+
+    while !shutdown.load(Ordering::SeqCst) {
+        let line = match receiver.recv_timeout(NOTIFY_INTERVAL) {
+            Ok(line) => line,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        // Send together the records written in the meantime
+        let mut sent = socket.write(Message::Text(line)).is_ok();
+        while let (true, Ok(line)) = (sent, receiver.try_recv()) {
+            sent = socket.write(Message::Text(line)).is_ok();
+        }
+
+        if !sent || socket.flush().is_err() {
+            break;
+        }
+    }
+}
+
+/// Follow the primary at the given url as a backup, connecting again whenever the connection is lost, until the
+/// server is promoted or shut down
+pub fn follow(
+    url: &str,
+    admin_token: &str,
+    spaces: &Spaces,
+    snapshot_path: Option<PathBuf>,
+    shutdown: &AtomicBool,
+) {
+    while spaces.is_backup() && !shutdown.load(Ordering::SeqCst) {
+        if let Err(error) = replicate(url, admin_token, spaces, &snapshot_path, shutdown) {
+            eprintln!("Replication from the primary interrupted: {:?}", error);
+            sleep(RETRY_INTERVAL);
+        }
+    }
+}
+
+/// Copy the spaces of the primary and apply its changes, until the connection is lost or the server is promoted or
+/// shut down
+fn replicate(
+    url: &str,
+    admin_token: &str,
+    spaces: &Spaces,
+    snapshot_path: &Option<PathBuf>,
+    shutdown: &AtomicBool,
+) -> Result<(), TupleError> {
+    let (mut socket, _) = tungstenite::connect(url).map_err(|_| TupleError::Error)?;
+
+    let request = Operation::Admin(admin_token.to_string(), AdminOperation::Replicate);
+    socket
+        .send(Message::Text(serde_json::to_string(&request).unwrap()))
+        .map_err(|_| TupleError::Error)?;
+
+    match serde_json::from_str(&read(&mut socket)?) {
+        Ok(TupleError::NoError) => (),
+        Ok(error) => return Err(error),
+        Err(_) => return Err(TupleError::Error),
+    }
+
+    let snapshot: Snapshot =
+        serde_json::from_str(&read(&mut socket)?).map_err(|_| TupleError::Error)?;
+    let horizon = snapshot.horizon();
+    spaces.restore(snapshot)?;
+    eprintln!("Following the primary at {}", url);
+
+    // The log of the backup starts again from the copy of the spaces. If the snapshot cannot be written the backup
+    // copies the spaces again when it reconnects
+    if let (Some(wal), Some(path)) = (&spaces.log, snapshot_path) {
+        spaces.snapshot(wal, path).map_err(|e| {
+            eprintln!("Error writing the snapshot: {}", e);
+            TupleError::Error
+        })?;
+    }
+
+    // Wake up periodically to check the promotion and the shutdown
+    if let MaybeTlsStream::Plain(stream) = socket.get_ref() {
+        let _ = stream.set_read_timeout(Some(NOTIFY_INTERVAL));
+    }
+
+    while spaces.is_backup() && !shutdown.load(Ordering::SeqCst) {
+        let line = match socket.read() {
+            Ok(Message::Text(line)) => line,
+            Ok(Message::Close(_)) => return Err(TupleError::Error),
+            Ok(_) => continue,
+            Err(tungstenite::Error::Io(e))
+                if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut =>
+            {
+                continue
+            }
+            Err(_) => return Err(TupleError::Error),
+        };
+
+        let record: Record = serde_json::from_str(&line).map_err(|_| TupleError::Error)?;
+        if horizon.contains(&record) {
+            continue;
+        }
+
+        // Written in the log of the backup (if any), so that it is persistent and streamed to its own backups. If it
+        // cannot be the backup copies the spaces again when it reconnects
+        spaces.follow(record)?;
+    }
+
+    let _ = socket.close(None);
+    Ok(())
+}
+
+/// Read the next text message of the primary
+fn read(socket: &mut WebSocket<MaybeTlsStream<TcpStream>>) -> Result<String, TupleError> {
+    loop {
+        match socket.read() {
+            Ok(Message::Text(text)) => return Ok(text),
+            Ok(Message::Close(_)) | Err(_) => return Err(TupleError::Error),
+            Ok(_) => (),
+        }
+    }
+}
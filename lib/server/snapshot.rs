@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::wal::{self, Change, Record};
+
+/// Point-in-time state of all the spaces, which replaces the records of the log written before it
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Sequence number of the first record of the log not cut by the snapshot
+    pub lsn: u64,
+
+    /// Term of the record before lsn, in a cluster
+    #[serde(default)]
+    pub term: u64,
+    pub spaces: Vec<SpaceSnapshot>,
+}
+
+/// State of a space, containing the changes of all the records of the space with a smaller sequence number
+#[derive(Serialize, Deserialize)]
+pub struct SpaceSnapshot {
+    pub name: String,
+    pub multiset: bool,
+    pub lsn: u64,
+    pub next_seq: u64,
+    pub next_lease: u64,
+
+    /// Put of every tuple of the space
+    pub tuples: Vec<Change>,
+}
+
+/// Records of the log already contained in a snapshot, which are skipped when the log is replayed after it
+#[derive(Default)]
+pub struct Horizon {
+    lsn: u64,
+    spaces: HashMap<String, u64>,
+}
+
+impl Horizon {
+    pub fn contains(&self, record: &Record) -> bool {
+        record.lsn < *self.spaces.get(&record.space).unwrap_or(&self.lsn)
+    }
+}
+
+impl Snapshot {
+    pub fn horizon(&self) -> Horizon {
+        Horizon {
+            lsn: self.lsn,
+            spaces: self
+                .spaces
+                .iter()
+                .map(|space| (space.name.clone(), space.lsn))
+                .collect(),
+        }
+    }
+
+    /// Path of the snapshot of the given log
+    pub fn path(wal: &Path) -> PathBuf {
+        let mut path = wal.to_path_buf().into_os_string();
+        path.push(".snapshot");
+
+        path.into()
+    }
+
+    /// Read the snapshot, if one was written
+    pub fn load(path: &Path) -> io::Result<Option<Snapshot>> {
+        match fs::read(path) {
+            Ok(content) => serde_json::from_slice(&content)
+                .map(Some)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Write the snapshot in a new file, which atomically replaces the previous one
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut tmp = path.to_path_buf().into_os_string();
+        tmp.push(".tmp");
+
+        let mut file = File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec(self)?)?;
+        file.sync_all()?;
+
+        wal::replace(&tmp, path)
+    }
+}
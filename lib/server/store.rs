@@ -0,0 +1,606 @@
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::ops::Bound;
+use std::time::Instant;
+
+use crate::data::*;
+
+/// Lease of a tuple put with a time to live, identified by an id that the owner uses to renew it
+#[derive(Clone, Copy)]
+pub struct Lease {
+    pub id: u64,
+    pub expires: Instant,
+}
+
+/// A tuple stored in the Tuple Space, together with its lease (if any)
+#[derive(Clone)]
+pub struct Entry {
+    /// Sequence number of the insertion, the entries are kept sorted by it
+    pub seq: u64,
+    pub tuple: Tuple,
+    pub lease: Option<Lease>,
+    pub priority: i32,
+}
+
+impl Entry {
+    /// An entry is alive until its lease expires, expired entries are invisible to the operations
+    pub fn is_alive(&self, now: Instant) -> bool {
+        match self.lease {
+            Some(lease) => lease.expires > now,
+            None => true,
+        }
+    }
+
+    pub fn matches(&self, tuple: &Tuple) -> bool {
+        self.tuple.len() == tuple.len() && self.tuple.matches(tuple)
+    }
+}
+
+/// Change of the entries of a store, recorded to be written in the write-ahead log, or undone if the operation fails
+pub enum Mutation {
+    Put(Entry),
+    Take(Entry),
+}
+
+/// Storage of the entries of a shard of a Tuple Space. The server accesses it with the shard locked, so it needs no
+/// synchronization of its own. The expired entries stay stored until the server removes them, but they are invisible
+/// to scan and count. A storage reports the errors of the medium it is kept on, see Store for how the server handles
+/// them
+pub trait Storage: Send + Sync {
+    fn insert(&mut self, entry: Entry) -> io::Result<()>;
+
+    fn remove(&mut self, seq: u64) -> io::Result<Option<Entry>>;
+
+    fn get(&self, seq: u64) -> io::Result<Option<Cow<'_, Entry>>>;
+
+    /// Sequence number of the entry with the given lease
+    fn leased(&self, lease: u64) -> io::Result<Option<u64>>;
+
+    /// Alive entries matching the pattern (or all of them if there is no pattern) inserted after the given sequence
+    /// number, in insertion order
+    fn scan<'a>(
+        &'a self,
+        pattern: Option<&'a Tuple>,
+        after: u64,
+        now: Instant,
+    ) -> Box<dyn Iterator<Item = io::Result<Cow<'a, Entry>>> + 'a>;
+
+    /// Number of alive entries matching the pattern (or of all of them if there is no pattern)
+    fn count(&self, pattern: Option<&Tuple>, now: Instant) -> io::Result<usize> {
+        self.scan(pattern, 0, now)
+            .try_fold(0, |count, entry| entry.map(|_| count + 1))
+    }
+
+    /// Remove the alive entries matching the pattern, returning them in insertion order. On an error none of them is
+    /// removed
+    fn remove_matching(&mut self, pattern: &Tuple, now: Instant) -> io::Result<Vec<Entry>> {
+        let seqs = self
+            .scan(Some(pattern), 0, now)
+            .map(|entry| entry.map(|entry| entry.seq))
+            .collect::<io::Result<Vec<u64>>>()?;
+
+        let mut entries = vec![];
+        for seq in seqs {
+            match self.remove(seq) {
+                Ok(entry) => entries.extend(entry),
+                Err(e) => {
+                    for entry in entries {
+                        let _ = self.insert(entry);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Sequence numbers of the entries whose lease is expired
+    fn expired(&self, now: Instant) -> io::Result<Vec<u64>>;
+
+    /// Remove all the entries
+    fn clear(&mut self) -> io::Result<()>;
+}
+
+/// Constructor of the storages of the spaces, one for every shard
+pub trait Backend: Send + Sync {
+    /// New empty storage for a shard of the given space, indexing the field in the given position
+    fn storage(&self, space: &str, index_field: usize) -> io::Result<Box<dyn Storage>>;
+}
+
+/// Backend keeping the entries in memory, the default
+pub struct MemoryBackend;
+
+impl Backend for MemoryBackend {
+    fn storage(&self, _space: &str, index_field: usize) -> io::Result<Box<dyn Storage>> {
+        Ok(Box::new(MemoryStorage::new(index_field)))
+    }
+}
+
+/// Indexes of the entries by arity, by the value of one field (the first by default) and by the whole tuple, so that a
+/// pattern only looks at the entries that can match it. Every index is kept sorted by sequence number
+pub struct Index {
+    /// Position of the indexed field
+    field: usize,
+
+    /// Sequence numbers of the entries by arity
+    by_arity: HashMap<usize, BTreeSet<u64>>,
+
+    /// Sequence numbers of the entries by arity and value of the indexed field
+    by_value: BTreeMap<(usize, Value), BTreeSet<u64>>,
+
+    /// Sequence numbers of the entries by hash of the tuple, used by the patterns with only data
+    by_hash: HashMap<u64, BTreeSet<u64>>,
+
+    /// Sequence number of the leased entries by lease id
+    leases: HashMap<u64, u64>,
+
+    /// Leased entries by expiration
+    expiring: BTreeSet<(Instant, u64)>,
+}
+
+impl Index {
+    pub fn new(field: usize) -> Self {
+        Index {
+            field,
+            by_arity: HashMap::new(),
+            by_value: BTreeMap::new(),
+            by_hash: HashMap::new(),
+            leases: HashMap::new(),
+            expiring: BTreeSet::new(),
+        }
+    }
+
+    /// Value of the indexed field, if the tuple has it and it is not a type
+    fn key(&self, tuple: &Tuple) -> Option<(usize, Value)> {
+        match tuple.iter().nth(self.field) {
+            Some(Field::Value(val)) => Some((tuple.len(), val.clone())),
+            _ => None,
+        }
+    }
+
+    fn hash(tuple: &Tuple) -> u64 {
+        let mut hasher = DefaultHasher::new();
+
+        for field in tuple.iter() {
+            field.hash(&mut hasher);
+        }
+
+        hasher.finish()
+    }
+
+    pub fn add(&mut self, entry: &Entry) {
+        let seq = entry.seq;
+
+        self.by_arity
+            .entry(entry.tuple.len())
+            .or_default()
+            .insert(seq);
+
+        if let Some(key) = self.key(&entry.tuple) {
+            self.by_value.entry(key).or_default().insert(seq);
+        }
+
+        self.by_hash
+            .entry(Index::hash(&entry.tuple))
+            .or_default()
+            .insert(seq);
+
+        if let Some(lease) = entry.lease {
+            self.leases.insert(lease.id, seq);
+            self.expiring.insert((lease.expires, seq));
+        }
+    }
+
+    pub fn remove(&mut self, entry: &Entry) {
+        let seq = entry.seq;
+        let arity = entry.tuple.len();
+
+        if let Some(seqs) = self.by_arity.get_mut(&arity) {
+            seqs.remove(&seq);
+
+            if seqs.is_empty() {
+                self.by_arity.remove(&arity);
+            }
+        }
+
+        if let Some(key) = self.key(&entry.tuple) {
+            if let Some(seqs) = self.by_value.get_mut(&key) {
+                seqs.remove(&seq);
+
+                if seqs.is_empty() {
+                    self.by_value.remove(&key);
+                }
+            }
+        }
+
+        let hash = Index::hash(&entry.tuple);
+        if let Some(seqs) = self.by_hash.get_mut(&hash) {
+            seqs.remove(&seq);
+
+            if seqs.is_empty() {
+                self.by_hash.remove(&hash);
+            }
+        }
+
+        if let Some(lease) = entry.lease {
+            self.leases.remove(&lease.id);
+            self.expiring.remove(&(lease.expires, seq));
+        }
+    }
+
+    /// Sequence numbers of the entries that can match the pattern inserted after the given one, in insertion order
+    pub fn candidates<'a>(&'a self, pattern: &Tuple, after: u64) -> impl Iterator<Item = u64> + 'a {
+        let seqs = match self.key(pattern) {
+            _ if pattern.has_data_only() => self.by_hash.get(&Index::hash(pattern)),
+            Some(key) => self.by_value.get(&key),
+            None => self.by_arity.get(&pattern.len()),
+        };
+
+        seqs.into_iter()
+            .flat_map(move |seqs| seqs.range((Bound::Excluded(after), Bound::Unbounded)))
+            .copied()
+    }
+
+    pub fn leased(&self, lease: u64) -> Option<u64> {
+        self.leases.get(&lease).copied()
+    }
+
+    pub fn expired(&self, now: Instant) -> Vec<u64> {
+        self.expiring
+            .iter()
+            .take_while(|(expires, _)| *expires <= now)
+            .map(|(_, seq)| *seq)
+            .collect()
+    }
+
+    pub fn clear(&mut self) {
+        *self = Index::new(self.field);
+    }
+}
+
+/// Storage keeping the entries in memory, sorted by sequence number
+pub struct MemoryStorage {
+    entries: BTreeMap<u64, Entry>,
+    index: Index,
+}
+
+impl MemoryStorage {
+    /// Construct an empty storage, indexing the field in the given position
+    pub fn new(field: usize) -> Self {
+        MemoryStorage {
+            entries: BTreeMap::new(),
+            index: Index::new(field),
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn insert(&mut self, entry: Entry) -> io::Result<()> {
+        self.index.add(&entry);
+        self.entries.insert(entry.seq, entry);
+
+        Ok(())
+    }
+
+    fn remove(&mut self, seq: u64) -> io::Result<Option<Entry>> {
+        let entry = self.entries.remove(&seq);
+
+        if let Some(entry) = &entry {
+            self.index.remove(entry);
+        }
+
+        Ok(entry)
+    }
+
+    fn get(&self, seq: u64) -> io::Result<Option<Cow<'_, Entry>>> {
+        Ok(self.entries.get(&seq).map(Cow::Borrowed))
+    }
+
+    fn leased(&self, lease: u64) -> io::Result<Option<u64>> {
+        Ok(self.index.leased(lease))
+    }
+
+    fn scan<'a>(
+        &'a self,
+        pattern: Option<&'a Tuple>,
+        after: u64,
+        now: Instant,
+    ) -> Box<dyn Iterator<Item = io::Result<Cow<'a, Entry>>> + 'a> {
+        let pattern = match pattern {
+            Some(pattern) => pattern,
+            None => {
+                return Box::new(
+                    self.entries
+                        .range((Bound::Excluded(after), Bound::Unbounded))
+                        .map(|(_, entry)| entry)
+                        .filter(move |entry| entry.is_alive(now))
+                        .map(|entry| Ok(Cow::Borrowed(entry))),
+                )
+            }
+        };
+
+        Box::new(
+            self.index
+                .candidates(pattern, after)
+                .map(|seq| &self.entries[&seq])
+                .filter(move |entry| entry.is_alive(now) && entry.matches(pattern))
+                .map(|entry| Ok(Cow::Borrowed(entry))),
+        )
+    }
+
+    fn expired(&self, now: Instant) -> io::Result<Vec<u64>> {
+        Ok(self.index.expired(now))
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        self.entries.clear();
+        self.index.clear();
+
+        Ok(())
+    }
+}
+
+/// Report an error of the storage, which fails the operation with an Error
+pub fn failed(error: io::Error) -> TupleError {
+    eprintln!("Error accessing the storage of the tuples: {}", error);
+    TupleError::Error
+}
+
+/// Entries of a shard as seen by the operations: a storage, whose changes are recorded to be written in the
+/// write-ahead log when the operation succeeds, or undone when it fails (also because of an error of the storage,
+/// after which the operation may have changed part of the shard already)
+pub struct Store {
+    storage: Box<dyn Storage>,
+
+    /// Changes of the running operation
+    journal: Vec<Mutation>,
+}
+
+impl Store {
+    /// Construct a store on an empty storage
+    pub fn new(storage: Box<dyn Storage>) -> Self {
+        Store {
+            storage,
+            journal: vec![],
+        }
+    }
+
+    pub fn insert(&mut self, entry: Entry) -> Result<(), TupleError> {
+        self.storage.insert(entry.clone()).map_err(failed)?;
+        self.journal.push(Mutation::Put(entry));
+
+        Ok(())
+    }
+
+    pub fn remove(&mut self, seq: u64) -> Result<Option<Entry>, TupleError> {
+        let entry = self.storage.remove(seq).map_err(failed)?;
+
+        if let Some(entry) = entry.as_ref() {
+            self.journal.push(Mutation::Take(entry.clone()));
+        }
+
+        Ok(entry)
+    }
+
+    /// Remove the alive entries matching the pattern, in insertion order
+    pub fn remove_matching(
+        &mut self,
+        pattern: &Tuple,
+        now: Instant,
+    ) -> Result<Vec<Entry>, TupleError> {
+        let entries = self.storage.remove_matching(pattern, now).map_err(failed)?;
+        self.journal
+            .extend(entries.iter().cloned().map(Mutation::Take));
+
+        Ok(entries)
+    }
+
+    pub fn get(&self, seq: u64) -> Result<Option<Cow<'_, Entry>>, TupleError> {
+        self.storage.get(seq).map_err(failed)
+    }
+
+    /// Sequence number of the entry with the given lease
+    pub fn leased(&self, lease: u64) -> Result<Option<u64>, TupleError> {
+        self.storage.leased(lease).map_err(failed)
+    }
+
+    /// Replace the lease of an entry
+    pub fn set_lease(&mut self, seq: u64, lease: Lease) -> Result<(), TupleError> {
+        if let Some(mut entry) = self.remove(seq)? {
+            entry.lease = Some(lease);
+            self.insert(entry)?;
+        }
+
+        Ok(())
+    }
+
+    /// Alive entries matching the pattern (or all of them if there is no pattern) inserted after the given sequence
+    /// number, in insertion order
+    pub fn select<'a>(
+        &'a self,
+        pattern: Option<&'a Tuple>,
+        after: u64,
+        now: Instant,
+    ) -> Box<dyn Iterator<Item = Result<Cow<'a, Entry>, TupleError>> + 'a> {
+        Box::new(
+            self.storage
+                .scan(pattern, after, now)
+                .map(|entry| entry.map_err(failed)),
+        )
+    }
+
+    /// Alive entries matching the pattern, in insertion order
+    pub fn matching<'a>(
+        &'a self,
+        pattern: &'a Tuple,
+        now: Instant,
+    ) -> Box<dyn Iterator<Item = Result<Cow<'a, Entry>, TupleError>> + 'a> {
+        self.select(Some(pattern), 0, now)
+    }
+
+    /// True if an alive entry matches the pattern
+    pub fn contains(&self, pattern: &Tuple, now: Instant) -> Result<bool, TupleError> {
+        Ok(self.matching(pattern, now).next().transpose()?.is_some())
+    }
+
+    /// Number of alive entries matching the pattern (or of all of them if there is no pattern)
+    pub fn count(&self, pattern: Option<&Tuple>, now: Instant) -> Result<usize, TupleError> {
+        self.storage.count(pattern, now).map_err(failed)
+    }
+
+    /// Sequence numbers of the entries whose lease is expired
+    pub fn expired(&self, now: Instant) -> Result<Vec<u64>, TupleError> {
+        self.storage.expired(now).map_err(failed)
+    }
+
+    /// Changes recorded since the last commit or rollback
+    pub fn journal(&self) -> &[Mutation] {
+        &self.journal
+    }
+
+    /// Forget the changes recorded, once written in the log
+    pub fn commit(&mut self) {
+        self.journal.clear();
+    }
+
+    /// Undo the changes recorded, in reverse order. A change that cannot be undone because of another error of the
+    /// storage is reported and left in place
+    pub fn rollback(&mut self) {
+        for mutation in std::mem::take(&mut self.journal).into_iter().rev() {
+            let undone = match mutation {
+                Mutation::Put(entry) => self.storage.remove(entry.seq).map(|_| ()),
+                Mutation::Take(entry) => self.storage.insert(entry),
+            };
+
+            if let Err(e) = undone {
+                failed(e);
+            }
+        }
+    }
+
+    /// Remove all the entries, without recording the change
+    pub fn clear(&mut self) -> Result<(), TupleError> {
+        self.storage.clear().map_err(failed)
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn entry(seq: u64, tuple: Tuple, lease: Option<Lease>) -> Entry {
+        Entry {
+            seq,
+            tuple,
+            lease,
+            priority: 0,
+        }
+    }
+
+    fn string(val: &str) -> Field {
+        Field::Value(Value::String(val.to_string()))
+    }
+
+    fn int(val: i32) -> Field {
+        Field::Value(Value::Integer(val))
+    }
+
+    fn seqs<'a>(entries: impl Iterator<Item = io::Result<Cow<'a, Entry>>>) -> Vec<u64> {
+        entries.map(|entry| entry.unwrap().seq).collect()
+    }
+
+    /// Exercise a storage through the trait, shared by the tests of the backends: the patterns look up every index, the
+    /// expired entries are invisible, and the removed entries leave all of them
+    pub fn check(storage: &mut dyn Storage) {
+        let now = Instant::now();
+        let jobs = tuple!(string("job"), Field::Type(Type::Integer));
+
+        storage
+            .insert(entry(1, tuple!(string("job"), int(1)), None))
+            .unwrap();
+        storage
+            .insert(entry(
+                2,
+                tuple!(string("job"), int(2)),
+                Some(Lease {
+                    id: 7,
+                    expires: now + Duration::from_secs(3600),
+                }),
+            ))
+            .unwrap();
+        storage
+            .insert(entry(3, tuple!(string("log"), int(1)), None))
+            .unwrap();
+        storage
+            .insert(entry(4, tuple!(string("job"), int(3), int(3)), None))
+            .unwrap();
+        storage
+            .insert(entry(
+                5,
+                tuple!(string("job"), int(4)),
+                Some(Lease {
+                    id: 8,
+                    expires: now,
+                }),
+            ))
+            .unwrap();
+
+        assert_eq!(seqs(storage.scan(Some(&jobs), 0, now)), vec![1, 2]);
+        assert_eq!(seqs(storage.scan(Some(&jobs), 1, now)), vec![2]);
+        assert_eq!(seqs(storage.scan(None, 0, now)), vec![1, 2, 3, 4]);
+        assert_eq!(seqs(storage.scan(None, u64::MAX, now)), Vec::<u64>::new());
+        assert_eq!(
+            seqs(storage.scan(Some(&tuple!(string("log"), int(1))), 0, now)),
+            vec![3]
+        );
+        assert_eq!(
+            seqs(storage.scan(
+                Some(&tuple!(
+                    Field::Type(Type::String),
+                    Field::Type(Type::Integer)
+                )),
+                0,
+                now
+            )),
+            vec![1, 2, 3]
+        );
+        assert_eq!(storage.count(Some(&jobs), now).unwrap(), 2);
+        assert_eq!(storage.count(None, now).unwrap(), 4);
+
+        assert_eq!(storage.leased(7).unwrap(), Some(2));
+        assert_eq!(storage.leased(9).unwrap(), None);
+        assert_eq!(storage.expired(now).unwrap(), vec![5]);
+
+        let taken = storage.remove_matching(&jobs, now).unwrap();
+        assert_eq!(
+            taken.iter().map(|entry| entry.seq).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(seqs(storage.scan(None, 0, now)), vec![3, 4]);
+        assert_eq!(storage.count(Some(&jobs), now).unwrap(), 0);
+        assert_eq!(storage.leased(7).unwrap(), None);
+
+        assert_eq!(
+            storage.get(3).unwrap().unwrap().tuple.to_string(),
+            "(log, 1)"
+        );
+        assert_eq!(storage.remove(3).unwrap().unwrap().seq, 3);
+        assert!(storage.get(3).unwrap().is_none());
+        assert!(storage.remove(3).unwrap().is_none());
+
+        storage.clear().unwrap();
+        assert_eq!(storage.count(None, now).unwrap(), 0);
+        assert!(storage.expired(now).unwrap().is_empty());
+    }
+
+    #[test]
+    fn memory_storage() {
+        check(&mut MemoryStorage::new(0));
+    }
+}
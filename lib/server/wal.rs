@@ -0,0 +1,611 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use super::store::{Entry, Lease, Mutation};
+use crate::data::{Tuple, TupleError};
+
+/// When the write-ahead log is forced to the disk. A record is always written to the file before the reply of its
+/// operation, so only a crash of the machine (not of the server) can lose the records not synced yet
+#[derive(Clone, Copy, Debug)]
+pub enum SyncPolicy {
+    /// Sync every record before replying to the client, the safest and slowest choice
+    Always,
+
+    /// Sync the log in the background at the given interval
+    Periodic(Duration),
+
+    /// Never sync, leaving it to the operating system
+    Never,
+}
+
+/// A change of a space, as written in the log
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Change {
+    /// The space is created, with multiset semantics if true
+    Create(bool),
+    Drop,
+    Clear,
+    Put {
+        seq: u64,
+        tuple: Tuple,
+        priority: i32,
+
+        /// Lease id and expiration, in milliseconds since the Unix epoch
+        lease: Option<(u64, u64)>,
+    },
+    Take(u64),
+}
+
+impl Change {
+    /// Expiration of a lease as a wall clock time, which (unlike an Instant) is still valid after a restart
+    fn to_unix(expires: Instant) -> u64 {
+        let time = SystemTime::now() + expires.saturating_duration_since(Instant::now());
+
+        time.duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64
+    }
+
+    /// Inverse of to_unix, an expiration already passed is now
+    pub fn to_instant(expires: u64) -> Instant {
+        let time = UNIX_EPOCH + Duration::from_millis(expires);
+
+        Instant::now() + time.duration_since(SystemTime::now()).unwrap_or_default()
+    }
+}
+
+/// The put of an entry
+impl From<&Entry> for Change {
+    fn from(entry: &Entry) -> Self {
+        Change::Put {
+            seq: entry.seq,
+            tuple: entry.tuple.clone(),
+            priority: entry.priority,
+            lease: entry
+                .lease
+                .map(|Lease { id, expires }| (id, Change::to_unix(expires))),
+        }
+    }
+}
+
+impl From<&Mutation> for Change {
+    fn from(mutation: &Mutation) -> Self {
+        match mutation {
+            Mutation::Put(entry) => Change::from(entry),
+            Mutation::Take(entry) => Change::Take(entry.seq),
+        }
+    }
+}
+
+/// The changes of a space made by one operation, which are replayed all together or (if the record is torn) not at
+/// all
+#[derive(Serialize, Deserialize)]
+pub struct Record {
+    /// Log sequence number, increasing in the order the records are written
+    pub lsn: u64,
+
+    /// Term of the leader of the cluster that wrote the record, 0 outside a cluster
+    #[serde(default)]
+    pub term: u64,
+    pub space: String,
+    pub changes: Vec<Change>,
+}
+
+/// Backup server following the log, receiving every record written after it subscribed. Return false when the
+/// backup is disconnected
+pub type Replica = Box<dyn FnMut(&str) -> bool + Send>;
+
+/// Write-ahead log of the changes of all the spaces, one JSON record per line, written in a file (if the server is
+/// persistent) and streamed to the backups (if any)
+pub struct Wal {
+    path: Option<PathBuf>,
+    file: Mutex<LogFile>,
+    sync: SyncPolicy,
+}
+
+struct LogFile {
+    file: Option<File>,
+
+    /// Length of the file, where the next record is written
+    len: u64,
+
+    /// Sequence number of the next record
+    next_lsn: u64,
+
+    replicas: Vec<Replica>,
+
+    /// Term written in the new records
+    term: u64,
+
+    /// Last records written (sequence number, term and line), kept by a node of a cluster to send them to the other
+    /// nodes
+    tail: VecDeque<(u64, u64, String)>,
+
+    /// Maximum length of the tail, 0 if no record is kept
+    keep: usize,
+
+    /// Term of the record before the tail
+    base_term: u64,
+}
+
+impl LogFile {
+    fn new(file: Option<File>, len: u64, next_lsn: u64) -> Self {
+        LogFile {
+            file,
+            len,
+            next_lsn,
+            replicas: vec![],
+            term: 0,
+            tail: VecDeque::new(),
+            keep: 0,
+            base_term: 0,
+        }
+    }
+
+    /// Write a record, whose sequence number is the next one. The record is streamed to the replicas only once it is
+    /// in the file (and synced, if the policy asks for it): if the file cannot be written the part already written is
+    /// cut, and the record is not written at all
+    fn write(&mut self, record: &Record, line: String, sync: SyncPolicy) -> io::Result<()> {
+        if let Some(file) = self.file.as_mut() {
+            let written =
+                file.write_all(format!("{}\n", line).as_bytes())
+                    .and_then(|_| match sync {
+                        SyncPolicy::Always => file.sync_data(),
+                        _ => Ok(()),
+                    });
+
+            if let Err(e) = written {
+                let _ = file.set_len(self.len);
+                return Err(e);
+            }
+        }
+        self.len += line.len() as u64 + 1;
+        self.next_lsn = record.lsn + 1;
+
+        self.replicas.retain_mut(|replica| replica(&line));
+        self.push(record.lsn, record.term, line);
+        Ok(())
+    }
+
+    fn push(&mut self, lsn: u64, term: u64, line: String) {
+        if self.keep == 0 {
+            return;
+        }
+
+        self.tail.push_back((lsn, term, line));
+        while self.tail.len() > self.keep {
+            if let Some((_, term, _)) = self.tail.pop_front() {
+                self.base_term = term;
+            }
+        }
+    }
+
+    /// Sequence number of the first record of the tail
+    fn start(&self) -> u64 {
+        self.next_lsn - self.tail.len() as u64
+    }
+
+    /// Term of the last record
+    fn last_term(&self) -> u64 {
+        self.tail
+            .back()
+            .map_or(self.base_term, |(_, term, _)| *term)
+    }
+}
+
+impl Wal {
+    /// Open (or create) the log, returning it together with the records already written. A final record without
+    /// its line end was torn by a crash while it was written: it is discarded and cut from the file. The new records
+    /// are numbered after the ones in the file and at least from first_lsn
+    pub fn open(path: &Path, sync: SyncPolicy, first_lsn: u64) -> io::Result<(Self, Vec<Record>)> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+
+        let mut content = vec![];
+        file.read_to_end(&mut content)?;
+
+        let mut records: Vec<Record> = vec![];
+        let mut end = 0;
+
+        while let Some(len) = content[end..].iter().position(|byte| *byte == b'\n') {
+            let record = serde_json::from_slice(&content[end..end + len])
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+
+            records.push(record);
+            end += len + 1;
+        }
+
+        let next_lsn = records
+            .last()
+            .map_or(first_lsn, |record| first_lsn.max(record.lsn + 1));
+
+        if end < content.len() {
+            eprintln!("Discarded a torn record at the end of the write-ahead log");
+            file.set_len(end as u64)?;
+            file.sync_data()?;
+        }
+
+        Ok((
+            Wal {
+                path: Some(path.to_path_buf()),
+                file: Mutex::new(LogFile::new(Some(file), end as u64, next_lsn)),
+                sync,
+            },
+            records,
+        ))
+    }
+
+    /// A log without a file, whose records are only streamed to the backups
+    pub fn memory() -> Self {
+        Wal {
+            path: None,
+            file: Mutex::new(LogFile::new(None, 0, 0)),
+            sync: SyncPolicy::Never,
+        }
+    }
+
+    /// Write the changes of a space as a new record
+    pub fn append(&self, space: &str, changes: Vec<Change>) -> io::Result<()> {
+        let mut log = self.file.lock().unwrap();
+
+        let record = Record {
+            lsn: log.next_lsn,
+            term: log.term,
+            space: space.to_string(),
+            changes,
+        };
+        let line = serde_json::to_string(&record)?;
+
+        log.write(&record, line, self.sync)
+    }
+
+    /// Write a record received from the leader of the cluster, which follows the last one. The line is the record as
+    /// written by the leader
+    pub fn write(&self, record: &Record, line: String) -> io::Result<()> {
+        let mut log = self.file.lock().unwrap();
+
+        if record.lsn != log.next_lsn {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Record out of order",
+            ));
+        }
+
+        log.write(record, line, self.sync)
+    }
+
+    /// Keep in memory the last records written (at most keep), starting from the ones read when the log was opened.
+    /// The term is the one of the record before them
+    pub fn keep(&self, keep: usize, records: &[Record], term: u64) -> io::Result<()> {
+        let mut log = self.file.lock().unwrap();
+        log.keep = keep;
+        log.base_term = term;
+
+        for record in records {
+            log.push(record.lsn, record.term, serde_json::to_string(record)?);
+        }
+
+        Ok(())
+    }
+
+    /// Set the term written in the new records, when the server is elected leader of the cluster
+    pub fn set_term(&self, term: u64) {
+        self.file.lock().unwrap().term = term;
+    }
+
+    /// Sequence number of the next record and term of the last one
+    pub fn last(&self) -> (u64, u64) {
+        let log = self.file.lock().unwrap();
+
+        (log.next_lsn, log.last_term())
+    }
+
+    /// Term of the record with the given sequence number, if it is in the tail or right before it
+    pub fn term(&self, lsn: u64) -> Option<u64> {
+        let log = self.file.lock().unwrap();
+        let start = log.start();
+
+        if lsn >= log.next_lsn {
+            None
+        } else if lsn >= start {
+            Some(log.tail[(lsn - start) as usize].1)
+        } else if lsn + 1 == start {
+            Some(log.base_term)
+        } else {
+            None
+        }
+    }
+
+    /// Lines of at most max records of the tail, starting from the given sequence number. Return None if the tail
+    /// does not contain it anymore
+    pub fn lines(&self, from: u64, max: usize) -> Option<Vec<String>> {
+        let log = self.file.lock().unwrap();
+
+        if from < log.start() || from > log.next_lsn {
+            return None;
+        }
+
+        Some(
+            log.tail
+                .range((from - log.start()) as usize..)
+                .take(max)
+                .map(|(_, _, line)| line.clone())
+                .collect(),
+        )
+    }
+
+    /// Remove all the records, the log continues after a snapshot that ends before next_lsn with a record of the
+    /// given term
+    pub fn reset(&self, next_lsn: u64, term: u64) -> io::Result<()> {
+        let mut log = self.file.lock().unwrap();
+
+        if let Some(file) = log.file.as_mut() {
+            file.set_len(0)?;
+            file.sync_data()?;
+        }
+
+        log.len = 0;
+        log.next_lsn = next_lsn;
+        log.tail.clear();
+        log.base_term = term;
+
+        Ok(())
+    }
+
+    pub fn sync(&self) -> io::Result<()> {
+        match self.file.lock().unwrap().file.as_ref() {
+            Some(file) => file.sync_data(),
+            None => Ok(()),
+        }
+    }
+
+    /// Stream the records written from now on to a backup, returning the sequence number of the first one
+    pub fn subscribe(&self, replica: Replica) -> u64 {
+        let mut log = self.file.lock().unwrap();
+        log.replicas.push(replica);
+
+        log.next_lsn
+    }
+
+    /// Sequence number of the next record, a record is in a snapshot taken before it only if it has a smaller one
+    pub fn next_lsn(&self) -> u64 {
+        self.file.lock().unwrap().next_lsn
+    }
+
+    /// Sequence number and offset in the file of the next record, with the term of the last one
+    pub fn mark(&self) -> (u64, u64, u64) {
+        let log = self.file.lock().unwrap();
+
+        (log.next_lsn, log.len, log.last_term())
+    }
+
+    /// Cut the records before the given offset (returned by mark), which are in a snapshot already written. The
+    /// rest of the log is copied in a new file, which atomically replaces the old one
+    pub fn compact(&self, offset: u64) -> io::Result<()> {
+        let mut log = self.file.lock().unwrap();
+
+        let (path, file) = match (&self.path, log.file.as_mut()) {
+            (Some(path), Some(file)) => (path, file),
+            _ => return Ok(()),
+        };
+
+        let mut tail = vec![];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_to_end(&mut tail)?;
+
+        let mut compacted = path.clone().into_os_string();
+        compacted.push(".compact");
+
+        let mut file = File::create(&compacted)?;
+        file.write_all(&tail)?;
+        file.sync_all()?;
+        replace(&compacted, path)?;
+
+        log.file = Some(OpenOptions::new().read(true).append(true).open(path)?);
+        log.len = tail.len() as u64;
+
+        Ok(())
+    }
+}
+
+/// Rename a file over another one and sync the directory, so that the new file survives a crash
+pub fn replace(from: impl AsRef<Path>, to: &Path) -> io::Result<()> {
+    fs::rename(from, to)?;
+
+    match to.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+/// The log seen by a space, which writes its changes tagged with its name
+#[derive(Clone)]
+pub struct SpaceLog {
+    wal: Arc<Wal>,
+    space: String,
+}
+
+impl SpaceLog {
+    pub fn new(wal: Arc<Wal>, space: &str) -> Self {
+        SpaceLog {
+            wal,
+            space: space.to_string(),
+        }
+    }
+
+    /// Write the changes of an operation, before it replies to the client. If the log cannot be written the
+    /// operation fails with an Error, and the caller rolls back its changes
+    pub fn append(&self, changes: Vec<Change>) -> Result<(), TupleError> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        self.wal.append(&self.space, changes).map_err(|e| {
+            eprintln!("Error writing the write-ahead log: {}", e);
+            TupleError::Error
+        })
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use crate::data::{Field, Value};
+
+    use super::*;
+
+    /// Make every write of the log fail from now on, replacing its file with a read-only one
+    pub fn break_file(wal: &Wal) {
+        let path = wal.path.as_ref().unwrap();
+        wal.file.lock().unwrap().file = Some(File::open(path).unwrap());
+    }
+
+    fn wal_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("rustuple-wal-{}-{}.wal", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+
+        path
+    }
+
+    fn put(seq: u64) -> Change {
+        Change::Put {
+            seq,
+            tuple: tuple!(Field::Value(Value::Integer(seq as i32))),
+            priority: 0,
+            lease: None,
+        }
+    }
+
+    /// Write two records and a third one torn by a crash: it is cut from the file when the log is reopened, and the
+    /// next record is numbered after the ones replayed
+    #[test]
+    fn torn_record_is_cut_on_open() {
+        let path = wal_path("torn");
+
+        let (wal, records) = Wal::open(&path, SyncPolicy::Always, 0).unwrap();
+        assert!(records.is_empty());
+        wal.append("jobs", vec![Change::Create(false)]).unwrap();
+        wal.append("jobs", vec![put(0), put(1)]).unwrap();
+        let len = fs::metadata(&path).unwrap().len();
+        drop(wal);
+
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"lsn":2,"space":"jobs","chan"#).unwrap();
+        drop(file);
+
+        let (wal, records) = Wal::open(&path, SyncPolicy::Always, 0).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+        assert_eq!(
+            records.iter().map(|record| record.lsn).collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+        assert!(matches!(records[0].changes[..], [Change::Create(false)]));
+        assert!(matches!(
+            records[1].changes[..],
+            [Change::Put { seq: 0, .. }, Change::Put { seq: 1, .. }]
+        ));
+        assert_eq!(wal.next_lsn(), 2);
+
+        wal.append("jobs", vec![Change::Take(0)]).unwrap();
+        drop(wal);
+
+        let (_, records) = Wal::open(&path, SyncPolicy::Always, 0).unwrap();
+        assert_eq!(records.len(), 3);
+        assert!(matches!(records[2].changes[..], [Change::Take(0)]));
+
+        let _ = fs::remove_file(&path);
+    }
+
+    /// Whatever the policy, a record is in the file once append returns: the policy only decides when it is synced
+    #[test]
+    fn records_are_written_with_every_sync_policy() {
+        let policies = [
+            SyncPolicy::Always,
+            SyncPolicy::Periodic(Duration::from_millis(10)),
+            SyncPolicy::Never,
+        ];
+
+        for (i, policy) in policies.into_iter().enumerate() {
+            let path = wal_path(&format!("sync-{}", i));
+
+            let (wal, _) = Wal::open(&path, policy, 0).unwrap();
+            wal.append("jobs", vec![put(0)]).unwrap();
+            wal.sync().unwrap();
+            wal.append("jobs", vec![put(1)]).unwrap();
+            drop(wal);
+
+            let (wal, records) = Wal::open(&path, policy, 0).unwrap();
+            assert_eq!(records.len(), 2, "{:?}", policy);
+            assert_eq!(wal.next_lsn(), 2, "{:?}", policy);
+
+            let _ = fs::remove_file(&path);
+        }
+    }
+
+    /// Compacting at a mark keeps only the records written after it, which keep their sequence numbers
+    #[test]
+    fn compact_cuts_the_records_before_the_mark() {
+        let path = wal_path("compact");
+
+        let (wal, _) = Wal::open(&path, SyncPolicy::Never, 0).unwrap();
+        wal.append("jobs", vec![put(0)]).unwrap();
+        let (lsn, offset, _) = wal.mark();
+        wal.append("jobs", vec![put(1)]).unwrap();
+        wal.compact(offset).unwrap();
+        wal.append("jobs", vec![put(2)]).unwrap();
+        drop(wal);
+
+        let (_, records) = Wal::open(&path, SyncPolicy::Never, 0).unwrap();
+        assert_eq!(lsn, 1);
+        assert_eq!(
+            records.iter().map(|record| record.lsn).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+
+        let _ = fs::remove_file(&path);
+    }
+
+    /// A record that cannot be written in the file is not streamed to the replicas and does not take a sequence
+    /// number, the next one is written in its place
+    #[test]
+    fn failed_record_is_not_streamed() {
+        let path = wal_path("failed");
+        let streamed = Arc::new(Mutex::new(vec![]));
+
+        let (wal, _) = Wal::open(&path, SyncPolicy::Always, 0).unwrap();
+        let lines = Arc::clone(&streamed);
+        wal.subscribe(Box::new(move |line: &str| {
+            lines.lock().unwrap().push(line.to_string());
+            true
+        }));
+        wal.append("jobs", vec![put(0)]).unwrap();
+
+        break_file(&wal);
+        assert!(wal.append("jobs", vec![put(1)]).is_err());
+        assert_eq!(streamed.lock().unwrap().len(), 1);
+        assert_eq!(wal.mark(), (1, fs::metadata(&path).unwrap().len(), 0));
+        drop(wal);
+
+        let (wal, records) = Wal::open(&path, SyncPolicy::Always, 0).unwrap();
+        assert_eq!(records.len(), 1);
+        wal.append("jobs", vec![put(1)]).unwrap();
+        drop(wal);
+
+        let (_, records) = Wal::open(&path, SyncPolicy::Always, 0).unwrap();
+        assert_eq!(
+            records.iter().map(|record| record.lsn).collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+
+        let _ = fs::remove_file(&path);
+    }
+}
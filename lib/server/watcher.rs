@@ -0,0 +1,52 @@
+use std::io;
+use std::net::{Shutdown, TcpStream};
+use std::sync::mpsc::{channel, Sender};
+use std::thread::spawn;
+
+use super::delivery::Interrupter;
+
+/// Thread watching the socket of a connection while a blocking request is pending: it interrupts the wait of the
+/// request as soon as the client sends a message (or closes the connection), so the wait does not wake up to look at
+/// the socket. Started by the first blocking request of the connection and ended with it
+pub struct Watcher {
+    stream: TcpStream,
+    armed: Sender<Interrupter>,
+}
+
+impl Watcher {
+    /// Start watching a copy of the stream of the connection. The socket must have no read timeout while the watcher
+    /// is armed, or it would interrupt the wait when the timeout expires
+    pub fn new(stream: &TcpStream) -> io::Result<Self> {
+        let watched = stream.try_clone()?;
+        let (armed, arms) = channel::<Interrupter>();
+
+        spawn(move || {
+            let mut buf = [0; 1];
+
+            // The message is only peeked, it is read by the interrupted wait which then arms the watcher again
+            for interrupter in arms {
+                let _ = watched.peek(&mut buf);
+                interrupter.interrupt();
+            }
+        });
+
+        Ok(Watcher {
+            stream: stream.try_clone()?,
+            armed,
+        })
+    }
+
+    /// Interrupt the wait at the next message of the client, or at once if a message is already waiting to be read
+    pub fn arm(&self, interrupter: Interrupter) -> io::Result<()> {
+        self.armed
+            .send(interrupter)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Watcher ended"))
+    }
+}
+
+impl Drop for Watcher {
+    /// The watcher can be blocked on the socket until the next message, the connection is closed to end it
+    fn drop(&mut self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
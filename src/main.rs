@@ -1,18 +1,10 @@
-use clap::Parser;
-use rustuple::data::{Operation, Tuple, TupleError};
-use std::fmt::Display;
-use std::net::TcpListener;
-use std::net::TcpStream;
-use std::sync::{Arc, Mutex};
-use std::thread::sleep;
-use std::thread::spawn;
+use std::path::PathBuf;
 use std::time::Duration;
-use std::vec;
-use tungstenite::{
-    accept_hdr,
-    handshake::server::{ErrorResponse, Request, Response},
-};
-use tungstenite::{Message, WebSocket};
+
+use clap::{Parser, ValueEnum};
+#[cfg(feature = "disk")]
+use rustuple::server::DiskBackend;
+use rustuple::server::{Server, SyncPolicy};
 
 /// Parser for command line arguments
 #[derive(Parser)]
@@ -22,288 +14,129 @@ struct Cli {
 
     /// Port number
     port_num: String,
-}
-
-/// Struct to create a new Tuple data space, which is mutually accessed by threads
-#[derive(Clone)]
-struct TupleSpace {
-    tuples: Arc<Mutex<Vec<Tuple>>>,
-}
-
-impl TupleSpace {
-    /// Construct a new Tuple Space
-    pub fn new() -> Self {
-        TupleSpace {
-            tuples: Arc::new(Mutex::new(vec![])),
-        }
-    }
-
-    /// Needed for mutual exclusion to increment the strong reference counting of the Arc
-    pub fn clone(&self) -> Self {
-        TupleSpace {
-            tuples: Arc::clone(&self.tuples),
-        }
-    }
-
-    /// Insert a new Tuple in the Tuple Space and return Ok(()) if Tuple Space not contain the specific Tuple, otherwise an Error
-    pub fn out(&mut self, tuple: Tuple) -> Result<(), TupleError> {
-        let mut space = self.tuples.lock().unwrap();
-
-        if !space.iter().any(|elem| elem.equal(&tuple)) {
-            space.push(tuple);
-            return Ok(());
-        }
-
-        Err(TupleError::TupleAlreadyPresentError)
-    }
-
-    /// Extract some tuples out of the Tuple Space, returning Ok(Vec<Tuple>) if at least one is matching, otherwise return an Error
-    pub fn _in(&mut self, tuple: &Tuple) -> Result<Vec<Tuple>, TupleError> {
-        let mut space = self.tuples.lock().unwrap();
-        let mut read_space = space.clone();
-        let to_remove: Vec<usize> = vec![];
-
-        let ret = space
-            .iter()
-            .filter(|&elem| elem.len() == tuple.len())
-            .filter(|&elem| elem.matching_tuples(tuple.clone()))
-            .cloned()
-            .collect::<Vec<Tuple>>();
-
-        for i in ret.clone().iter() {
-            let finded = read_space.iter().enumerate().find(|elem| elem.1.equal(i));
-            match finded {
-                Some(val) => {
-                    space.remove(val.0);
-                    read_space = space.clone();
-                },
-                None => continue,
-            }
-        }
-
-        for i in to_remove.iter() {
-            space.remove(*i);
-        }
-
-        if ret.is_empty() {
-            return Err(TupleError::NoMatchingTupleError);
-        } else {
-            return Ok(ret);
-        }
-    }
-
-    /// Read some tuples of the Tuple Space, returning Ok(Vec<Tuple>) if at least one is matching, otherwise return an Error
-    pub fn _rd(&mut self, tuple: &Tuple) -> Result<Vec<Tuple>, TupleError> {
-        let space = self.tuples.lock().unwrap();
-
-        let ret = space
-            .iter()
-            .filter(|&elem| elem.len() == tuple.len())
-            .filter(|&elem| elem.matching_tuples(tuple.clone()))
-            .cloned()
-            .collect::<Vec<Tuple>>();
-
-        if ret.is_empty() {
-            return Err(TupleError::NoMatchingTupleError);
-        } else {
-            return Ok(ret);
-        }
-    }
-}
-
-impl Display for TupleSpace {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "[\n").unwrap();
-
-        for i in (*self.tuples.lock().unwrap()).iter() {
-            write!(f, "{}\n", i).unwrap()
-        }
-
-        write!(f, "]").unwrap();
-
-        Ok(())
-    }
-}
-
-fn deserialize(message: String) -> Result<Operation, TupleError> {
-    match serde_json::from_str(&message) {
-        Ok(res) => Ok(res),
-        Err(e) => {
-            println!("Error serializing! Error: {}", e);
-            Err(TupleError::Error)
-        }
-    }
-}
-
-fn serialize_vector(vector: Vec<Tuple>) -> Result<String, TupleError> {
-    match serde_json::to_string(&vector) {
-        Ok(res) => Ok(res),
-        Err(e) => {
-            println!("Error serializing! Error: {}", e);
-            Err(TupleError::Error)
-        }
-    }
-}
 
-fn handle_out(space: &mut TupleSpace, tuple: Tuple) -> Result<(), TupleError> {
-    if !tuple.has_data_only() {
-        return Err(TupleError::TupleNotOnlyDataError);
-    }
-
-    space.out(tuple)
+    /// Store duplicate tuples (multiset semantics) instead of rejecting them
+    #[arg(long)]
+    multiset: bool,
+
+    /// Create a named Tuple Space the first time a client connects to it, instead of only with the CreateSpace admin
+    /// operation
+    #[arg(long)]
+    auto_create_spaces: bool,
+
+    /// Credential required by the admin operations, which are disabled if it is not set
+    #[arg(long)]
+    admin_token: Option<String>,
+
+    /// Position of the field used to index the tuples, the patterns with a value in it are matched only against the
+    /// tuples with the same value
+    #[arg(long, default_value_t = 0)]
+    index_field: usize,
+
+    /// Serve the connections as tasks of a tokio runtime instead of one thread each
+    #[cfg(feature = "async")]
+    #[arg(long = "async")]
+    async_io: bool,
+
+    /// Persist the Tuple Spaces in this write-ahead log, replayed at startup
+    #[arg(long)]
+    wal: Option<PathBuf>,
+
+    /// When the write-ahead log is synced to the disk
+    #[arg(long, value_enum, default_value_t = WalSync::Always)]
+    wal_sync: WalSync,
+
+    /// Interval of the periodic sync of the write-ahead log, in milliseconds
+    #[arg(long, default_value_t = 1000)]
+    wal_sync_interval: u64,
+
+    /// Keep the tuples in an embedded on-disk store in this directory instead of memory
+    #[cfg(feature = "disk")]
+    #[arg(long)]
+    disk: Option<PathBuf>,
+
+    /// Accept backup servers, streaming them every change of the Tuple Spaces
+    #[arg(long)]
+    primary: bool,
+
+    /// Run as a backup of the primary server at this address (IP:PORT), authenticated by the admin token, until
+    /// promoted with the Promote admin operation
+    #[arg(long, requires = "admin_token")]
+    backup_of: Option<String>,
+
+    /// Interval between the snapshots of the Tuple Spaces, which compact the write-ahead log, in seconds
+    #[arg(long, requires = "wal")]
+    snapshot_interval: Option<u64>,
+
+    /// Run as a node of a cluster with the servers at these addresses (IP:PORT,IP:PORT,...), authenticated by the
+    /// admin token. The nodes elect a leader, which serves the clients
+    #[arg(
+        long,
+        value_delimiter = ',',
+        requires_all = ["admin_token", "wal"],
+        conflicts_with_all = ["primary", "backup_of"]
+    )]
+    peers: Option<Vec<String>>,
 }
 
-fn handle_in_bl(
-    space: &mut TupleSpace,
-    socket: &mut WebSocket<TcpStream>,
-    tuple: Tuple,
-) -> Result<(), TupleError> {
-    if tuple.has_data_only() {
-        return Err(TupleError::TupleOnlyDataError);
-    }
+/// Sync policy of the write-ahead log
+#[derive(Clone, Copy, ValueEnum)]
+enum WalSync {
+    /// Before every reply
+    Always,
 
-    let mut ret = space._in(&tuple);
-    while ret.is_err() {
-        sleep(Duration::from_secs(1));
-        ret = space._in(&tuple);
-    }
-
-    let serialized = serialize_vector(ret.unwrap())?;
+    /// In the background, every --wal-sync-interval milliseconds
+    Periodic,
 
-    match socket.write(Message::Text(serialized)) {
-        Ok(_) => Ok(()),
-        Err(_) => return Err(TupleError::Error),
-    }
+    /// Left to the operating system
+    Never,
 }
 
-fn handle_rd_bl(
-    space: &mut TupleSpace,
-    socket: &mut WebSocket<TcpStream>,
-    tuple: Tuple,
-) -> Result<(), TupleError> {
-    if tuple.has_data_only() {
-        return Err(TupleError::TupleOnlyDataError);
-    }
-
-    let mut ret = space._rd(&tuple);
-    while ret.is_err() {
-        sleep(Duration::from_secs(1));
-        ret = space._rd(&tuple);
-    }
-
-    let serialized = serialize_vector(ret.unwrap())?;
+fn main() {
+    let args = Cli::parse();
 
-    match socket.write(Message::Text(serialized)) {
-        Ok(_) => Ok(()),
-        Err(_) => return Err(TupleError::Error),
-    }
-}
+    let mut server = Server::bind(format!("{}:{}", args.ip_addr, args.port_num))
+        .unwrap()
+        .multiset(args.multiset)
+        .auto_create(args.auto_create_spaces)
+        .index_field(args.index_field)
+        .primary(args.primary);
 
-fn handle_in_non_bl(
-    space: &mut TupleSpace,
-    socket: &mut WebSocket<TcpStream>,
-    tuple: Tuple,
-) -> Result<(), TupleError> {
-    if tuple.has_data_only() {
-        return Err(TupleError::TupleOnlyDataError);
+    if let Some(token) = args.admin_token {
+        server = server.admin_token(token);
     }
 
-    let ret = space._in(&tuple)?;
-    let serialized = serialize_vector(ret)?;
-
-    match socket.write(Message::Text(serialized)) {
-        Ok(_) => Ok(()),
-        Err(_) => return Err(TupleError::Error),
+    if let Some(primary_addr) = args.backup_of {
+        server = server.backup_of(primary_addr);
     }
-}
 
-fn handle_rd_non_bl(
-    space: &mut TupleSpace,
-    socket: &mut WebSocket<TcpStream>,
-    tuple: Tuple,
-) -> Result<(), TupleError> {
-    if tuple.has_data_only() {
-        return Err(TupleError::TupleOnlyDataError);
+    if let Some(peers) = args.peers {
+        server = server.cluster(peers);
     }
 
-    let ret = space._rd(&tuple)?;
-    let serialized = serialize_vector(ret)?;
+    if let Some(path) = args.wal {
+        let sync = match args.wal_sync {
+            WalSync::Always => SyncPolicy::Always,
+            WalSync::Periodic => {
+                SyncPolicy::Periodic(Duration::from_millis(args.wal_sync_interval))
+            }
+            WalSync::Never => SyncPolicy::Never,
+        };
 
-    match socket.write(Message::Text(serialized)) {
-        Ok(_) => Ok(()),
-        Err(_) => return Err(TupleError::Error),
+        server = server.wal(path, sync);
     }
-}
 
-fn incoming_operations(
-    space: &mut TupleSpace,
-    socket: &mut WebSocket<TcpStream>,
-    message: String,
-) -> Result<(), TupleError> {
-    let operation = deserialize(message)?;
-
-    match operation {
-        Operation::Out(val) => return handle_out(space, val),
-        Operation::InBl(val) => return handle_in_bl(space, socket, val),
-        Operation::RdBl(val) => return handle_rd_bl(space, socket, val),
-        Operation::InNonBl(val) => return handle_in_non_bl(space, socket, val),
-        Operation::RdNonBl(val) => return handle_rd_non_bl(space, socket, val),
+    if let Some(interval) = args.snapshot_interval {
+        server = server.snapshot_interval(Duration::from_secs(interval));
     }
-}
 
-fn callback(req: &Request, response: Response) -> Result<Response, ErrorResponse> {
-    println!("Received a new ws handshake");
-    println!("The request's path is: {}", req.uri().path());
-    println!("The request's headers are:");
-    for (ref header, _value) in req.headers() {
-        println!("* {}", header);
+    #[cfg(feature = "disk")]
+    if let Some(path) = args.disk {
+        server = server.backend(DiskBackend::open(path).unwrap());
     }
 
-    Ok(response)
-}
-
-fn main() {
-    let args = Cli::parse();
+    #[cfg(feature = "async")]
+    let server = server.async_io(args.async_io);
 
-    let server = TcpListener::bind(format!("{}:{}", args.ip_addr, args.port_num)).unwrap();
-
-    let space = TupleSpace::new();
-
-    for stream in server.incoming() {
-        let mut cloned = space.clone();
-        spawn(move || {
-            let mut websocket = accept_hdr(stream.unwrap(), callback).unwrap();
-
-            loop {
-                let msg = websocket.read();
-
-                let res = match msg {
-                    Ok(mex) => match mex {
-                        Message::Text(val) => incoming_operations(&mut cloned, &mut websocket, val),
-                        Message::Close(_) => {
-                            break;
-                        }
-                        _ => panic!("Operation not permitted!"),
-                    },
-                    Err(_) => {
-                        break;
-                    }
-                };
-
-                match res {
-                    Ok(_) => {
-                        let _ = websocket.send(Message::Text(
-                            serde_json::to_string(&TupleError::NoError).unwrap(),
-                        ));
-                    }
-                    Err(error) => {
-                        let _ =
-                            websocket.send(Message::Text(serde_json::to_string(&error).unwrap()));
-                    }
-                }
-
-                println!("Tuple Space: {}", cloned);
-            }
-        });
-    }
+    server.run().unwrap();
 }
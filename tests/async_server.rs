@@ -0,0 +1,66 @@
+#![cfg(feature = "async")]
+
+mod common;
+
+use std::thread::{sleep, spawn};
+use std::time::Duration;
+
+use rustuple::data::*;
+use rustuple::server::Server;
+use rustuple::tuple_space::TupleSpace;
+
+use common::{pair, pattern, reply, send};
+
+#[test]
+fn blocked_in_is_cancelled() {
+    let handle = Server::bind("127.0.0.1:0")
+        .unwrap()
+        .async_io(true)
+        .spawn()
+        .unwrap();
+    let url = format!("ws://{}/socket", handle.local_addr());
+    let mut client = TupleSpace::new(&url);
+
+    let cancel = client.cancel_handle();
+    let canceller = spawn(move || {
+        sleep(Duration::from_millis(200));
+        cancel.cancel();
+    });
+
+    assert!(matches!(
+        client.in_bl(pattern("job")),
+        Err(TupleError::CancelledError)
+    ));
+    canceller.join().unwrap();
+
+    // The cancelled request left the queue, so the next tuple stays in the space for the next request
+    let mut producer = TupleSpace::new(&url);
+    producer.out(pair("job", 1)).unwrap();
+    assert_eq!(producer.count(pattern("job")).unwrap(), 1);
+
+    let taken = client.in_bl(pattern("job")).unwrap();
+    assert_eq!(taken[0].to_string(), "(job, 1)");
+
+    handle.shutdown();
+}
+
+#[test]
+fn operations_are_refused_while_blocked() {
+    let handle = Server::bind("127.0.0.1:0")
+        .unwrap()
+        .async_io(true)
+        .spawn()
+        .unwrap();
+    let url = format!("ws://{}/socket", handle.local_addr());
+    let (mut socket, _) = tungstenite::connect(&url).unwrap();
+
+    send(&mut socket, &Operation::InBl(pattern("job")));
+    send(&mut socket, &Operation::Out(pair("job", 1)));
+    assert!(matches!(reply(&mut socket), TupleError::Error));
+
+    send(&mut socket, &Operation::Cancel);
+    assert!(matches!(reply(&mut socket), TupleError::CancelledError));
+    assert_eq!(TupleSpace::new(&url).count(pattern("job")).unwrap(), 0);
+
+    handle.shutdown();
+}
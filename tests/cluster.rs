@@ -0,0 +1,282 @@
+mod common;
+
+use std::fs;
+use std::net::{SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use rustuple::data::*;
+use rustuple::server::{Server, ServerHandle, SyncPolicy};
+use rustuple::tuple_space::TupleSpace;
+
+use common::{eventually, pair, pattern};
+
+const TOKEN: &str = "secret";
+
+/// Bind the servers first, so that every node knows the addresses of the other ones
+fn bind(nodes: usize) -> Vec<Server> {
+    (0..nodes)
+        .map(|_| Server::bind("127.0.0.1:0").unwrap().admin_token(TOKEN))
+        .collect()
+}
+
+fn addrs(servers: &[Server]) -> Vec<SocketAddr> {
+    servers.iter().map(Server::local_addr).collect()
+}
+
+/// Join the server to the cluster of all the given addresses
+fn join(server: Server, addrs: &[SocketAddr]) -> Server {
+    let local_addr = server.local_addr();
+    let peers = addrs
+        .iter()
+        .filter(|addr| **addr != local_addr)
+        .map(SocketAddr::to_string);
+
+    server.cluster(peers)
+}
+
+/// Start the servers as the nodes of a cluster, each one with its own log
+fn start(servers: Vec<Server>, paths: &[PathBuf]) -> Vec<ServerHandle> {
+    let addrs = addrs(&servers);
+
+    servers
+        .into_iter()
+        .zip(paths)
+        .map(|(server, path)| {
+            join(server, &addrs)
+                .wal(path, SyncPolicy::Always)
+                .spawn()
+                .unwrap()
+        })
+        .collect()
+}
+
+fn urls(addrs: &[SocketAddr]) -> Vec<String> {
+    addrs
+        .iter()
+        .map(|addr| format!("ws://{}/socket", addr))
+        .collect()
+}
+
+fn connect(addrs: &[SocketAddr]) -> TupleSpace {
+    let urls = urls(addrs);
+
+    TupleSpace::cluster(&urls.iter().map(String::as_str).collect::<Vec<&str>>())
+}
+
+/// Wait until exactly one of the running nodes is the leader and return its position
+fn leader(nodes: &[Option<ServerHandle>]) -> usize {
+    let deadline = Instant::now() + Duration::from_secs(10);
+
+    loop {
+        let leaders = nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| node.as_ref().is_some_and(ServerHandle::is_primary))
+            .map(|(idx, _)| idx)
+            .collect::<Vec<usize>>();
+
+        if let [leader] = leaders[..] {
+            return leader;
+        }
+
+        assert!(Instant::now() < deadline, "no leader was elected");
+        sleep(Duration::from_millis(20));
+    }
+}
+
+/// Remove the log of a node, with its ballot and its snapshot
+fn remove(path: &Path) {
+    let _ = fs::remove_file(path);
+    let _ = fs::remove_file(path.with_extension("wal.ballot"));
+    let _ = fs::remove_file(path.with_extension("wal.snapshot"));
+}
+
+/// Paths of the logs of the nodes of a cluster, left by no previous run
+fn wal_paths(name: &str, nodes: usize) -> Vec<PathBuf> {
+    (0..nodes)
+        .map(|node| {
+            let path = std::env::temp_dir().join(format!(
+                "rustuple-cluster-{}-{}-{}.wal",
+                std::process::id(),
+                name,
+                node
+            ));
+            remove(&path);
+
+            path
+        })
+        .collect()
+}
+
+/// True if the text is in the log of a node, or in its snapshot
+fn logged(path: &Path, text: &str) -> bool {
+    [path.to_path_buf(), path.with_extension("wal.snapshot")]
+        .iter()
+        .any(|path| fs::read_to_string(path).is_ok_and(|content| content.contains(text)))
+}
+
+/// Id of a node, read from its ballot
+fn ballot_id(path: &Path) -> u64 {
+    let ballot: serde_json::Value = serde_json::from_slice(&fs::read(path).unwrap()).unwrap();
+
+    ballot["id"].as_u64().unwrap()
+}
+
+#[test]
+fn cluster_survives_the_failure_of_its_leader() {
+    let servers = bind(3);
+    let addrs = addrs(&servers);
+    let paths = wal_paths("leader", 3);
+    let mut nodes = start(servers, &paths)
+        .into_iter()
+        .map(Some)
+        .collect::<Vec<_>>();
+
+    let mut client = connect(&addrs);
+    for val in 0..20 {
+        client.out(pair("job", val)).unwrap();
+    }
+    client.out(pair("done", 1)).unwrap();
+    client.in_non_bl(pattern("done")).unwrap();
+
+    // The followers refuse the clients
+    let old = leader(&nodes);
+    for idx in (0..nodes.len()).filter(|idx| *idx != old) {
+        let mut follower = TupleSpace::new(&urls(&addrs)[idx]);
+        assert!(matches!(
+            follower.out(pair("job", 100)),
+            Err(TupleError::NotPrimaryError)
+        ));
+    }
+
+    nodes[old].take().unwrap().shutdown();
+
+    // The client follows the new leader, which has all the committed changes
+    assert_eq!(client.count(pattern("job")).unwrap(), 20);
+    assert_eq!(client.count(pattern("done")).unwrap(), 0);
+    client.out(pair("job", 20)).unwrap();
+    assert_eq!(client.count(pattern("job")).unwrap(), 21);
+
+    let new = leader(&nodes);
+    assert_ne!(new, old);
+
+    for node in nodes.into_iter().flatten() {
+        node.shutdown();
+    }
+    paths.iter().for_each(|path| remove(path));
+}
+
+#[test]
+fn cluster_survives_the_failure_of_a_minority() {
+    let servers = bind(5);
+    let addrs = addrs(&servers);
+    let paths = wal_paths("minority", 5);
+    let mut nodes = start(servers, &paths)
+        .into_iter()
+        .map(Some)
+        .collect::<Vec<_>>();
+
+    let mut client = connect(&addrs);
+    client.out(pair("before", 1)).unwrap();
+
+    // Two failures, including the leader
+    let old = leader(&nodes);
+    nodes[old].take().unwrap().shutdown();
+    let other = (old + 1) % nodes.len();
+    nodes[other].take().unwrap().shutdown();
+
+    client.out(pair("after", 2)).unwrap();
+    assert_eq!(client.count(pattern("before")).unwrap(), 1);
+    assert_eq!(client.count(pattern("after")).unwrap(), 1);
+
+    for node in nodes.into_iter().flatten() {
+        node.shutdown();
+    }
+    paths.iter().for_each(|path| remove(path));
+}
+
+/// The nodes take snapshots of their spaces while they run, so a restarted node rebuilds them from its snapshot and
+/// the records of its log after it, then gets from the leader the changes it missed
+#[test]
+fn restarted_node_catches_up_from_its_log() {
+    let servers = bind(3)
+        .into_iter()
+        .map(|server| server.snapshot_interval(Duration::from_millis(100)))
+        .collect::<Vec<Server>>();
+    let addrs = addrs(&servers);
+    let paths = wal_paths("restart", 3);
+    let mut nodes = start(servers, &paths)
+        .into_iter()
+        .map(Some)
+        .collect::<Vec<_>>();
+
+    let mut client = connect(&addrs);
+    client.out(pair("first", 1)).unwrap();
+
+    // A follower misses some changes while it is down
+    let follower = (leader(&nodes) + 1) % nodes.len();
+    let ballot = paths[follower].with_extension("wal.ballot");
+    let id = ballot_id(&ballot);
+    eventually(|| paths[follower].with_extension("wal.snapshot").exists());
+    nodes[follower].take().unwrap().shutdown();
+    eventually(|| TcpStream::connect(addrs[follower]).is_err());
+
+    client.out(pair("second", 2)).unwrap();
+
+    let server = Server::bind(addrs[follower])
+        .unwrap()
+        .admin_token(TOKEN)
+        .snapshot_interval(Duration::from_millis(100));
+    nodes[follower] = Some(
+        join(server, &addrs)
+            .wal(&paths[follower], SyncPolicy::Always)
+            .spawn()
+            .unwrap(),
+    );
+    // The node keeps its id, its vote for itself in the current term stays its own
+    assert_eq!(ballot_id(&ballot), id);
+
+    // Once the leader is down the restarted node is needed for a majority, so it has all the changes
+    eventually(|| logged(&paths[follower], "second"));
+    let old = leader(&nodes);
+    nodes[old].take().unwrap().shutdown();
+
+    assert_eq!(client.count(pattern("first")).unwrap(), 1);
+    assert_eq!(client.count(pattern("second")).unwrap(), 1);
+
+    for node in nodes.into_iter().flatten() {
+        node.shutdown();
+    }
+    paths.iter().for_each(|path| remove(path));
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_cluster_survives_the_failure_of_its_leader() {
+    let servers = bind(3)
+        .into_iter()
+        .map(|server| server.async_io(true))
+        .collect::<Vec<Server>>();
+    let addrs = addrs(&servers);
+    let paths = wal_paths("async", 3);
+    let mut nodes = start(servers, &paths)
+        .into_iter()
+        .map(Some)
+        .collect::<Vec<_>>();
+
+    let mut client = connect(&addrs);
+    client.out(pair("job", 1)).unwrap();
+
+    let old = leader(&nodes);
+    nodes[old].take().unwrap().shutdown();
+
+    let taken = client.in_bl(pattern("job")).unwrap();
+    assert_eq!(taken[0].to_string(), "(job, 1)");
+
+    for node in nodes.into_iter().flatten() {
+        node.shutdown();
+    }
+    paths.iter().for_each(|path| remove(path));
+}
@@ -0,0 +1,54 @@
+//! Helpers shared by the integration tests, each test crate uses only some of them
+#![allow(dead_code)]
+
+use std::net::TcpStream;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use rustuple::data::*;
+use rustuple::server::ServerHandle;
+use rustuple::tuple;
+use rustuple::tuple_space::TupleSpace;
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{Message, WebSocket};
+
+pub fn connect(server: &ServerHandle, path: &str) -> TupleSpace {
+    TupleSpace::new(&format!("ws://{}{}", server.local_addr(), path))
+}
+
+pub fn pair(key: &str, val: i32) -> Tuple {
+    tuple!(
+        Field::Value(Value::String(key.to_string())),
+        Field::Value(Value::Integer(val))
+    )
+}
+
+pub fn pattern(key: &str) -> Tuple {
+    tuple!(
+        Field::Value(Value::String(key.to_string())),
+        Field::Type(Type::Integer)
+    )
+}
+
+/// Send an operation on a raw connection and read the reply, for the requests the client library never sends
+pub fn send(socket: &mut WebSocket<MaybeTlsStream<TcpStream>>, operation: &Operation) {
+    let serialized = serde_json::to_string(operation).unwrap();
+    socket.send(Message::Text(serialized)).unwrap();
+}
+
+pub fn reply(socket: &mut WebSocket<MaybeTlsStream<TcpStream>>) -> TupleError {
+    match socket.read().unwrap() {
+        Message::Text(val) => serde_json::from_str(&val).unwrap(),
+        msg => panic!("unexpected message {msg:?}"),
+    }
+}
+
+/// Wait until the condition holds, failing the test if it does not within a few seconds
+pub fn eventually(mut condition: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+
+    while !condition() {
+        assert!(Instant::now() < deadline, "condition not met in time");
+        sleep(Duration::from_millis(20));
+    }
+}
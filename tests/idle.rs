@@ -0,0 +1,63 @@
+//! Alone in its test crate, so that the CPU time of the process is spent only by this test and its server
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use std::fs;
+use std::thread::sleep;
+use std::time::Duration;
+
+use rustuple::data::*;
+use rustuple::server::Server;
+
+use common::{pattern, reply, send};
+
+/// Time spent on the CPU by the other threads of the process (the server), in nanoseconds
+fn cpu_time() -> u64 {
+    let current = fs::read_link("/proc/thread-self").unwrap();
+    let current = current.file_name().unwrap();
+
+    fs::read_dir("/proc/self/task")
+        .unwrap()
+        .filter_map(|task| task.ok())
+        .filter(|task| task.file_name() != current)
+        .filter_map(|task| fs::read_to_string(task.path().join("schedstat")).ok())
+        .map(|stat| {
+            stat.split_whitespace()
+                .next()
+                .unwrap()
+                .parse::<u64>()
+                .unwrap()
+        })
+        .sum()
+}
+
+/// The blocked requests wait for their tuples and the messages of their clients without waking up
+#[test]
+fn blocked_requests_do_not_use_the_cpu() {
+    let server = Server::bind("127.0.0.1:0").unwrap().spawn().unwrap();
+    let url = format!("ws://{}/socket", server.local_addr());
+
+    let mut sockets: Vec<_> = (0..100)
+        .map(|_| {
+            let (mut socket, _) = tungstenite::connect(&url).unwrap();
+            send(&mut socket, &Operation::InBl(pattern("job")));
+            socket
+        })
+        .collect();
+    sleep(Duration::from_millis(200));
+
+    let before = cpu_time();
+    sleep(Duration::from_secs(1));
+    let spent = Duration::from_nanos(cpu_time() - before);
+    assert!(spent < Duration::from_millis(5), "spent {:?}", spent);
+
+    // The waits still end as soon as their clients cancel them
+    for socket in sockets.iter_mut() {
+        send(socket, &Operation::Cancel);
+    }
+    for socket in sockets.iter_mut() {
+        assert!(matches!(reply(socket), TupleError::CancelledError));
+    }
+    server.shutdown();
+}
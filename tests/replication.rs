@@ -0,0 +1,257 @@
+mod common;
+
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{sleep, spawn};
+use std::time::Duration;
+
+use rustuple::data::*;
+use rustuple::server::{Server, ServerHandle, SyncPolicy};
+use rustuple::tuple_space::TupleSpace;
+
+use common::{connect, eventually, pair, pattern};
+
+const TOKEN: &str = "secret";
+
+fn primary() -> Server {
+    Server::bind("127.0.0.1:0")
+        .unwrap()
+        .admin_token(TOKEN)
+        .primary(true)
+}
+
+fn backup_of(primary: &ServerHandle) -> Server {
+    Server::bind("127.0.0.1:0")
+        .unwrap()
+        .admin_token(TOKEN)
+        .backup_of(primary.local_addr().to_string())
+}
+
+/// Name and size of every space of the server
+fn sizes(space: &mut TupleSpace) -> Vec<(String, usize)> {
+    space
+        .list_spaces(TOKEN)
+        .unwrap()
+        .into_iter()
+        .map(|info| (info.name, info.size))
+        .collect()
+}
+
+#[test]
+fn backup_copies_the_primary() {
+    let primary = primary().spawn().unwrap();
+    let mut client = connect(&primary, "/socket");
+
+    // Copied by the snapshot sent when the backup connects
+    for val in 0..10 {
+        client.out(pair("before", val)).unwrap();
+    }
+    client.in_non_bl(pattern("before")).unwrap();
+    client.out(pair("kept", 1)).unwrap();
+
+    let backup = backup_of(&primary).spawn().unwrap();
+    let mut observer = connect(&backup, "/socket");
+    eventually(|| sizes(&mut observer) == sizes(&mut client));
+
+    // Streamed to the backup as they happen
+    client
+        .create_space(TOKEN, "jobs", SpaceOptions::new().multiset(true))
+        .unwrap();
+    let mut jobs = connect(&primary, "/spaces/jobs");
+    jobs.out(pair("job", 1)).unwrap();
+    jobs.out(pair("job", 1)).unwrap();
+    client
+        .out_with(pair("high", 1), OutOptions::new().priority(5))
+        .unwrap();
+    client.replace(pattern("kept"), pair("kept", 2)).unwrap();
+    client
+        .create_space(TOKEN, "gone", SpaceOptions::new())
+        .unwrap();
+    client.drop_space(TOKEN, "gone").unwrap();
+
+    eventually(|| sizes(&mut observer) == sizes(&mut client));
+
+    observer.promote(TOKEN).unwrap();
+    let mut promoted = connect(&backup, "/socket");
+    let kept = promoted.rd_non_bl(pattern("kept")).unwrap();
+    assert_eq!(kept[0].to_string(), "(kept, 2)");
+
+    let mut promoted_jobs = connect(&backup, "/spaces/jobs");
+    assert_eq!(promoted_jobs.count(pattern("job")).unwrap(), 2);
+    promoted_jobs.out(pair("job", 2)).unwrap();
+
+    backup.shutdown();
+    primary.shutdown();
+}
+
+#[test]
+fn backup_refuses_the_clients_until_promoted() {
+    let primary = primary().spawn().unwrap();
+    let backup = backup_of(&primary).spawn().unwrap();
+    let mut client = connect(&backup, "/socket");
+
+    assert!(matches!(
+        client.out(pair("a", 1)),
+        Err(TupleError::NotPrimaryError)
+    ));
+    assert!(matches!(
+        client.create_space(TOKEN, "jobs", SpaceOptions::new()),
+        Err(TupleError::NotPrimaryError)
+    ));
+    assert!(client.list_spaces(TOKEN).is_ok());
+
+    // The primary fails, the backup takes its place
+    primary.shutdown();
+    backup.promote();
+
+    // The spaces were replaced by the copy of the primary, the clients connect again
+    let mut client = connect(&backup, "/socket");
+    client.out(pair("a", 1)).unwrap();
+    assert_eq!(client.count(pattern("a")).unwrap(), 1);
+
+    backup.shutdown();
+}
+
+#[test]
+fn backup_with_a_wrong_token_is_refused() {
+    let primary = primary().spawn().unwrap();
+    let mut client = connect(&primary, "/socket");
+    client.out(pair("a", 1)).unwrap();
+
+    let backup = Server::bind("127.0.0.1:0")
+        .unwrap()
+        .admin_token("wrong")
+        .backup_of(primary.local_addr().to_string())
+        .spawn()
+        .unwrap();
+    let mut observer = connect(&backup, "/socket");
+
+    sleep(Duration::from_millis(300));
+    let spaces = observer.list_spaces("wrong").unwrap();
+    assert_eq!(spaces.len(), 1);
+    assert_eq!(spaces[0].size, 0);
+
+    backup.shutdown();
+    primary.shutdown();
+}
+
+#[test]
+fn backup_keeps_following_when_its_snapshot_cannot_be_written() {
+    let wal = std::env::temp_dir().join(format!(
+        "rustuple-replication-{}-snapshot.wal",
+        std::process::id()
+    ));
+    let blocked = wal.with_extension("wal.snapshot.tmp");
+    let _ = fs::remove_file(&wal);
+    fs::create_dir_all(&blocked).unwrap();
+
+    let primary = primary().spawn().unwrap();
+    let mut client = connect(&primary, "/socket");
+    client.out(pair("before", 1)).unwrap();
+
+    // The copy of the spaces cannot be written, the backup connects again until it can
+    let backup = backup_of(&primary)
+        .wal(&wal, SyncPolicy::Always)
+        .spawn()
+        .unwrap();
+    let mut observer = connect(&backup, "/socket");
+    sleep(Duration::from_millis(300));
+    fs::remove_dir(&blocked).unwrap();
+
+    client.out(pair("after", 1)).unwrap();
+    eventually(|| sizes(&mut observer) == sizes(&mut client));
+
+    backup.shutdown();
+    primary.shutdown();
+    let _ = fs::remove_file(&wal);
+    let _ = fs::remove_file(wal.with_extension("wal.snapshot"));
+}
+
+#[test]
+fn backup_restarts_with_every_record_streamed_during_its_snapshots() {
+    let wal = std::env::temp_dir().join(format!(
+        "rustuple-replication-{}-streaming.wal",
+        std::process::id()
+    ));
+    let _ = fs::remove_file(&wal);
+    let _ = fs::remove_file(wal.with_extension("wal.snapshot"));
+
+    let primary = primary().spawn().unwrap();
+    let backup = backup_of(&primary)
+        .wal(&wal, SyncPolicy::Never)
+        .snapshot_interval(Duration::from_millis(1))
+        .spawn()
+        .unwrap();
+    let mut observer = connect(&backup, "/socket");
+
+    // The backup is stopped while the records stream, after a snapshot written in between them
+    let streaming = Arc::new(AtomicBool::new(true));
+    let producer = {
+        let mut client = connect(&primary, "/socket");
+        let streaming = Arc::clone(&streaming);
+        spawn(move || {
+            let mut val = 0;
+            while streaming.load(Ordering::SeqCst) {
+                client.out(pair("a", val)).unwrap();
+                val += 1;
+            }
+        })
+    };
+    eventually(|| {
+        sizes(&mut observer)
+            .iter()
+            .map(|(_, size)| size)
+            .sum::<usize>()
+            >= 2000
+    });
+    backup.shutdown();
+    streaming.store(false, Ordering::SeqCst);
+    producer.join().unwrap();
+
+    // The restarted backup rebuilds its spaces from its last snapshot and the records of the log after it, every
+    // record up to the last one it wrote is there
+    let restarted = Server::bind("127.0.0.1:0")
+        .unwrap()
+        .wal(&wal, SyncPolicy::Never)
+        .spawn()
+        .unwrap();
+    let mut vals: Vec<i32> = connect(&restarted, "/socket")
+        .rd_non_bl(pattern("a"))
+        .unwrap()
+        .iter()
+        .map(|tuple| match tuple.iter().last() {
+            Some(Field::Value(Value::Integer(val))) => *val,
+            field => panic!("unexpected field {:?}", field),
+        })
+        .collect();
+    vals.sort();
+    assert!(vals.len() >= 2000);
+    assert_eq!(vals, (0..vals.len() as i32).collect::<Vec<_>>());
+
+    restarted.shutdown();
+    primary.shutdown();
+    let _ = fs::remove_file(&wal);
+    let _ = fs::remove_file(wal.with_extension("wal.snapshot"));
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_backup_copies_the_primary() {
+    let primary = primary().async_io(true).spawn().unwrap();
+    let backup = backup_of(&primary).async_io(true).spawn().unwrap();
+    let mut client = connect(&primary, "/socket");
+    let mut observer = connect(&backup, "/socket");
+
+    for val in 0..100 {
+        client.out(pair("a", val)).unwrap();
+    }
+    eventually(|| sizes(&mut observer) == sizes(&mut client));
+
+    backup.promote();
+    let mut observer = connect(&backup, "/socket");
+    assert_eq!(observer.count(pattern("a")).unwrap(), 100);
+
+    backup.shutdown();
+    primary.shutdown();
+}
@@ -0,0 +1,629 @@
+mod common;
+
+use std::io;
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
+
+use rustuple::data::*;
+use rustuple::server::{Backend, MemoryStorage, Server, Storage};
+use rustuple::tuple_space::TupleSpace;
+
+use common::{connect, eventually, pair, pattern, reply, send};
+
+/// Count returns the number of tuples matching the pattern, without taking them out
+#[test]
+fn count_returns_the_matching_tuples() {
+    let server = Server::bind("127.0.0.1:0").unwrap().spawn().unwrap();
+    let mut client = connect(&server, "/socket");
+
+    assert_eq!(client.count(pattern("job")).unwrap(), 0);
+    client.out(pair("job", 1)).unwrap();
+    client.out(pair("job", 2)).unwrap();
+    client.out(pair("other", 1)).unwrap();
+
+    assert_eq!(client.count(pattern("job")).unwrap(), 2);
+    assert_eq!(client.count(pair("job", 1)).unwrap(), 1);
+    assert_eq!(client.count(pattern("job")).unwrap(), 2);
+
+    client.in_non_bl(pattern("job")).unwrap();
+    assert_eq!(client.count(pattern("job")).unwrap(), 0);
+    assert_eq!(client.count(pattern("other")).unwrap(), 1);
+    server.shutdown();
+}
+
+/// A multiset stores every copy put, an In takes one copy of each matching tuple and an InOne a single copy, while a
+/// set refuses the duplicates
+#[test]
+fn multiset_stores_the_duplicates() {
+    let server = Server::bind("127.0.0.1:0")
+        .unwrap()
+        .multiset(true)
+        .spawn()
+        .unwrap();
+    let mut client = connect(&server, "/socket");
+
+    client.out(pair("job", 1)).unwrap();
+    client.out(pair("job", 1)).unwrap();
+    client.out(pair("job", 1)).unwrap();
+    client.out(pair("job", 2)).unwrap();
+    assert_eq!(client.count(pattern("job")).unwrap(), 4);
+
+    let taken = client.in_non_bl(pattern("job")).unwrap();
+    assert_eq!(
+        taken.iter().map(Tuple::to_string).collect::<Vec<_>>(),
+        ["(job, 1)", "(job, 2)"]
+    );
+    assert_eq!(client.count(pair("job", 1)).unwrap(), 2);
+
+    assert_eq!(
+        client.in_one_non_bl(pattern("job")).unwrap().to_string(),
+        "(job, 1)"
+    );
+    assert_eq!(client.count(pattern("job")).unwrap(), 1);
+    server.shutdown();
+
+    let server = Server::bind("127.0.0.1:0").unwrap().spawn().unwrap();
+    let mut client = connect(&server, "/socket");
+
+    client.out(pair("job", 1)).unwrap();
+    assert!(matches!(
+        client.out(pair("job", 1)),
+        Err(TupleError::TupleAlreadyPresentError)
+    ));
+    assert_eq!(client.count(pattern("job")).unwrap(), 1);
+    server.shutdown();
+}
+
+/// A leased tuple is removed by the server once its time to live expires, unless its lease is renewed in time
+#[test]
+fn leases_expire_unless_renewed() {
+    let server = Server::bind("127.0.0.1:0").unwrap().spawn().unwrap();
+    let mut client = connect(&server, "/socket");
+    let ttl = OutOptions::new().ttl(Duration::from_millis(300));
+
+    let expiring = client.out_with(pair("expiring", 1), ttl).unwrap().unwrap();
+    let renewed = client.out_with(pair("renewed", 1), ttl).unwrap().unwrap();
+    client
+        .renew_lease(renewed, Duration::from_secs(60))
+        .unwrap();
+
+    eventually(|| client.count(pattern("expiring")).unwrap() == 0);
+    assert_eq!(client.count(pattern("renewed")).unwrap(), 1);
+    assert!(matches!(
+        client.renew_lease(expiring, Duration::from_secs(60)),
+        Err(TupleError::LeaseNotFoundError)
+    ));
+
+    // A tuple taken out has no lease to renew
+    client.in_non_bl(pattern("renewed")).unwrap();
+    assert!(matches!(
+        client.renew_lease(renewed, Duration::from_secs(60)),
+        Err(TupleError::LeaseNotFoundError)
+    ));
+    server.shutdown();
+}
+
+/// Replace swaps the first matching tuple for the new one, fails without a matching tuple and, in a set, when the new
+/// tuple is already present. The lease of the replaced tuple is dropped
+#[test]
+fn replace_swaps_the_matching_tuple() {
+    let server = Server::bind("127.0.0.1:0").unwrap().spawn().unwrap();
+    let mut client = connect(&server, "/socket");
+
+    let lease = client
+        .out_with(
+            pair("counter", 1),
+            OutOptions::new().ttl(Duration::from_secs(60)),
+        )
+        .unwrap()
+        .unwrap();
+    let replaced = client
+        .replace(pattern("counter"), pair("counter", 2))
+        .unwrap();
+    assert_eq!(replaced.to_string(), "(counter, 1)");
+    assert_eq!(
+        client.rd_non_bl(pattern("counter")).unwrap()[0].to_string(),
+        "(counter, 2)"
+    );
+    assert!(matches!(
+        client.renew_lease(lease, Duration::from_secs(60)),
+        Err(TupleError::LeaseNotFoundError)
+    ));
+
+    // A compare-and-swap fails when the value changed in the meantime
+    assert!(matches!(
+        client.replace(pair("counter", 1), pair("counter", 3)),
+        Err(TupleError::NoMatchingTupleError)
+    ));
+    assert!(matches!(
+        client.replace(pattern("missing"), pair("missing", 1)),
+        Err(TupleError::NoMatchingTupleError)
+    ));
+    assert_eq!(client.count(pattern("missing")).unwrap(), 0);
+
+    // A set refuses to replace a tuple with one already present
+    client.out(pair("other", 1)).unwrap();
+    assert!(matches!(
+        client.replace(pattern("counter"), pair("other", 1)),
+        Err(TupleError::TupleAlreadyPresentError)
+    ));
+    assert_eq!(client.count(pattern("counter")).unwrap(), 1);
+    assert_eq!(client.count(pattern("other")).unwrap(), 1);
+    server.shutdown();
+}
+
+/// A transaction applies all its steps, or none of them if one fails, and the connection serves the next requests
+#[test]
+fn transactions_commit_or_roll_back_every_step() {
+    let server = Server::bind("127.0.0.1:0").unwrap().spawn().unwrap();
+    let mut client = connect(&server, "/socket");
+    client.out(pair("job", 1)).unwrap();
+
+    let results = client
+        .transaction(vec![
+            TransactionStep::In(pattern("job")),
+            TransactionStep::Out(pair("done", 1)),
+        ])
+        .unwrap();
+    assert_eq!(results.len(), 2);
+    assert!(results.iter().all(|result| result.is_ok()));
+    assert_eq!(client.count(pattern("job")).unwrap(), 0);
+    assert_eq!(client.count(pattern("done")).unwrap(), 1);
+
+    // The failed Rd rolls back the In before it, the Out after it is not executed
+    let results = client
+        .transaction(vec![
+            TransactionStep::In(pattern("done")),
+            TransactionStep::Rd(pattern("missing")),
+            TransactionStep::Out(pair("never", 1)),
+        ])
+        .unwrap();
+    assert_eq!(results.len(), 2);
+    assert!(results[0].is_ok());
+    assert!(matches!(results[1], Err(TupleError::NoMatchingTupleError)));
+    assert_eq!(client.count(pattern("done")).unwrap(), 1);
+    assert_eq!(client.count(pattern("never")).unwrap(), 0);
+    server.shutdown();
+}
+
+/// A subscriber is notified of the tuples matching its pattern, in the order they are put, and of none after it
+/// unsubscribes
+#[test]
+fn subscribers_are_notified_of_the_matching_tuples() {
+    let server = Server::bind("127.0.0.1:0").unwrap().spawn().unwrap();
+    let mut subscriber = connect(&server, "/socket");
+    let mut producer = connect(&server, "/socket");
+
+    let jobs = subscriber.subscribe(pattern("job")).unwrap();
+    producer.out(pair("other", 1)).unwrap();
+    producer.out(pair("job", 1)).unwrap();
+
+    // The notifications of a connection are sent in order, so the first one shows that the other tuple was skipped
+    let notification = subscriber.notifications().next().unwrap();
+    assert_eq!(notification.subscription, jobs);
+    assert_eq!(notification.tuple.to_string(), "(job, 1)");
+
+    subscriber.unsubscribe(jobs).unwrap();
+    producer.out(pair("job", 2)).unwrap();
+    let marks = subscriber.subscribe(pattern("mark")).unwrap();
+    producer.out(pair("mark", 1)).unwrap();
+
+    let notification = subscriber.notifications().next().unwrap();
+    assert_eq!(notification.subscription, marks);
+    assert_eq!(notification.tuple.to_string(), "(mark, 1)");
+    server.shutdown();
+}
+
+/// A CancelHandle aborts a pending blocking In or Rd, which leaves the queue without taking the tuples put later
+#[test]
+fn blocked_requests_are_cancelled() {
+    let server = Server::bind("127.0.0.1:0").unwrap().spawn().unwrap();
+    let mut client = connect(&server, "/socket");
+
+    for take in [true, false] {
+        let cancel = client.cancel_handle();
+        let canceller = spawn(move || {
+            sleep(Duration::from_millis(200));
+            cancel.cancel();
+        });
+
+        let res = match take {
+            true => client.in_bl(pattern("job")),
+            false => client.rd_bl(pattern("job")),
+        };
+        assert!(matches!(res, Err(TupleError::CancelledError)));
+        canceller.join().unwrap();
+    }
+
+    let mut producer = connect(&server, "/socket");
+    producer.out(pair("job", 1)).unwrap();
+    assert_eq!(producer.count(pattern("job")).unwrap(), 1);
+    assert_eq!(
+        client.in_bl(pattern("job")).unwrap()[0].to_string(),
+        "(job, 1)"
+    );
+    server.shutdown();
+}
+
+/// An operation sent while a blocking request is pending is refused with an Error, and the blocked request can still
+/// be cancelled
+#[test]
+fn operations_are_refused_while_blocked() {
+    let server = Server::bind("127.0.0.1:0").unwrap().spawn().unwrap();
+    let url = format!("ws://{}/socket", server.local_addr());
+    let (mut socket, _) = tungstenite::connect(&url).unwrap();
+
+    send(&mut socket, &Operation::InBl(pattern("job")));
+    send(&mut socket, &Operation::Out(pair("job", 1)));
+    assert!(matches!(reply(&mut socket), TupleError::Error));
+
+    send(&mut socket, &Operation::Cancel);
+    assert!(matches!(reply(&mut socket), TupleError::CancelledError));
+    assert_eq!(
+        connect(&server, "/socket").count(pattern("job")).unwrap(),
+        0
+    );
+    server.shutdown();
+}
+
+/// The admin operations require the token of the server, and are all refused by a server without one
+#[test]
+fn admin_operations_manage_the_spaces() {
+    let server = Server::bind("127.0.0.1:0")
+        .unwrap()
+        .admin_token("secret")
+        .spawn()
+        .unwrap();
+    let mut admin = connect(&server, "/socket");
+
+    assert!(matches!(
+        admin.create_space("wrong", "jobs", SpaceOptions::new()),
+        Err(TupleError::UnauthorizedError)
+    ));
+    admin
+        .create_space("secret", "jobs", SpaceOptions::new())
+        .unwrap();
+    assert!(matches!(
+        admin.create_space("secret", "jobs", SpaceOptions::new()),
+        Err(TupleError::SpaceAlreadyPresentError)
+    ));
+
+    let mut client = connect(&server, "/spaces/jobs");
+    client.out(pair("job", 1)).unwrap();
+    client.out(pair("job", 2)).unwrap();
+    let listed = admin.list_spaces("secret").unwrap();
+    let jobs = listed.iter().find(|space| space.name == "jobs").unwrap();
+    assert_eq!(jobs.size, 2);
+
+    admin.clear_space("secret", "jobs").unwrap();
+    assert_eq!(client.count(pattern("job")).unwrap(), 0);
+
+    // The default space is selected by every path without a name
+    assert!(matches!(
+        admin.drop_space("secret", "default"),
+        Err(TupleError::Error)
+    ));
+    admin.drop_space("secret", "jobs").unwrap();
+    assert!(matches!(
+        client.count(pattern("job")),
+        Err(TupleError::SpaceNotFoundError)
+    ));
+    assert!(matches!(
+        admin.drop_space("secret", "jobs"),
+        Err(TupleError::SpaceNotFoundError)
+    ));
+    let listed = admin.list_spaces("secret").unwrap();
+    assert!(listed.iter().all(|space| space.name != "jobs"));
+    server.shutdown();
+
+    let server = Server::bind("127.0.0.1:0").unwrap().spawn().unwrap();
+    let mut admin = connect(&server, "/socket");
+
+    assert!(matches!(
+        admin.list_spaces(""),
+        Err(TupleError::UnauthorizedError)
+    ));
+    assert!(matches!(
+        admin.drop_space("secret", "default"),
+        Err(TupleError::UnauthorizedError)
+    ));
+    server.shutdown();
+}
+
+/// A named space is created only by the admin, unless the server creates the spaces on first use
+#[test]
+fn named_spaces_are_created_by_the_admin() {
+    let server = Server::bind("127.0.0.1:0")
+        .unwrap()
+        .admin_token("secret")
+        .spawn()
+        .unwrap();
+    let url = format!("ws://{}/spaces/jobs", server.local_addr());
+
+    assert!(tungstenite::connect(&url).is_err());
+    let mut admin = connect(&server, "/socket");
+    admin
+        .create_space("secret", "jobs", SpaceOptions::new())
+        .unwrap();
+    assert!(tungstenite::connect(&url).is_ok());
+    server.shutdown();
+
+    let server = Server::bind("127.0.0.1:0")
+        .unwrap()
+        .admin_token("secret")
+        .auto_create(true)
+        .spawn()
+        .unwrap();
+    let mut client = connect(&server, "/spaces/jobs");
+    client.out(pair("job", 1)).unwrap();
+
+    let mut admin = connect(&server, "/socket");
+    let listed = admin.list_spaces("secret").unwrap();
+    assert!(listed.iter().any(|space| space.name == "jobs"));
+    server.shutdown();
+}
+
+/// Backend failing to build the storages of the space named broken
+struct Failing;
+
+impl Backend for Failing {
+    fn storage(&self, space: &str, index_field: usize) -> io::Result<Box<dyn Storage>> {
+        match space {
+            "broken" => Err(io::Error::other("broken storage")),
+            _ => Ok(Box::new(MemoryStorage::new(index_field))),
+        }
+    }
+}
+
+/// A space whose storage cannot be built is not created, and the server keeps serving the other spaces
+#[test]
+fn spaces_without_a_storage_are_refused() {
+    let server = Server::bind("127.0.0.1:0")
+        .unwrap()
+        .admin_token("secret")
+        .auto_create(true)
+        .backend(Failing)
+        .spawn()
+        .unwrap();
+    let mut admin = connect(&server, "/socket");
+
+    assert!(matches!(
+        admin.create_space("secret", "broken", SpaceOptions::new()),
+        Err(TupleError::Error)
+    ));
+    let url = format!("ws://{}/spaces/broken", server.local_addr());
+    assert!(tungstenite::connect(&url).is_err());
+
+    admin
+        .create_space("secret", "jobs", SpaceOptions::new())
+        .unwrap();
+    let mut client = connect(&server, "/spaces/jobs");
+    client.out(pair("job", 1)).unwrap();
+    let listed = admin.list_spaces("secret").unwrap();
+    assert!(listed.iter().all(|space| space.name != "broken"));
+    assert_eq!(listed.len(), 2);
+    server.shutdown();
+}
+
+/// A scan walks the matching tuples one page at a time in insertion order, returning exactly once each tuple present
+/// for the whole scan and also the tuples put after its start
+#[test]
+fn scan_walks_the_pages_with_the_cursor() {
+    let server = Server::bind("127.0.0.1:0").unwrap().spawn().unwrap();
+    let mut client = connect(&server, "/socket");
+
+    for val in 0..5 {
+        client.out(pair("item", val)).unwrap();
+    }
+    client.out(pair("other", 0)).unwrap();
+
+    let mut scanned = vec![];
+    let mut cursor = None;
+    let mut pages = 0;
+
+    loop {
+        let page = client.scan(Some(pattern("item")), 2, cursor).unwrap();
+        assert!(page.tuples.len() <= 2);
+        scanned.extend(page.tuples.iter().map(Tuple::to_string));
+        pages += 1;
+
+        cursor = match page.cursor {
+            Some(cursor) => Some(cursor),
+            None => break,
+        };
+
+        // The tuples put during the scan come after the ones already there
+        if pages <= 2 {
+            client.out(pair("item", 10 + pages)).unwrap();
+        }
+    }
+
+    assert_eq!(
+        scanned,
+        [
+            "(item, 0)",
+            "(item, 1)",
+            "(item, 2)",
+            "(item, 3)",
+            "(item, 4)",
+            "(item, 11)",
+            "(item, 12)"
+        ]
+    );
+    assert!(pages >= 4);
+    server.shutdown();
+}
+
+/// The blocked requests are served in arrival order: a new tuple goes to the oldest blocked In, after a copy to every
+/// blocked Rd, and the next In waits for the next tuple
+#[test]
+fn blocked_ins_are_served_in_arrival_order() {
+    let server = Server::bind("127.0.0.1:0").unwrap().spawn().unwrap();
+    let mut producer = connect(&server, "/socket");
+    let mut blocked = vec![];
+
+    for take in [true, true, false] {
+        let mut client = connect(&server, "/socket");
+
+        blocked.push(spawn(move || {
+            let res = match take {
+                true => client.in_bl(pattern("job")),
+                false => client.rd_bl(pattern("job")),
+            };
+            res.unwrap()[0].to_string()
+        }));
+        // Leave the time to queue the request before sending the next one
+        sleep(Duration::from_millis(200));
+    }
+
+    let reader = blocked.pop().unwrap();
+    let second = blocked.pop().unwrap();
+    let first = blocked.pop().unwrap();
+
+    producer.out(pair("job", 1)).unwrap();
+    assert_eq!(first.join().unwrap(), "(job, 1)");
+    assert_eq!(reader.join().unwrap(), "(job, 1)");
+    sleep(Duration::from_millis(200));
+    assert!(!second.is_finished());
+
+    producer.out(pair("job", 2)).unwrap();
+    assert_eq!(second.join().unwrap(), "(job, 2)");
+    assert_eq!(producer.count(pattern("job")).unwrap(), 0);
+    server.shutdown();
+}
+
+/// InAny and RdAny return the index of the first pattern with a match, InAny taking its tuples and RdAny only reading
+/// them, block until a later Out matches any pattern and refuse an empty list or a pattern made only of data
+#[test]
+fn any_operations_report_the_matching_pattern() {
+    let server = Server::bind("127.0.0.1:0").unwrap().spawn().unwrap();
+    let mut client = connect(&server, "/socket");
+    let mut producer = connect(&server, "/socket");
+
+    client.out(pair("b", 1)).unwrap();
+    let (idx, tuples) = client.rd_any(vec![pattern("a"), pattern("b")]).unwrap();
+    assert_eq!(idx, 1);
+    assert_eq!(tuples[0].to_string(), "(b, 1)");
+    assert_eq!(client.count(pattern("b")).unwrap(), 1);
+
+    // The first pattern in the list wins when more than one has a match
+    client.out(pair("a", 1)).unwrap();
+    let (idx, tuples) = client.in_any(vec![pattern("a"), pattern("b")]).unwrap();
+    assert_eq!(idx, 0);
+    assert_eq!(tuples[0].to_string(), "(a, 1)");
+    let (idx, tuples) = client.in_any(vec![pattern("a"), pattern("b")]).unwrap();
+    assert_eq!(idx, 1);
+    assert_eq!(tuples[0].to_string(), "(b, 1)");
+    assert_eq!(client.count(pattern("b")).unwrap(), 0);
+
+    let putter = spawn(move || {
+        sleep(Duration::from_millis(100));
+        producer.out(pair("b", 2)).unwrap();
+    });
+    let (idx, tuples) = client.in_any(vec![pattern("a"), pattern("b")]).unwrap();
+    assert_eq!(idx, 1);
+    assert_eq!(tuples[0].to_string(), "(b, 2)");
+    assert_eq!(client.count(pattern("b")).unwrap(), 0);
+    putter.join().unwrap();
+
+    assert!(matches!(client.in_any(vec![]), Err(TupleError::Error)));
+    assert!(matches!(client.rd_any(vec![]), Err(TupleError::Error)));
+    assert!(matches!(
+        client.rd_any(vec![pattern("a"), pair("b", 1)]),
+        Err(TupleError::TupleOnlyDataError)
+    ));
+    server.shutdown();
+}
+
+/// WaitEmpty fails with TimeoutError while a matching tuple stays, and returns as soon as the last one is taken
+#[test]
+fn wait_empty_returns_when_the_last_tuple_is_taken() {
+    let server = Server::bind("127.0.0.1:0").unwrap().spawn().unwrap();
+    let mut client = connect(&server, "/socket");
+    let mut worker = connect(&server, "/socket");
+
+    client.out(pair("job", 1)).unwrap();
+    client.out(pair("job", 2)).unwrap();
+    client.out(pair("other", 1)).unwrap();
+
+    let started = Instant::now();
+    assert!(matches!(
+        client.wait_empty(pattern("job"), Some(Duration::from_millis(200))),
+        Err(TupleError::TimeoutError)
+    ));
+    assert!(started.elapsed() >= Duration::from_millis(200));
+    assert_eq!(client.count(pattern("job")).unwrap(), 2);
+
+    let taker = spawn(move || {
+        for _ in 0..2 {
+            sleep(Duration::from_millis(100));
+            worker.in_one_non_bl(pattern("job")).unwrap();
+        }
+    });
+
+    // The tuples not matching the pattern do not keep the wait blocked
+    client
+        .wait_empty(pattern("job"), Some(Duration::from_secs(5)))
+        .unwrap();
+    assert_eq!(client.count(pattern("job")).unwrap(), 0);
+    taker.join().unwrap();
+    server.shutdown();
+}
+
+/// WaitCount blocks until the threshold of matching tuples is reached while another client puts them, then reads them
+/// or takes exactly those tuples out. A threshold already met returns at once with every matching tuple
+#[test]
+fn wait_count_returns_when_the_threshold_is_reached() {
+    let server = Server::bind("127.0.0.1:0").unwrap().spawn().unwrap();
+    let mut client = connect(&server, "/socket");
+    let producer = connect(&server, "/socket");
+
+    client.out(pair("ready", 1)).unwrap();
+    client.out(pair("ready", 2)).unwrap();
+    let ready = client.wait_count(pattern("ready"), 1, false).unwrap();
+    assert_eq!(ready.len(), 2);
+    assert_eq!(client.count(pattern("ready")).unwrap(), 2);
+
+    let put = |mut producer: TupleSpace, key: &'static str, count: i32| {
+        spawn(move || {
+            for val in 0..count {
+                sleep(Duration::from_millis(50));
+                producer.out(pair(key, val)).unwrap();
+            }
+            producer
+        })
+    };
+
+    let putter = put(producer, "result", 3);
+    let results = client.wait_count(pattern("result"), 3, false).unwrap();
+    assert_eq!(results.len(), 3);
+    let producer = putter.join().unwrap();
+    assert_eq!(client.count(pattern("result")).unwrap(), 3);
+
+    // The request is served by the Out that reaches the threshold, the following ones stay
+    let putter = put(producer, "done", 5);
+    let done = client.wait_count(pattern("done"), 3, true).unwrap();
+    assert_eq!(done.len(), 3);
+    putter.join().unwrap();
+    assert_eq!(client.count(pattern("done")).unwrap(), 2);
+    server.shutdown();
+}
+
+/// A page size as large as the address space returns every matching tuple in a single page
+#[test]
+fn scan_with_the_largest_page_returns_every_tuple() {
+    let server = Server::bind("127.0.0.1:0").unwrap().spawn().unwrap();
+    let mut client = connect(&server, "/socket");
+
+    for val in 0..3 {
+        client.out(pair("item", val)).unwrap();
+    }
+
+    let page = client.scan(None, usize::MAX, None).unwrap();
+    assert_eq!(
+        page.tuples.iter().map(Tuple::to_string).collect::<Vec<_>>(),
+        ["(item, 0)", "(item, 1)", "(item, 2)"]
+    );
+    assert!(page.cursor.is_none());
+    server.shutdown();
+}